@@ -0,0 +1,46 @@
+//! Dispatch throughput for a few command names in mixed case. Run with
+//! `cargo bench --features bench`.
+
+use std::hint::black_box;
+use std::time::Instant;
+
+use codecrafters_redis::{handle_command_into, ConnectionState, RespValue, ServerState};
+
+const ITERATIONS: u32 = 1_000_000;
+
+fn command(args: &[&str]) -> RespValue {
+    RespValue::Array(Some(
+        args.iter()
+            .map(|arg| RespValue::BulkString(Some(arg.as_bytes().to_vec())))
+            .collect(),
+    ))
+}
+
+fn main() {
+    let server = ServerState::new();
+    let mut conn = ConnectionState::new();
+    let mut out = Vec::new();
+    handle_command_into(
+        &command(&["SET", "key", "value"]),
+        &server,
+        &mut conn,
+        &mut out,
+    );
+
+    let cases = [
+        ("PING", command(&["PING"])),
+        ("get", command(&["get", "key"])),
+        ("StrLen", command(&["StrLen", "key"])),
+        ("unknown", command(&["nosuchcommand"])),
+    ];
+    for (label, value) in &cases {
+        let start = Instant::now();
+        for _ in 0..ITERATIONS {
+            out.clear();
+            handle_command_into(black_box(value), &server, &mut conn, &mut out);
+            black_box(&out);
+        }
+        let per_op = start.elapsed() / ITERATIONS;
+        println!("{:<10} {:>6?}/op", label, per_op);
+    }
+}
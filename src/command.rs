@@ -1,65 +1,914 @@
+use std::borrow::Cow;
 use std::collections::HashMap;
-
+use std::time::{Duration, Instant, UNIX_EPOCH};
+
+use crate::command_table::{self, CommandSpec};
+use crate::config::Settings;
+use crate::glob::glob_match;
+use crate::parser::PROTO_MAX_BULK_LEN;
+use crate::rng;
+use crate::storage::{
+    jittered_millis, parse_strict_i64, ListPop, SetCondition, SetTtl, StreamRead,
+    DEFAULT_PACKED_THRESHOLD,
+};
+use crate::ConnectionState;
+use crate::Reply;
 use crate::RespValue;
+use crate::ServerState;
 use crate::Storage;
 
-pub fn handle_command(value: &RespValue, storage: &Storage) -> String {
-    match value {
+pub fn handle_command(
+    value: &RespValue,
+    server: &ServerState,
+    conn: &mut ConnectionState,
+) -> Vec<u8> {
+    let mut out = Vec::new();
+    handle_command_into(value, server, conn, &mut out);
+    out
+}
+
+/// Appends the reply to `out`, so a connection can reuse one buffer for every
+/// command instead of allocating a fresh reply each time.
+pub fn handle_command_into(
+    value: &RespValue,
+    server: &ServerState,
+    conn: &mut ConnectionState,
+    out: &mut Vec<u8>,
+) {
+    execute_into(value, server, conn, out, true);
+}
+
+/// `handle_command_into`, with the rate limit optional so EXEC can run the
+/// commands it already paid for when they were queued.
+fn execute_into(
+    value: &RespValue,
+    server: &ServerState,
+    conn: &mut ConnectionState,
+    out: &mut Vec<u8>,
+    rate_limited: bool,
+) {
+    let storage = server.db(conn.db);
+    let start = out.len();
+
+    let reply = match value {
         RespValue::Array(Some(elements)) if !elements.is_empty() => {
-            let command = extract_command_name(&elements[0]);
+            let command = dispatch_name(&elements[0]);
+
+            let rate = server.config.max_commands_per_sec();
+            if rate_limited && rate > 0 && !conn.rate_limiter.try_acquire(rate, Instant::now()) {
+                out.extend_from_slice(b"-ERR command rate limit exceeded, retry later\r\n");
+                return;
+            }
+
+            let indexed = command_table::lookup_indexed(&command);
+            let spec = indexed.map(|(_, spec)| spec);
+            if let Some(spec) = spec {
+                if !spec.check_arity(elements.len()) {
+                    conn.transaction_aborted |= conn.in_transaction();
+                    out.extend_from_slice(wrong_number_of_arguments(spec.name).as_bytes());
+                    return;
+                }
+            }
+
+            let requires_auth = spec.is_some_and(|spec| !spec.flags.contains(&"no-auth"));
+            if requires_auth && !conn.authenticated && server.config.requirepass().is_some() {
+                out.extend_from_slice(b"-NOAUTH Authentication required.\r\n");
+                return;
+            }
+
+            // RESP2 has no push type, so a subscribed RESP2 connection can't
+            // tell replies from messages and may only manage subscriptions.
+            if conn.protocol < 3
+                && conn.subscription_count() > 0
+                && !matches!(
+                    &*command,
+                    "SUBSCRIBE"
+                        | "SSUBSCRIBE"
+                        | "PSUBSCRIBE"
+                        | "UNSUBSCRIBE"
+                        | "SUNSUBSCRIBE"
+                        | "PUNSUBSCRIBE"
+                        | "PING"
+                        | "QUIT"
+                        | "RESET"
+                )
+            {
+                out.extend_from_slice(
+                    format!(
+                        "-ERR Can't execute '{}': only (P|S)SUBSCRIBE / (P|S)UNSUBSCRIBE / PING / QUIT / RESET are allowed in this context\r\n",
+                        command.to_lowercase()
+                    )
+                    .as_bytes(),
+                );
+                return;
+            }
+
+            if conn.in_transaction()
+                && !matches!(&*command, "MULTI" | "EXEC" | "DISCARD" | "WATCH" | "RESET")
+            {
+                // Errors found while queueing abort the transaction; errors
+                // raised while running it only fill their own reply slot.
+                if spec.is_none() {
+                    conn.transaction_aborted = true;
+                    out.extend_from_slice(
+                        format!("-ERR unknown command: '{}'\r\n", command).as_bytes(),
+                    );
+                    return;
+                }
+                out.extend_from_slice(queue_command(value, conn).as_bytes());
+                return;
+            }
 
-            match command.as_str() {
-                "PING" => handle_ping(elements),
-                "ECHO" => handle_echo(elements),
-                "SET" => handle_set(elements, storage),
-                "GET" => handle_get(elements, storage),
+            let started = Instant::now();
+            // Handlers taking `out` write their reply straight into it and
+            // leave the String empty, which costs no allocation.
+            let reply = match &*command {
+                "PING" => handle_ping(elements, out),
+                "ECHO" => handle_echo(elements, out),
+                "SET" => handle_set(elements, storage, &server.config.get(), out),
+                "GET" => handle_get(elements, storage, out),
+                "GETRANGE" | "SUBSTR" => handle_getrange(elements, storage, out),
+                "SETRANGE" => handle_setrange(elements, storage),
+                "SETBIT" => handle_setbit(elements, storage),
+                "APPEND" => handle_append(elements, storage),
+                "STRLEN" => handle_strlen(elements, storage),
+                "INCR" => handle_incr_by(storage, &elements[1], Some(1)),
+                "DECR" => handle_incr_by(storage, &elements[1], Some(-1)),
+                "INCRBY" => handle_incr_by(storage, &elements[1], parse_increment(&elements[2])),
+                "DECRBY" => handle_incr_by(
+                    storage,
+                    &elements[1],
+                    parse_increment(&elements[2]).and_then(i64::checked_neg),
+                ),
+                "EXPIRE" => handle_expire(elements, storage, 1000),
+                "PEXPIRE" => handle_expire(elements, storage, 1),
+                "TTL" => handle_ttl(elements, storage, false, false),
+                "PTTL" => handle_ttl(elements, storage, true, false),
+                "EXPIRETIME" => handle_ttl(elements, storage, false, true),
+                "PEXPIRETIME" => handle_ttl(elements, storage, true, true),
                 "RPUSH" => handle_rpush(elements, storage),
                 "LPUSH" => handle_lpush(elements, storage),
                 "LRANGE" => handle_lrange(elements, storage),
                 "LLEN" => handle_llen(elements, storage),
                 "LPOP" => handle_lpop(elements, storage),
+                "LMPOP" => handle_lmpop(elements, server, storage, conn.protocol),
                 "BLPOP" => handle_blpop(elements, storage),
+                "BLMPOP" => handle_blmpop(elements, server, storage, conn.protocol),
                 "TYPE" => handle_type(elements, storage),
+                "EXISTS" => handle_exists(elements, storage),
+                "DEL" => handle_del(elements, storage),
+                "COPY" => handle_copy(elements, server, conn),
+                "MOVE" => handle_move(elements, server, conn),
+                "FLUSHDB" => handle_flush(elements, std::slice::from_ref(storage)),
+                "DBSIZE" => integer(storage.key_counts().0 as i64),
+                "FLUSHALL" => handle_flush(elements, &server.databases),
+                "SCAN" => handle_scan(elements, storage),
+                "KEYS" => handle_keys(elements, server, storage),
                 "XADD" => handle_xadd(elements, storage),
                 "XRANGE" => handle_xrange(elements, storage),
                 "XREAD" => handle_xread(elements, storage),
+                "INFO" => handle_info(elements, server),
+                "DEBUG" => handle_debug(elements, server, storage, out),
+                "OBJECT" => handle_object(elements, storage),
+                "MEMORY" => handle_memory(elements, storage, conn.protocol),
+                "SELECT" => handle_select(elements, server, conn),
+                "MULTI" => handle_multi(conn),
+                "EXEC" => handle_exec(server, conn, out),
+                "DISCARD" => handle_discard(conn),
+                "WATCH" => handle_watch(elements, server, conn),
+                "UNWATCH" => handle_unwatch(conn),
+                "COMMAND" => handle_command_table(elements),
+                // Channels are server-wide whatever db is selected, and with a
+                // single node the shard channel commands are plain aliases.
+                "SUBSCRIBE" | "SSUBSCRIBE" => handle_subscribe(elements, server, conn, false),
+                "PSUBSCRIBE" => handle_subscribe(elements, server, conn, true),
+                "UNSUBSCRIBE" | "SUNSUBSCRIBE" => handle_unsubscribe(elements, server, conn, false),
+                "PUNSUBSCRIBE" => handle_unsubscribe(elements, server, conn, true),
+                "PUBLISH" | "SPUBLISH" => handle_publish(elements, server),
+                "HELLO" => handle_hello(elements, server, conn),
+                "RESET" => handle_reset(server, conn),
+                "AUTH" => handle_auth(elements, server, conn),
+                "CONFIG" => handle_config(elements, server, conn),
+                "CLIENT" => handle_client(elements, server, conn),
+                "CLUSTER" => handle_cluster(elements, server),
                 _ => format!("-ERR unknown command: '{}'\r\n", command),
+            };
+
+            out.extend_from_slice(reply.as_bytes());
+            if let Some((index, _)) = indexed {
+                server.command_stats.record(index, started.elapsed());
+            }
+            if let Some(spec) = spec {
+                if out.get(start) != Some(&b'-') {
+                    track_keys(spec, elements, server, conn);
+                }
             }
+            return;
         }
         _ => "-ERR Invalid command format \r\n".to_string(),
+    };
+
+    out.extend_from_slice(reply.as_bytes());
+}
+
+fn handle_command_table(elements: &[RespValue]) -> String {
+    let subcommand = match elements.get(1) {
+        Some(value) => extract_command_name(value),
+        None => return format_command_specs(command_table::COMMANDS.iter().map(Some)),
+    };
+
+    match subcommand.as_str() {
+        "COUNT" => integer(command_table::COMMANDS.len() as i64),
+        "INFO" => {
+            if elements.len() == 2 {
+                return format_command_specs(command_table::COMMANDS.iter().map(Some));
+            }
+            format_command_specs(
+                elements[2..]
+                    .iter()
+                    .map(|name| command_table::lookup(&extract_key(name))),
+            )
+        }
+        _ => format!(
+            "-ERR unknown subcommand '{}'. Try COMMAND HELP.\r\n",
+            extract_key(&elements[1])
+        ),
     }
 }
 
-fn handle_xread(elements: &[RespValue], storage: &Storage) -> String {
-    if elements.len() != 4 {
-        return "-ERR wrong number of arguments for command\r\n".to_string();
+/// Client-side caching bookkeeping: reads by a tracking connection are
+/// remembered, writes invalidate the key for everyone who read it.
+fn track_keys(
+    spec: &CommandSpec,
+    elements: &[RespValue],
+    server: &ServerState,
+    conn: &ConnectionState,
+) {
+    let is_write = spec.flags.contains(&"write");
+    let is_read = conn.tracking && spec.flags.contains(&"readonly");
+    if spec.first_key <= 0 || !(is_write || is_read) {
+        return;
     }
 
-    if extract_command_name(&elements[1]) != "STREAMS" {
-        return "-ERR syntax error\r\n".to_string();
+    let last = if spec.last_key < 0 {
+        elements.len() as i64 + spec.last_key
+    } else {
+        spec.last_key
+    };
+    let mut i = spec.first_key;
+    while i <= last && (i as usize) < elements.len() {
+        let key = extract_key(&elements[i as usize]);
+        if is_write {
+            server.clients.invalidate(&key);
+        } else {
+            server.clients.track_read(conn.id, &key);
+        }
+        i += spec.step.max(1);
     }
+}
 
-    let stream_name = extract_key(&elements[2]);
+/// `HELLO [protover [AUTH username password] [SETNAME name]]`. Nothing
+/// changes unless every option is valid and authentication succeeds.
+fn handle_hello(
+    elements: &[RespValue],
+    server: &ServerState,
+    conn: &mut ConnectionState,
+) -> String {
+    let mut protocol = conn.protocol;
+    if let Some(version) = elements.get(1) {
+        match extract_integer_from_resp_value(version) {
+            Some(version @ 2..=3) => protocol = version as u8,
+            Some(_) => return "-NOPROTO unsupported protocol version\r\n".to_string(),
+            None => {
+                return "-ERR Protocol version is not an integer or out of range\r\n".to_string()
+            }
+        }
+    }
 
-    let id = match &elements[3] {
-        RespValue::BulkString(Some(s)) => String::from_utf8_lossy(s).to_string(),
-        RespValue::SimpleString(s) => s.clone(),
-        _ => return "-ERR Invalid stream ID specified as stream command argument\r\n".to_string(),
+    let mut credentials = None;
+    let mut name = None;
+    let mut i = 2;
+    while i < elements.len() {
+        let option = extract_command_name(&elements[i]);
+        match option.as_str() {
+            "AUTH" if i + 2 < elements.len() => {
+                credentials = Some((extract_key(&elements[i + 1]), extract_key(&elements[i + 2])));
+                i += 3;
+            }
+            "SETNAME" if i + 1 < elements.len() => {
+                name = Some(extract_key(&elements[i + 1]));
+                i += 2;
+            }
+            _ => {
+                return format!(
+                    "-ERR Syntax error in HELLO option '{}'\r\n",
+                    extract_key(&elements[i])
+                )
+            }
+        }
+    }
+
+    if let Some((username, password)) = credentials {
+        if let Err(e) = authenticate(server, Some(&username), &password) {
+            return format!("-{}\r\n", e);
+        }
+        conn.authenticated = true;
+    } else if !conn.authenticated && server.config.requirepass().is_some() {
+        return "-NOAUTH HELLO must be called with the client already authenticated, otherwise the HELLO <proto> AUTH <user> <pass> option can be used to authenticate the client and select the RESP protocol version at the same time\r\n".to_string();
+    }
+
+    if protocol != conn.protocol && conn.subscription_count() > 0 {
+        server.clients.set_push_protocol(conn.id, protocol);
+    }
+    conn.protocol = protocol;
+    if name.is_some() {
+        conn.name = name;
+    }
+
+    let field = |name: &str| Reply::Bulk(name.to_string());
+    Reply::Map(vec![
+        (field("server"), field("redis")),
+        (field("version"), field(env!("CARGO_PKG_VERSION"))),
+        (field("proto"), Reply::Integer(conn.protocol as i64)),
+        (field("id"), Reply::Integer(conn.id as i64)),
+        (field("mode"), field("standalone")),
+        (field("role"), field("master")),
+        (field("modules"), Reply::Array(vec![])),
+    ])
+    .encode(conn.protocol)
+}
+
+/// Drops the transaction, watches, subscriptions, tracking, selected db,
+/// name and protocol, and authentication if a password is required.
+fn handle_reset(server: &ServerState, conn: &mut ConnectionState) -> String {
+    if conn.tracking {
+        server.clients.disable_tracking(conn.id);
+    }
+    server.clients.unsubscribe_all(conn.id);
+    conn.reset(server.config.requirepass().is_none());
+    "+RESET\r\n".to_string()
+}
+
+fn handle_auth(elements: &[RespValue], server: &ServerState, conn: &mut ConnectionState) -> String {
+    let (username, password) = match elements.len() {
+        2 => (None, extract_key(&elements[1])),
+        3 => (Some(extract_key(&elements[1])), extract_key(&elements[2])),
+        _ => return "-ERR syntax error\r\n".to_string(),
     };
 
-    match storage.xread(&stream_name, &id) {
-        Ok(v) => format_xread(&stream_name, v),
-        Err(e) => {
-            format!("-{}\r\n", e)
+    match authenticate(server, username.as_deref(), &password) {
+        Ok(()) => {
+            conn.authenticated = true;
+            "+OK\r\n".to_string()
         }
+        Err(e) => format!("-{}\r\n", e),
     }
 }
 
-fn handle_xrange(elements: &[RespValue], storage: &Storage) -> String {
-    if elements.len() != 4 {
-        return "-ERR wrong number of arguments for command\r\n".to_string();
+/// Checks credentials against the default user, the only user there is.
+/// Without requirepass the default user takes any password, but the
+/// one-argument AUTH form is rejected as a likely misconfiguration.
+fn authenticate(
+    server: &ServerState,
+    username: Option<&str>,
+    password: &str,
+) -> Result<(), String> {
+    let wrongpass = || "WRONGPASS invalid username-password pair or user is disabled.".to_string();
+    if username.is_some_and(|username| username != "default") {
+        return Err(wrongpass());
+    }
+
+    match server.config.requirepass() {
+        Some(requirepass) if requirepass == password => Ok(()),
+        Some(_) => Err(wrongpass()),
+        None if username.is_some() => Ok(()),
+        None => Err("ERR AUTH <password> called without any password configured for the default user. Are you sure your configuration is correct?".to_string()),
+    }
+}
+
+fn handle_config(elements: &[RespValue], server: &ServerState, conn: &ConnectionState) -> String {
+    let subcommand = extract_command_name(&elements[1]);
+    match subcommand.as_str() {
+        "GET" if elements.len() > 2 => {
+            let patterns: Vec<String> = elements[2..].iter().map(extract_key).collect();
+            let field = |value: &str| Reply::Bulk(value.to_string());
+            Reply::Map(
+                server
+                    .config
+                    .matching(&patterns)
+                    .into_iter()
+                    .map(|(name, value)| (field(name), field(&value)))
+                    .collect(),
+            )
+            .encode(conn.protocol)
+        }
+        "SET" if elements.len() > 2 && elements.len().is_multiple_of(2) => {
+            let pairs: Vec<(String, String)> = elements[2..]
+                .chunks(2)
+                .map(|pair| (extract_key(&pair[0]), extract_key(&pair[1])))
+                .collect();
+            match server.config.set(&pairs) {
+                Ok(()) => "+OK\r\n".to_string(),
+                Err(e) => format!("-{}\r\n", e),
+            }
+        }
+        "GET" | "SET" => wrong_number_of_arguments(&format!("config|{}", subcommand)),
+        _ => format!(
+            "-ERR unknown subcommand '{}'. Try CONFIG HELP.\r\n",
+            extract_key(&elements[1])
+        ),
+    }
+}
+
+/// Standalone answers for the CLUSTER probes cluster-aware clients send on
+/// connect: no slots, no shards and this node as the only member.
+fn handle_cluster(elements: &[RespValue], server: &ServerState) -> String {
+    let subcommand = extract_command_name(&elements[1]);
+    match subcommand.as_str() {
+        "INFO" => {
+            let info = "cluster_enabled:0\r\ncluster_state:ok\r\ncluster_slots_assigned:0\r\n\
+                        cluster_slots_ok:0\r\ncluster_slots_pfail:0\r\ncluster_slots_fail:0\r\n\
+                        cluster_known_nodes:1\r\ncluster_size:0\r\ncluster_current_epoch:0\r\n\
+                        cluster_my_epoch:0\r\n";
+            format!("${}\r\n{}\r\n", info.len(), info)
+        }
+        "MYID" => format!("${}\r\n{}\r\n", server.node_id.len(), server.node_id),
+        "SLOTS" | "SHARDS" => "*0\r\n".to_string(),
+        "NODES" => {
+            let line = format!("{} :0@0 myself,master - 0 0 0 connected\n", server.node_id);
+            format!("${}\r\n{}\r\n", line.len(), line)
+        }
+        _ => format!(
+            "-ERR unknown subcommand '{}'. Try CLUSTER HELP.\r\n",
+            extract_key(&elements[1])
+        ),
+    }
+}
+
+fn handle_client(
+    elements: &[RespValue],
+    server: &ServerState,
+    conn: &mut ConnectionState,
+) -> String {
+    let subcommand = extract_command_name(&elements[1]);
+    match subcommand.as_str() {
+        "TRACKING" if elements.len() == 3 => {
+            match extract_command_name(&elements[2]).as_str() {
+                "ON" => {
+                    if conn.protocol < 3 {
+                        return "-ERR CLIENT TRACKING needs RESP3, switch with HELLO 3 first\r\n"
+                            .to_string();
+                    }
+                    let Some(sink) = conn.push.clone() else {
+                        return "-ERR this connection can not receive push messages\r\n"
+                            .to_string();
+                    };
+                    server.clients.enable_tracking(conn.id, sink);
+                    conn.tracking = true;
+                }
+                "OFF" => {
+                    server.clients.disable_tracking(conn.id);
+                    conn.tracking = false;
+                }
+                _ => return "-ERR syntax error\r\n".to_string(),
+            }
+            "+OK\r\n".to_string()
+        }
+        "TRACKING" => wrong_number_of_arguments("client|tracking"),
+        "ID" if elements.len() == 2 => integer(conn.id as i64),
+        "LIST" if elements.len() == 2 => {
+            let list: String = server
+                .clients
+                .list()
+                .into_iter()
+                .map(|(id, addr)| {
+                    let addr = addr.map(|addr| addr.to_string()).unwrap_or_default();
+                    format!("id={} addr={}\n", id, addr)
+                })
+                .collect();
+            format!("${}\r\n{}\r\n", list.len(), list)
+        }
+        "ID" | "LIST" => {
+            wrong_number_of_arguments(&format!("client|{}", subcommand.to_lowercase()))
+        }
+        _ => format!(
+            "-ERR unknown subcommand '{}'. Try CLIENT HELP.\r\n",
+            extract_key(&elements[1])
+        ),
+    }
+}
+
+fn handle_select(
+    elements: &[RespValue],
+    server: &ServerState,
+    conn: &mut ConnectionState,
+) -> String {
+    let index = match extract_integer_from_resp_value(&elements[1]) {
+        Some(i) => i,
+        None => return "-ERR value is not an integer or out of range\r\n".to_string(),
+    };
+
+    if index < 0 || index as usize >= server.databases.len() {
+        return "-ERR DB index is out of range\r\n".to_string();
+    }
+
+    conn.db = index as usize;
+    "+OK\r\n".to_string()
+}
+
+fn handle_multi(conn: &mut ConnectionState) -> String {
+    if conn.in_transaction() {
+        return "-ERR MULTI calls can not be nested\r\n".to_string();
+    }
+
+    conn.transaction = Some(Vec::new());
+    conn.transaction_aborted = false;
+    "+OK\r\n".to_string()
+}
+
+fn queue_command(value: &RespValue, conn: &mut ConnectionState) -> String {
+    if let Some(queue) = conn.transaction.as_mut() {
+        queue.push(value.clone());
+    }
+    "+QUEUED\r\n".to_string()
+}
+
+fn handle_exec(server: &ServerState, conn: &mut ConnectionState, out: &mut Vec<u8>) -> String {
+    let queued = match conn.transaction.take() {
+        Some(queued) => queued,
+        None => return "-ERR EXEC without MULTI\r\n".to_string(),
+    };
+
+    let watched = std::mem::take(&mut conn.watched);
+    if std::mem::take(&mut conn.transaction_aborted) {
+        return "-EXECABORT Transaction discarded because of previous errors.\r\n".to_string();
+    }
+    let dirty = watched
+        .iter()
+        .any(|(db, key, version)| server.db(*db).key_version(key) != *version);
+    if dirty {
+        let null = if conn.protocol >= 3 {
+            "_\r\n"
+        } else {
+            "*-1\r\n"
+        };
+        return null.to_string();
+    }
+
+    out.extend_from_slice(format!("*{}\r\n", queued.len()).as_bytes());
+    for value in &queued {
+        execute_into(value, server, conn, out, false);
+    }
+    String::new()
+}
+
+fn handle_discard(conn: &mut ConnectionState) -> String {
+    conn.watched.clear();
+    match conn.transaction.take() {
+        Some(_) => "+OK\r\n".to_string(),
+        None => "-ERR DISCARD without MULTI\r\n".to_string(),
+    }
+}
+
+/// Records each key's current version. EXEC compares only these versions, so
+/// its cost grows with the number of watched keys, not the keyspace.
+fn handle_watch(
+    elements: &[RespValue],
+    server: &ServerState,
+    conn: &mut ConnectionState,
+) -> String {
+    if conn.in_transaction() {
+        return "-ERR WATCH inside MULTI is not allowed\r\n".to_string();
+    }
+
+    let storage = server.db(conn.db);
+    for key in &elements[1..] {
+        let key = extract_key(key);
+        let version = storage.key_version(&key);
+        conn.watched.push((conn.db, key, version));
     }
+    "+OK\r\n".to_string()
+}
+
+fn handle_unwatch(conn: &mut ConnectionState) -> String {
+    conn.watched.clear();
+    "+OK\r\n".to_string()
+}
+
+fn handle_subscribe(
+    elements: &[RespValue],
+    server: &ServerState,
+    conn: &mut ConnectionState,
+    pattern: bool,
+) -> String {
+    let kind = if pattern { "psubscribe" } else { "subscribe" };
+    let mut out = String::new();
+    for channel in &elements[1..] {
+        let channel = extract_key(channel);
+        if let Some(sink) = &conn.push {
+            server
+                .clients
+                .subscribe(conn.id, sink.clone(), conn.protocol, &channel, pattern);
+        }
+        let set = if pattern {
+            &mut conn.pattern_subscriptions
+        } else {
+            &mut conn.subscriptions
+        };
+        set.insert(channel.clone());
+        out.push_str(&pubsub_frame(
+            kind,
+            Some(&channel),
+            conn.subscription_count(),
+            conn.protocol,
+        ));
+    }
+    out
+}
+
+/// Without arguments every channel (or pattern) is dropped, one frame each.
+/// A client with nothing to drop still gets a single frame with a null
+/// channel and a count of 0.
+fn handle_unsubscribe(
+    elements: &[RespValue],
+    server: &ServerState,
+    conn: &mut ConnectionState,
+    pattern: bool,
+) -> String {
+    let kind = if pattern {
+        "punsubscribe"
+    } else {
+        "unsubscribe"
+    };
+    let set = if pattern {
+        &conn.pattern_subscriptions
+    } else {
+        &conn.subscriptions
+    };
+    let channels: Vec<String> = if elements.len() > 1 {
+        elements[1..].iter().map(extract_key).collect()
+    } else {
+        let mut all: Vec<String> = set.iter().cloned().collect();
+        all.sort();
+        all
+    };
+
+    if channels.is_empty() {
+        return pubsub_frame(kind, None, conn.subscription_count(), conn.protocol);
+    }
+
+    let mut out = String::new();
+    for channel in channels {
+        server.clients.unsubscribe(conn.id, &channel, pattern);
+        if pattern {
+            conn.pattern_subscriptions.remove(&channel);
+        } else {
+            conn.subscriptions.remove(&channel);
+        }
+        out.push_str(&pubsub_frame(
+            kind,
+            Some(&channel),
+            conn.subscription_count(),
+            conn.protocol,
+        ));
+    }
+    out
+}
+
+fn handle_publish(elements: &[RespValue], server: &ServerState) -> String {
+    let channel = extract_key(&elements[1]);
+    let message = match &elements[2] {
+        RespValue::BulkString(Some(message)) => message.as_slice(),
+        RespValue::SimpleString(message) => message.as_bytes(),
+        _ => return "-ERR Invalid value type\r\n".to_string(),
+    };
+    integer(server.clients.publish(&channel, message) as i64)
+}
+
+fn pubsub_frame(kind: &str, channel: Option<&str>, count: usize, protocol: u8) -> String {
+    let header = if protocol >= 3 { '>' } else { '*' };
+    let channel = match channel {
+        Some(channel) => Reply::Bulk(channel.to_string()),
+        None => Reply::Null,
+    };
+    format!(
+        "{}3\r\n${}\r\n{}\r\n{}:{}\r\n",
+        header,
+        kind.len(),
+        kind,
+        channel.encode(protocol),
+        count
+    )
+}
+
+fn handle_info(elements: &[RespValue], server: &ServerState) -> String {
+    let section = match elements.get(1) {
+        Some(value) => extract_key(value).to_lowercase(),
+        None => "default".to_string(),
+    };
+
+    let info = match section.as_str() {
+        "stats" => server.stats_info(),
+        "replication" => server.replication.info(),
+        "commandstats" => server.command_stats.info(),
+        "keyspace" => server.keyspace_info(),
+        "default" => format!(
+            "{}\r\n{}\r\n{}",
+            server.stats_info(),
+            server.replication.info(),
+            server.keyspace_info()
+        ),
+        "all" | "everything" => format!(
+            "{}\r\n{}\r\n{}\r\n{}",
+            server.stats_info(),
+            server.replication.info(),
+            server.command_stats.info(),
+            server.keyspace_info()
+        ),
+        _ => String::new(),
+    };
+
+    format!("${}\r\n{}\r\n", info.len(), info)
+}
+
+fn handle_debug(
+    elements: &[RespValue],
+    server: &ServerState,
+    storage: &Storage,
+    out: &mut Vec<u8>,
+) -> String {
+    let subcommand = extract_command_name(&elements[1]);
+
+    match subcommand.as_str() {
+        "CHANGE-REPL-ID" => {
+            server.replication.change_replid();
+            "+OK\r\n".to_string()
+        }
+        "OBJECT" if elements.len() == 3 => match storage.debug_object(&extract_key(&elements[2])) {
+            Some(description) => format!("+{}\r\n", description),
+            None => "-ERR no such key\r\n".to_string(),
+        },
+        "LISTPACK-ENTRIES" => "+OK\r\n".to_string(),
+        "LISTPACK" if elements.len() == 3 => {
+            match storage.listpack_entries(&extract_key(&elements[2])) {
+                Ok(Some(entries)) => {
+                    let dump: Vec<u8> = entries
+                        .iter()
+                        .flat_map(|entry| entry.iter().chain(b"\n"))
+                        .copied()
+                        .collect();
+                    write_bulk(out, &dump);
+                    String::new()
+                }
+                Ok(None) => "-ERR no such key\r\n".to_string(),
+                Err(e) => format!("-{}\r\n", e),
+            }
+        }
+        "QUICKLIST-PACKED-THRESHOLD" if elements.len() == 3 => {
+            // Same bounds as Redis; 0 restores the default.
+            let threshold = match parse_memory(&extract_key(&elements[2])) {
+                Some(0) => DEFAULT_PACKED_THRESHOLD,
+                Some(bytes) if bytes < (1 << 32) - (1 << 20) => bytes as usize,
+                _ => {
+                    return "-ERR argument must be a memory value bigger than 1 and smaller than 4gb\r\n"
+                        .to_string()
+                }
+            };
+            for db in &server.databases {
+                db.set_packed_threshold(threshold);
+            }
+            "+OK\r\n".to_string()
+        }
+        "SLEEP" | "FLUSHALL" if !server.config.get().enable_debug_command => format!(
+            "-ERR DEBUG {} not allowed, start the server with --enable-debug-command yes\r\n",
+            subcommand
+        ),
+        // Only this connection's thread sleeps; the others keep being served.
+        "SLEEP" if elements.len() == 3 => {
+            match extract_timeout(&elements[2]).map(Duration::try_from_secs_f64) {
+                Some(Ok(duration)) => {
+                    std::thread::sleep(duration);
+                    "+OK\r\n".to_string()
+                }
+                _ => "-ERR value is not a valid float\r\n".to_string(),
+            }
+        }
+        // Nothing is persisted, so the keyspace is all there is to clear.
+        "FLUSHALL" if elements.len() == 2 => {
+            for db in &server.databases {
+                db.flush(false);
+            }
+            "+OK\r\n".to_string()
+        }
+        _ => format!(
+            "-ERR unknown subcommand '{}'. Try DEBUG HELP.\r\n",
+            extract_key(&elements[1])
+        ),
+    }
+}
+
+fn handle_object(elements: &[RespValue], storage: &Storage) -> String {
+    let subcommand = extract_command_name(&elements[1]);
+
+    match subcommand.as_str() {
+        "ENCODING" if elements.len() == 3 => {
+            match storage.object_encoding(&extract_key(&elements[2])) {
+                Some(encoding) => format!("${}\r\n{}\r\n", encoding.len(), encoding),
+                None => "-ERR no such key\r\n".to_string(),
+            }
+        }
+        "REFCOUNT" if elements.len() == 3 => {
+            match storage.object_refcount(&extract_key(&elements[2])) {
+                Some(refcount) => integer(refcount),
+                None => "-ERR no such key\r\n".to_string(),
+            }
+        }
+        "ENCODING" | "REFCOUNT" => {
+            wrong_number_of_arguments(&format!("object|{}", subcommand.to_lowercase()))
+        }
+        _ => format!(
+            "-ERR unknown subcommand '{}'. Try OBJECT HELP.\r\n",
+            extract_key(&elements[1])
+        ),
+    }
+}
+
+/// `MEMORY USAGE key [SAMPLES count]`. Sizes are exact for the model, so
+/// SAMPLES is accepted and ignored.
+fn handle_memory(elements: &[RespValue], storage: &Storage, protocol: u8) -> String {
+    let subcommand = extract_command_name(&elements[1]);
+    match subcommand.as_str() {
+        "USAGE" if elements.len() == 3 || elements.len() == 5 => {
+            if elements.len() == 5 && extract_command_name(&elements[3]) != "SAMPLES" {
+                return "-ERR syntax error\r\n".to_string();
+            }
+            match storage.memory_usage(&extract_key(&elements[2])) {
+                Some(bytes) => integer(bytes as i64),
+                None => Reply::Null.encode(protocol),
+            }
+        }
+        "USAGE" => wrong_number_of_arguments("memory|usage"),
+        _ => format!(
+            "-ERR unknown subcommand '{}'. Try MEMORY HELP.\r\n",
+            extract_key(&elements[1])
+        ),
+    }
+}
+
+fn handle_xread(elements: &[RespValue], storage: &Storage) -> String {
+    let mut count = None;
+    let mut block = None;
+    let mut i = 1;
+
+    loop {
+        let option = match elements.get(i) {
+            Some(option) => extract_command_name(option),
+            None => return "-ERR syntax error\r\n".to_string(),
+        };
+
+        match option.as_str() {
+            "STREAMS" => break,
+            "COUNT" if i + 1 < elements.len() => {
+                count = match extract_integer_from_resp_value(&elements[i + 1]) {
+                    Some(n) if n > 0 => Some(n as usize),
+                    Some(_) => None,
+                    None => return "-ERR value is not an integer or out of range\r\n".to_string(),
+                };
+            }
+            "BLOCK" if i + 1 < elements.len() => {
+                block = match extract_integer_from_resp_value(&elements[i + 1]) {
+                    Some(ms) if ms >= 0 => Some(Duration::from_millis(ms as u64)),
+                    Some(_) => return "-ERR timeout is negative\r\n".to_string(),
+                    None => {
+                        return "-ERR timeout is not an integer or out of range\r\n".to_string();
+                    }
+                };
+            }
+            _ => return "-ERR syntax error\r\n".to_string(),
+        }
+        i += 2;
+    }
+
+    let args = &elements[i + 1..];
+    if args.is_empty() || !args.len().is_multiple_of(2) {
+        return "-ERR Unbalanced 'xread' list of streams: for each stream key an ID or '$' must be specified.\r\n".to_string();
+    }
+
+    let (keys, ids) = args.split_at(args.len() / 2);
+    let streams: Vec<(String, String)> = keys
+        .iter()
+        .zip(ids)
+        .map(|(key, id)| (extract_key(key), extract_key(id)))
+        .collect();
 
+    match storage.xread_streams(&streams, count, block) {
+        Ok(results) if results.is_empty() && block.is_some() => "*-1\r\n".to_string(),
+        Ok(results) => format_xread(results),
+        Err(e) => format!("-{}\r\n", e),
+    }
+}
+
+fn handle_xrange(elements: &[RespValue], storage: &Storage) -> String {
     let stream_name = extract_key(&elements[1]);
     let start = match &elements[2] {
         RespValue::BulkString(Some(s)) => String::from_utf8_lossy(s).to_string(),
@@ -82,8 +931,8 @@ fn handle_xrange(elements: &[RespValue], storage: &Storage) -> String {
 }
 
 fn handle_xadd(elements: &[RespValue], storage: &Storage) -> String {
-    if elements.len() < 5 || ((elements.len() - 3) % 2 != 0) {
-        return "-ERR wrong number of arguments for command\r\n".to_string();
+    if !(elements.len() - 3).is_multiple_of(2) {
+        return wrong_number_of_arguments("xadd");
     }
 
     let stream_name = extract_key(&elements[1]);
@@ -119,115 +968,464 @@ fn handle_xadd(elements: &[RespValue], storage: &Storage) -> String {
 }
 
 fn handle_type(elements: &[RespValue], storage: &Storage) -> String {
-    if elements.len() != 2 {
-        return "-ERR wrong number of arguments for 'GET' command\r\n".to_string();
-    }
-
     let key = extract_key(&elements[1]);
     let key_type = storage.get_type(&key);
-    format!("+{}\r\n", key_type.to_string())
+    format!("+{}\r\n", key_type)
+}
+
+fn handle_exists(elements: &[RespValue], storage: &Storage) -> String {
+    let count = elements[1..]
+        .iter()
+        .filter(|key| storage.exists(&extract_key(key)))
+        .count();
+    integer(count as i64)
 }
 
-fn handle_ping(_elements: &[RespValue]) -> String {
-    "+PONG\r\n".to_string()
+fn handle_del(elements: &[RespValue], storage: &Storage) -> String {
+    let count = elements[1..]
+        .iter()
+        .filter(|key| storage.delete(&extract_key(key)))
+        .count();
+    integer(count as i64)
 }
 
-fn handle_echo(elements: &[RespValue]) -> String {
-    if elements.len() < 2 {
-        return "-ERR wrong number of arguments for 'echo' command\r\n".to_string();
-    }
-    match &elements[1] {
-        RespValue::BulkString(Some(msg)) => {
-            return format!("${}\r\n{}\r\n", msg.len(), String::from_utf8_lossy(&msg))
+fn handle_copy(elements: &[RespValue], server: &ServerState, conn: &ConnectionState) -> String {
+    let source = extract_key(&elements[1]);
+    let destination = extract_key(&elements[2]);
+
+    let mut dest_db = conn.db;
+    let mut replace = false;
+    let mut i = 3;
+
+    while i < elements.len() {
+        let option = extract_command_name(&elements[i]);
+
+        match option.as_str() {
+            "REPLACE" => {
+                replace = true;
+                i += 1;
+            }
+            "DB" if i + 1 < elements.len() => {
+                dest_db = match extract_integer_from_resp_value(&elements[i + 1]) {
+                    Some(db) if db >= 0 && (db as usize) < server.databases.len() => db as usize,
+                    Some(_) => return "-ERR DB index is out of range\r\n".to_string(),
+                    None => {
+                        return "-ERR value is not an integer or out of range\r\n".to_string();
+                    }
+                };
+                i += 2;
+            }
+            _ => return "-ERR syntax error\r\n".to_string(),
         }
-        RespValue::SimpleString(msg) => return format!("${}\r\n{}\r\n", msg.len(), msg),
-        _ => "-ERR invalid argument type\r\n".to_string(),
     }
-}
 
-fn handle_get(elements: &[RespValue], storage: &Storage) -> String {
-    if elements.len() < 2 {
-        return "-ERR wrong number of arguments for 'GET' command\r\n".to_string();
+    if dest_db == conn.db && source == destination {
+        return "-ERR source and destination objects are the same\r\n".to_string();
     }
 
+    let copied = server
+        .db(conn.db)
+        .copy_to(&source, server.db(dest_db), &destination, replace);
+    integer(copied as i64)
+}
+
+fn handle_move(elements: &[RespValue], server: &ServerState, conn: &ConnectionState) -> String {
     let key = extract_key(&elements[1]);
+    let dest_db = match extract_integer_from_resp_value(&elements[2]) {
+        Some(db) if db >= 0 && (db as usize) < server.databases.len() => db as usize,
+        Some(_) => return "-ERR DB index is out of range\r\n".to_string(),
+        None => return "-ERR value is not an integer or out of range\r\n".to_string(),
+    };
 
-    match storage.get(&key) {
-        Some(v) => format!("${}\r\n{}\r\n", v.len(), String::from_utf8_lossy(&v)),
-        None => "$-1\r\n".to_string(),
+    if dest_db == conn.db {
+        return "-ERR source and destination objects are the same\r\n".to_string();
     }
+
+    integer(server.db(conn.db).move_to(&key, server.db(dest_db)) as i64)
 }
 
-fn handle_set(elements: &[RespValue], storage: &Storage) -> String {
-    if elements.len() < 3 {
-        return "-ERR wrong number of arguments for 'SET' command\r\n".to_string();
+fn handle_flush(elements: &[RespValue], databases: &[Storage]) -> String {
+    if elements.len() > 2 {
+        return "-ERR syntax error\r\n".to_string();
     }
 
-    let key = extract_key(&elements[1]);
-
-    let value = match &elements[2] {
-        RespValue::BulkString(Some(v)) => v.clone(),
-        RespValue::SimpleString(v) => v.as_bytes().to_vec(),
-        _ => return "-ERR Invalid value type\r\n".to_string(),
+    let lazy = match elements.get(1).map(extract_command_name).as_deref() {
+        None | Some("SYNC") => false,
+        Some("ASYNC") => true,
+        Some(_) => return "-ERR syntax error\r\n".to_string(),
     };
 
-    let mut i = 3;
-    let mut expiration: Option<(u64, bool)> = None;
-
+    for storage in databases {
+        storage.flush(lazy);
+    }
+    "+OK\r\n".to_string()
+}
+
+fn handle_keys(elements: &[RespValue], server: &ServerState, storage: &Storage) -> String {
+    let pattern = extract_key(&elements[1]);
+    let limit = server.config.get().keys_scan_limit;
+
+    match storage.keys(pattern.as_bytes(), limit) {
+        Ok(keys) => format_array(keys.into_iter().map(String::into_bytes).collect()),
+        Err(e) => format!("-{}\r\n", e),
+    }
+}
+
+fn handle_scan(elements: &[RespValue], storage: &Storage) -> String {
+    let cursor = match extract_key(&elements[1]).parse::<u64>() {
+        Ok(cursor) => cursor,
+        Err(_) => return "-ERR invalid cursor\r\n".to_string(),
+    };
+
+    let mut pattern: Option<String> = None;
+    let mut type_name: Option<String> = None;
+    let mut count = 10;
+    let mut i = 2;
+
     while i < elements.len() {
         let option = extract_command_name(&elements[i]);
+        if i + 1 >= elements.len() {
+            return "-ERR syntax error\r\n".to_string();
+        }
 
         match option.as_str() {
-            "EX" => {
-                if i + 1 >= elements.len() {
-                    return "-ERR syntax error\r\n".to_string();
+            "MATCH" => pattern = Some(extract_key(&elements[i + 1])),
+            "TYPE" => type_name = Some(extract_key(&elements[i + 1]).to_lowercase()),
+            "COUNT" => match extract_integer_from_resp_value(&elements[i + 1]) {
+                Some(n) if n >= 1 => count = n as usize,
+                Some(_) => return "-ERR syntax error\r\n".to_string(),
+                None => {
+                    return "-ERR value is not an integer or out of range\r\n".to_string();
                 }
+            },
+            _ => return "-ERR syntax error\r\n".to_string(),
+        }
+        i += 2;
+    }
 
-                let seconds = match extract_integer_from_resp_value(&elements[i + 1]) {
-                    Some(s) if s > 0 => s as u64,
-                    _ => return "-ERR invalid expire time in 'SET' command\r\n".to_string(),
-                };
-                expiration = Some((seconds, false));
-                i += 2;
+    let (next, keys) = storage.scan(cursor, count, type_name.as_deref());
+    let keys: Vec<Vec<u8>> = keys
+        .into_iter()
+        .filter(|key| match &pattern {
+            Some(pattern) => glob_match(pattern.as_bytes(), key.as_bytes()),
+            None => true,
+        })
+        .map(String::into_bytes)
+        .collect();
+
+    let next = next.to_string();
+    format!(
+        "*2\r\n${}\r\n{}\r\n{}",
+        next.len(),
+        next,
+        format_array(keys)
+    )
+}
+
+fn handle_ping(_elements: &[RespValue], out: &mut Vec<u8>) -> String {
+    out.extend_from_slice(b"+PONG\r\n");
+    String::new()
+}
+
+/// Replies with the exact bytes received, which need not be valid UTF-8.
+fn handle_echo(elements: &[RespValue], out: &mut Vec<u8>) -> String {
+    let msg = match &elements[1] {
+        RespValue::BulkString(Some(msg)) => msg.as_slice(),
+        RespValue::SimpleString(msg) => msg.as_bytes(),
+        _ => return "-ERR invalid argument type\r\n".to_string(),
+    };
+
+    write_bulk(out, msg);
+    String::new()
+}
+
+fn handle_get(elements: &[RespValue], storage: &Storage, out: &mut Vec<u8>) -> String {
+    let key = extract_key(&elements[1]);
+
+    match storage.get(&key) {
+        Some(v) => {
+            write_bulk(out, &v);
+            String::new()
+        }
+        None => "$-1\r\n".to_string(),
+    }
+}
+
+fn write_bulk(out: &mut Vec<u8>, bytes: &[u8]) {
+    out.push(b'$');
+    out.extend_from_slice(bytes.len().to_string().as_bytes());
+    out.extend_from_slice(b"\r\n");
+    out.extend_from_slice(bytes);
+    out.extend_from_slice(b"\r\n");
+}
+
+fn handle_getrange(elements: &[RespValue], storage: &Storage, out: &mut Vec<u8>) -> String {
+    let key = extract_key(&elements[1]);
+
+    let (start, end) = match (
+        extract_integer_from_resp_value(&elements[2]),
+        extract_integer_from_resp_value(&elements[3]),
+    ) {
+        (Some(start), Some(end)) => (start, end),
+        _ => return "-ERR value is not an integer or out of range\r\n".to_string(),
+    };
+
+    match storage.getrange(&key, start, end) {
+        Ok(v) => {
+            write_bulk(out, &v);
+            String::new()
+        }
+        Err(e) => format!("-{}\r\n", e),
+    }
+}
+
+fn handle_append(elements: &[RespValue], storage: &Storage) -> String {
+    let key = extract_key(&elements[1]);
+    let value = match &elements[2] {
+        RespValue::BulkString(Some(v)) => v.clone(),
+        RespValue::SimpleString(v) => v.as_bytes().to_vec(),
+        _ => return "-ERR Invalid value type\r\n".to_string(),
+    };
+
+    match storage.append(key, &value, PROTO_MAX_BULK_LEN) {
+        Ok(len) => integer(len as i64),
+        Err(e) => format!("-{}\r\n", e),
+    }
+}
+
+fn handle_strlen(elements: &[RespValue], storage: &Storage) -> String {
+    match storage.strlen(&extract_key(&elements[1])) {
+        Ok(len) => integer(len as i64),
+        Err(e) => format!("-{}\r\n", e),
+    }
+}
+
+fn handle_setrange(elements: &[RespValue], storage: &Storage) -> String {
+    let key = extract_key(&elements[1]);
+
+    let offset = match extract_integer_from_resp_value(&elements[2]) {
+        Some(offset) if offset >= 0 => offset as usize,
+        Some(_) => return "-ERR offset is out of range\r\n".to_string(),
+        None => return "-ERR value is not an integer or out of range\r\n".to_string(),
+    };
+
+    let value = match &elements[3] {
+        RespValue::BulkString(Some(v)) => v.clone(),
+        RespValue::SimpleString(v) => v.as_bytes().to_vec(),
+        _ => return "-ERR Invalid value type\r\n".to_string(),
+    };
+
+    if !value.is_empty() && offset.saturating_add(value.len()) > PROTO_MAX_BULK_LEN {
+        return "-ERR string exceeds maximum allowed size (proto-max-bulk-len)\r\n".to_string();
+    }
+
+    match storage.setrange(key, offset, &value) {
+        Ok(len) => integer(len as i64),
+        Err(e) => format!("-{}\r\n", e),
+    }
+}
+
+fn handle_setbit(elements: &[RespValue], storage: &Storage) -> String {
+    let key = extract_key(&elements[1]);
+
+    // Same bound as SETRANGE: the string may grow to proto-max-bulk-len.
+    let offset = match extract_integer_from_resp_value(&elements[2]) {
+        Some(offset) if offset >= 0 && (offset as u64) < PROTO_MAX_BULK_LEN as u64 * 8 => {
+            offset as usize
+        }
+        _ => return "-ERR bit offset is not an integer or out of range\r\n".to_string(),
+    };
+    let on = match extract_integer_from_resp_value(&elements[3]) {
+        Some(0) => false,
+        Some(1) => true,
+        _ => return "-ERR bit is not an integer or out of range\r\n".to_string(),
+    };
+
+    match storage.setbit(key, offset, on) {
+        Ok(previous) => integer(previous as i64),
+        Err(e) => format!("-{}\r\n", e),
+    }
+}
+
+/// `delta` is `None` when the increment argument was not a valid integer, or
+/// could not be negated for DECRBY.
+fn handle_incr_by(storage: &Storage, key: &RespValue, delta: Option<i64>) -> String {
+    let Some(delta) = delta else {
+        return "-ERR value is not an integer or out of range\r\n".to_string();
+    };
+
+    match storage.incr_by(extract_key(key), delta) {
+        Ok(n) => integer(n),
+        Err(e) => format!("-{}\r\n", e),
+    }
+}
+
+fn parse_increment(value: &RespValue) -> Option<i64> {
+    match value {
+        RespValue::BulkString(Some(bytes)) => parse_strict_i64(bytes),
+        RespValue::SimpleString(s) => parse_strict_i64(s.as_bytes()),
+        RespValue::Integer(i) => Some(*i),
+        _ => None,
+    }
+}
+
+/// `SET key value [NX | XX] [GET] [EX seconds | PX milliseconds | KEEPTTL]`.
+///
+/// NX and XX exclude each other, as do EX, PX and KEEPTTL; any other
+/// combination of options is allowed, in any order. The expire time is
+/// validated before the key is looked at. When NX or XX stops the write,
+/// nothing about the key changes, TTL included. With GET the reply is the
+/// old value (or null) whether or not the write happened; without it,
+/// a stopped write replies null instead of OK. Plain SETs with neither a
+/// TTL option nor KEEPTTL get default-ttl-seconds, if set.
+fn handle_set(
+    elements: &[RespValue],
+    storage: &Storage,
+    settings: &Settings,
+    out: &mut Vec<u8>,
+) -> String {
+    let key = extract_key(&elements[1]);
+
+    let value = match &elements[2] {
+        RespValue::BulkString(Some(v)) => v.clone(),
+        RespValue::SimpleString(v) => v.as_bytes().to_vec(),
+        _ => return "-ERR Invalid value type\r\n".to_string(),
+    };
+
+    let mut i = 3;
+    let mut condition = SetCondition::Always;
+    let mut get = false;
+    let mut expiration: Option<(u64, bool)> = None;
+    let mut keep_ttl = false;
+
+    while i < elements.len() {
+        let option = extract_command_name(&elements[i]);
+
+        match option.as_str() {
+            "NX" | "XX" if condition != SetCondition::Always => {
+                return "-ERR syntax error\r\n".to_string()
+            }
+            "NX" => {
+                condition = SetCondition::IfMissing;
+                i += 1;
+            }
+            "XX" => {
+                condition = SetCondition::IfExists;
+                i += 1;
+            }
+            "GET" => {
+                get = true;
+                i += 1;
             }
-            "PX" => {
+            "EX" | "PX" if expiration.is_some() || keep_ttl => {
+                return "-ERR syntax error\r\n".to_string()
+            }
+            "EX" | "PX" => {
                 if i + 1 >= elements.len() {
                     return "-ERR syntax error\r\n".to_string();
                 }
 
-                let milliseconds = match extract_integer_from_resp_value(&elements[i + 1]) {
+                let amount = match extract_integer_from_resp_value(&elements[i + 1]) {
                     Some(s) if s > 0 => s as u64,
-                    _ => return "-ERR invalid expire time in 'SET' command\r\n".to_string(),
+                    _ => return "-ERR invalid expire time in 'set' command\r\n".to_string(),
                 };
-                expiration = Some((milliseconds, true));
+                expiration = Some((amount, option == "PX"));
                 i += 2;
             }
-            _ => {
-                return format!(
-                    "-ERR syntax error, unexpected option '{}'. Only 'EX' or 'PX' are allowed",
-                    option
-                );
+            "KEEPTTL" if expiration.is_some() => return "-ERR syntax error\r\n".to_string(),
+            "KEEPTTL" => {
+                keep_ttl = true;
+                i += 1;
+            }
+            _ => return "-ERR syntax error\r\n".to_string(),
+        }
+    }
+    if !keep_ttl && expiration.is_none() && settings.default_ttl_seconds > 0 {
+        expiration = Some((settings.default_ttl_seconds, false));
+    }
+
+    let ttl = match expiration {
+        Some((amount, is_millis)) => {
+            let milliseconds = if is_millis {
+                Some(amount)
+            } else {
+                amount.checked_mul(1000)
+            };
+            match milliseconds {
+                Some(milliseconds) => SetTtl::Millis(jittered_millis(
+                    milliseconds,
+                    settings.expire_jitter,
+                    rng::next_u64(),
+                )),
+                None => return "-ERR invalid expire time in 'set' command\r\n".to_string(),
             }
         }
+        None if keep_ttl => SetTtl::Keep,
+        None => SetTtl::Clear,
+    };
+
+    match storage.set_with(key, value, condition, ttl, get) {
+        Ok((_, Some(old))) => {
+            write_bulk(out, &old);
+            String::new()
+        }
+        Ok((true, None)) if !get => "+OK\r\n".to_string(),
+        Ok(_) => "$-1\r\n".to_string(),
+        Err(e) if e.starts_with("WRONGTYPE") => format!("-{}\r\n", e),
+        Err(_) => "-ERR invalid expire time in 'set' command\r\n".to_string(),
     }
+}
+
+fn handle_expire(elements: &[RespValue], storage: &Storage, unit_ms: i64) -> String {
+    let command = extract_command_name(&elements[0]).to_lowercase();
+    let key = extract_key(&elements[1]);
 
-    match expiration {
-        Some((seconds, false)) => storage.set_ex(key, value, seconds),
-        Some((milliseconds, true)) => storage.set_px(key, value, milliseconds),
-        _ => storage.set(key, value),
+    let ttl = match extract_integer_from_resp_value(&elements[2]) {
+        Some(ttl) => ttl,
+        None => return "-ERR value is not an integer or out of range\r\n".to_string(),
+    };
+
+    let result = ttl
+        .checked_mul(unit_ms)
+        .ok_or_else(|| "overflow".to_string())
+        .and_then(|milliseconds| storage.expire(&key, milliseconds));
+
+    match result {
+        Ok(set) => integer(set as i64),
+        Err(_) => format!("-ERR invalid expire time in '{}' command\r\n", command),
     }
+}
 
-    "+OK\r\n".to_string()
+/// TTL and friends: -2 for a missing key, -1 for a key without a TTL, else
+/// the time left or the absolute expiry, in seconds or milliseconds.
+fn handle_ttl(elements: &[RespValue], storage: &Storage, millis: bool, absolute: bool) -> String {
+    let expire_at = match storage.expire_time(&extract_key(&elements[1])) {
+        None => return integer(-2),
+        Some(None) => return integer(-1),
+        Some(Some(at)) => at,
+    };
+
+    let ms = if absolute {
+        expire_at
+    } else {
+        let now = storage
+            .now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since_epoch| since_epoch.as_millis() as u64);
+        expire_at.saturating_sub(now)
+    };
+    match (millis, absolute) {
+        (true, _) => integer(ms as i64),
+        (false, true) => integer((ms / 1000) as i64),
+        // Redis rounds the remaining seconds to the nearest.
+        (false, false) => integer(((ms + 500) / 1000) as i64),
+    }
 }
 
 fn handle_list_push<F>(elements: &[RespValue], push_fn: F) -> String
 where
     F: FnOnce(String, Vec<Vec<u8>>) -> Result<usize, String>,
 {
-    if elements.len() < 3 {
-        return "-ERR wrong number of arguments for command\r\n".to_string();
-    };
-
     let key = extract_key(&elements[1]);
 
     let values: Result<Vec<Vec<u8>>, String> = elements[2..]
@@ -245,7 +1443,7 @@ where
     };
 
     match push_fn(key, values) {
-        Ok(len) => format!(":{}\r\n", len),
+        Ok(len) => integer(len as i64),
         Err(msg) => format!("-{}\r\n", msg),
     }
 }
@@ -259,10 +1457,6 @@ fn handle_lpush(elements: &[RespValue], storage: &Storage) -> String {
 }
 
 fn handle_lrange(elements: &[RespValue], storage: &Storage) -> String {
-    if elements.len() != 4 {
-        return "-ERR wrong number of arguments for command\r\n".to_string();
-    };
-
     let key = extract_key(&elements[1]);
 
     let start = match extract_integer_from_resp_value(&elements[2]) {
@@ -282,14 +1476,10 @@ fn handle_lrange(elements: &[RespValue], storage: &Storage) -> String {
 }
 
 fn handle_llen(elements: &[RespValue], storage: &Storage) -> String {
-    if elements.len() != 2 {
-        return "-ERR wrong number of arguments for command\r\n".to_string();
-    }
-
     let key = extract_key(&elements[1]);
 
     match storage.llen(&key) {
-        Ok(len) => format!(":{}\r\n", len),
+        Ok(len) => integer(len as i64),
         Err(e) => format!("-{}\r\n", e),
     }
 }
@@ -319,23 +1509,120 @@ fn handle_lpop(elements: &[RespValue], storage: &Storage) -> String {
                 Err(e) => format!("-{}\r\n", e),
             }
         }
-        _ => return "-ERR wrong number of arguments for command\r\n".to_string(),
+        _ => wrong_number_of_arguments("lpop"),
     }
 }
 
-fn handle_blpop(elements: &[RespValue], storage: &Storage) -> String {
-    if elements.len() < 3 {
-        return "-ERR wrong number of arguments for command\r\n".to_string();
+/// Parses `numkeys key [key ...] LEFT|RIGHT [COUNT count]` into the keys,
+/// whether to pop from the left, and the count.
+fn parse_mpop_args(args: &[RespValue]) -> Result<(Vec<String>, bool, usize), String> {
+    let numkeys = match extract_integer_from_resp_value(&args[0]) {
+        Some(n) if n > 0 => n as usize,
+        Some(_) => return Err("ERR numkeys should be greater than 0".to_string()),
+        None => return Err("ERR value is not an integer or out of range".to_string()),
+    };
+    if numkeys >= args.len() {
+        return Err("ERR syntax error".to_string());
+    }
+    let keys = args[1..=numkeys].iter().map(extract_key).collect();
+
+    let from_left = match extract_command_name(&args[numkeys + 1]).as_str() {
+        "LEFT" => true,
+        "RIGHT" => false,
+        _ => return Err("ERR syntax error".to_string()),
+    };
+
+    let count = match &args[numkeys + 2..] {
+        [] => 1,
+        [option, count] if extract_command_name(option) == "COUNT" => {
+            match extract_integer_from_resp_value(count) {
+                Some(n) if n > 0 => n as usize,
+                _ => return Err("ERR count should be greater than 0".to_string()),
+            }
+        }
+        _ => return Err("ERR syntax error".to_string()),
+    };
+    Ok((keys, from_left, count))
+}
+
+fn format_mpop(popped: Option<ListPop>, protocol: u8) -> String {
+    match popped {
+        Some((key, elements)) => format!(
+            "*2\r\n${}\r\n{}\r\n{}",
+            key.len(),
+            key,
+            format_array(elements)
+        ),
+        None if protocol >= 3 => "_\r\n".to_string(),
+        None => "*-1\r\n".to_string(),
+    }
+}
+
+fn handle_lmpop(
+    elements: &[RespValue],
+    server: &ServerState,
+    storage: &Storage,
+    protocol: u8,
+) -> String {
+    let (keys, from_left, count) = match parse_mpop_args(&elements[1..]) {
+        Ok(args) => args,
+        Err(e) => return format!("-{}\r\n", e),
+    };
+
+    match storage.lmpop(&keys, from_left, count) {
+        Ok(popped) => {
+            // The key list is variable, so track_keys can't see which one
+            // was written.
+            if let Some((key, _)) = &popped {
+                server.clients.invalidate(key);
+            }
+            format_mpop(popped, protocol)
+        }
+        Err(e) => format!("-{}\r\n", e),
+    }
+}
+
+fn handle_blmpop(
+    elements: &[RespValue],
+    server: &ServerState,
+    storage: &Storage,
+    protocol: u8,
+) -> String {
+    let popped = parse_timeout(&elements[1]).and_then(|timeout| {
+        let (keys, from_left, count) = parse_mpop_args(&elements[2..])?;
+        storage.blmpop(keys, from_left, count, timeout)
+    });
+
+    match popped {
+        Ok(popped) => {
+            if let Some((key, _)) = &popped {
+                server.clients.invalidate(key);
+            }
+            format_mpop(popped, protocol)
+        }
+        Err(e) => format!("-{}\r\n", e),
+    }
+}
+
+/// How long a blocking command may wait, given in seconds; zero means forever.
+fn parse_timeout(value: &RespValue) -> Result<Duration, String> {
+    match extract_timeout(value) {
+        None => Err("ERR timeout must be a number".to_string()),
+        Some(timeout) if timeout < 0.0 => Err("ERR timeout is negative".to_string()),
+        // Rejects infinities and values too large for a Duration.
+        Some(timeout) => Duration::try_from_secs_f64(timeout)
+            .map_err(|_| "ERR timeout is out of range".to_string()),
     }
+}
+
+fn handle_blpop(elements: &[RespValue], storage: &Storage) -> String {
     let keys_args = &elements[..elements.len() - 1];
     let timeout_arg = &elements[elements.len() - 1];
 
-    let keys: Vec<String> = keys_args.iter().map(|arg| extract_key(arg)).collect();
-    let timeout = extract_timeout(timeout_arg);
-
-    let timeout: f64 = match timeout {
-        Some(t) => t as f64,
-        None => return "-ERR timeout must be a number\r\n".to_string(),
+    let keys: Vec<String> = keys_args.iter().map(extract_key).collect();
+    let timeout = match parse_timeout(timeout_arg) {
+        Ok(timeout) => timeout,
+        Err(e) => return format!("-{}\r\n", e),
     };
 
     match storage.blpop(keys, timeout) {
@@ -353,6 +1640,107 @@ fn handle_blpop(elements: &[RespValue], storage: &Storage) -> String {
     }
 }
 
+/// Every name the dispatcher matches on, most frequent first. Anything listed
+/// here is dispatched without allocating. Tests hold it, the dispatcher and
+/// `command_table::COMMANDS` in step.
+const DISPATCHED_COMMANDS: &[&str] = &[
+    "GET",
+    "SET",
+    "PING",
+    "ECHO",
+    "GETRANGE",
+    "SUBSTR",
+    "SETRANGE",
+    "SETBIT",
+    "APPEND",
+    "STRLEN",
+    "INCR",
+    "DECR",
+    "INCRBY",
+    "DECRBY",
+    "EXPIRE",
+    "PEXPIRE",
+    "TTL",
+    "PTTL",
+    "EXPIRETIME",
+    "PEXPIRETIME",
+    "RPUSH",
+    "LPUSH",
+    "LRANGE",
+    "LLEN",
+    "LPOP",
+    "LMPOP",
+    "BLPOP",
+    "BLMPOP",
+    "TYPE",
+    "EXISTS",
+    "DEL",
+    "COPY",
+    "MOVE",
+    "FLUSHDB",
+    "DBSIZE",
+    "FLUSHALL",
+    "SCAN",
+    "KEYS",
+    "XADD",
+    "XRANGE",
+    "XREAD",
+    "INFO",
+    "DEBUG",
+    "OBJECT",
+    "MEMORY",
+    "SELECT",
+    "MULTI",
+    "EXEC",
+    "DISCARD",
+    "WATCH",
+    "UNWATCH",
+    "COMMAND",
+    "SUBSCRIBE",
+    "SSUBSCRIBE",
+    "PSUBSCRIBE",
+    "UNSUBSCRIBE",
+    "SUNSUBSCRIBE",
+    "PUNSUBSCRIBE",
+    "PUBLISH",
+    "SPUBLISH",
+    "HELLO",
+    "RESET",
+    "AUTH",
+    "CONFIG",
+    "CLIENT",
+    "CLUSTER",
+];
+
+/// Longest name in `DISPATCHED_COMMANDS`, rounded up.
+const MAX_COMMAND_NAME_LEN: usize = 16;
+
+/// Upper-cased command name for dispatch. Known commands are upper-cased in a
+/// stack buffer and borrowed from `DISPATCHED_COMMANDS`; only unknown names
+/// allocate, and only because the error reply echoes them.
+fn dispatch_name(value: &RespValue) -> Cow<'static, str> {
+    let name: &[u8] = match value {
+        RespValue::BulkString(Some(cmd)) => cmd,
+        RespValue::SimpleString(cmd) => cmd.as_bytes(),
+        _ => b"",
+    };
+
+    if !name.is_empty() && name.len() <= MAX_COMMAND_NAME_LEN {
+        let mut buf = [0u8; MAX_COMMAND_NAME_LEN];
+        let upper = &mut buf[..name.len()];
+        upper.copy_from_slice(name);
+        upper.make_ascii_uppercase();
+        if let Some(known) = DISPATCHED_COMMANDS
+            .iter()
+            .find(|known| known.as_bytes() == &*upper)
+        {
+            return Cow::Borrowed(known);
+        }
+    }
+
+    Cow::Owned(extract_command_name(value))
+}
+
 fn extract_command_name(value: &RespValue) -> String {
     match value {
         RespValue::BulkString(Some(cmd)) => String::from_utf8_lossy(cmd).to_uppercase(),
@@ -365,7 +1753,7 @@ fn extract_key(key_candidate: &RespValue) -> String {
     match key_candidate {
         RespValue::BulkString(Some(s)) => String::from_utf8_lossy(s).to_string(),
         RespValue::SimpleString(s) => s.clone(),
-        _ => return "-ERR Invalid key type\r\n".to_string(),
+        _ => "-ERR Invalid key type\r\n".to_string(),
     }
 }
 
@@ -380,12 +1768,92 @@ fn extract_integer_from_resp_value(value: &RespValue) -> Option<i64> {
 
 fn extract_timeout(value: &RespValue) -> Option<f64> {
     match value {
-        RespValue::BulkString(Some(bytes)) => String::from_utf8_lossy(bytes).parse::<f64>().ok(),
-        RespValue::SimpleString(s) => s.parse::<f64>().ok(),
+        RespValue::BulkString(Some(bytes)) => parse_redis_float(bytes).ok(),
+        RespValue::SimpleString(s) => parse_redis_float(s.as_bytes()).ok(),
         _ => None,
     }
 }
 
+/// Parses a memory size the way Redis config does: a plain byte count or one
+/// with a `k`, `kb`, `m`, `mb`, `g` or `gb` suffix in any case. The bare
+/// letters are powers of 1000, the `b` forms powers of 1024.
+fn parse_memory(value: &str) -> Option<u64> {
+    let lower = value.to_ascii_lowercase();
+    let split = lower
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(lower.len());
+    let (digits, unit) = lower.split_at(split);
+    let multiplier: u64 = match unit {
+        "" | "b" => 1,
+        "k" => 1000,
+        "kb" => 1024,
+        "m" => 1000 * 1000,
+        "mb" => 1024 * 1024,
+        "g" => 1000 * 1000 * 1000,
+        "gb" => 1024 * 1024 * 1024,
+        _ => return None,
+    };
+    if digits.is_empty() {
+        return None;
+    }
+    digits.parse::<u64>().ok()?.checked_mul(multiplier)
+}
+
+/// Parses a float the way Redis does: `inf`, `+inf` and `-inf` are accepted,
+/// while NaN, empty input and surrounding whitespace are rejected.
+fn parse_redis_float(bytes: &[u8]) -> Result<f64, String> {
+    let invalid = || "ERR value is not a valid float".to_string();
+
+    let s = std::str::from_utf8(bytes).map_err(|_| invalid())?;
+    if s.is_empty() || s.trim() != s {
+        return Err(invalid());
+    }
+
+    match s.parse::<f64>() {
+        Ok(f) if !f.is_nan() => Ok(f),
+        _ => Err(invalid()),
+    }
+}
+
+/// RESP integer reply. Absent values are a null reply, never a sentinel integer.
+fn integer(n: i64) -> String {
+    format!(":{}\r\n", n)
+}
+
+fn wrong_number_of_arguments(command: &str) -> String {
+    format!(
+        "-ERR wrong number of arguments for '{}' command\r\n",
+        command.to_lowercase()
+    )
+}
+
+fn format_command_specs<'a>(
+    specs: impl ExactSizeIterator<Item = Option<&'a command_table::CommandSpec>>,
+) -> String {
+    let mut out = format!("*{}\r\n", specs.len());
+    for spec in specs {
+        let spec = match spec {
+            Some(spec) => spec,
+            None => {
+                out.push_str("*-1\r\n");
+                continue;
+            }
+        };
+
+        out.push_str("*6\r\n");
+        out.push_str(&format!("${}\r\n{}\r\n", spec.name.len(), spec.name));
+        out.push_str(&integer(spec.arity));
+        out.push_str(&format!("*{}\r\n", spec.flags.len()));
+        for flag in spec.flags {
+            out.push_str(&format!("+{}\r\n", flag));
+        }
+        out.push_str(&integer(spec.first_key));
+        out.push_str(&integer(spec.last_key));
+        out.push_str(&integer(spec.step));
+    }
+    out
+}
+
 fn format_array(items: Vec<Vec<u8>>) -> String {
     if items.is_empty() {
         return "*0\r\n".to_string();
@@ -398,16 +1866,13 @@ fn format_array(items: Vec<Vec<u8>>) -> String {
     format!("*{}\r\n{}", items.len(), elements.join(""))
 }
 
-fn format_xread(stream_name: &str, items: Vec<Vec<Vec<u8>>>) -> String {
-    if items.is_empty() {
-        return "*0\r\n".to_string();
+fn format_xread(results: Vec<StreamRead>) -> String {
+    let mut out = format!("*{}\r\n", results.len());
+    for (stream_name, items) in results {
+        out.push_str("*2\r\n");
+        out.push_str(&format!("${}\r\n{}\r\n", stream_name.len(), &stream_name));
+        out.push_str(&format_xrange(items));
     }
-
-    let mut out = String::new();
-    out.push_str(&format!("*1\r\n",)); // only works for 1 stream xread
-    out.push_str(&format!("*2\r\n",));
-    out.push_str(&format!("${}\r\n{}\r\n", stream_name.len(), &stream_name));
-    out.push_str(&format_xrange(items));
     out
 }
 
@@ -450,74 +1915,97 @@ fn format_xrange(items: Vec<Vec<Vec<u8>>>) -> String {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::clock::{Clock, TestClock};
+    use crate::{Config, PushSink};
     use std::{
+        sync::{Arc, Mutex},
         thread::sleep,
         time::{Duration, SystemTime, UNIX_EPOCH},
     };
 
+    // Shadows the real handler so tests can compare replies with string
+    // literals. Tests that care about raw bytes call super::handle_command.
+    fn handle_command(
+        value: &RespValue,
+        server: &ServerState,
+        conn: &mut ConnectionState,
+    ) -> String {
+        String::from_utf8(super::handle_command(value, server, conn)).unwrap()
+    }
+
     #[test]
     fn test_ping_command_returns_pong() {
-        let storage = Storage::new();
+        let server = ServerState::new();
+        let mut conn = ConnectionState::new();
 
         let cmd = RespValue::Array(Some(vec![RespValue::BulkString(Some(b"PING".to_vec()))]));
-        assert_eq!(handle_command(&cmd, &storage), "+PONG\r\n")
+        assert_eq!(handle_command(&cmd, &server, &mut conn), "+PONG\r\n")
     }
     #[test]
     fn test_ping_command_handles_case_insensibly() {
-        let storage = Storage::new();
+        let server = ServerState::new();
+        let mut conn = ConnectionState::new();
 
         let cmd = RespValue::Array(Some(vec![RespValue::BulkString(Some(b"Ping".to_vec()))]));
-        assert_eq!(handle_command(&cmd, &storage), "+PONG\r\n")
+        assert_eq!(handle_command(&cmd, &server, &mut conn), "+PONG\r\n")
     }
 
     #[test]
     fn test_echo_command_works_with_bulk_string() {
-        let storage = Storage::new();
+        let server = ServerState::new();
+        let mut conn = ConnectionState::new();
 
         let cmd = RespValue::Array(Some(vec![
             RespValue::BulkString(Some(b"ECHO".to_vec())),
             RespValue::BulkString(Some(b"Hello".to_vec())),
         ]));
-        assert_eq!(handle_command(&cmd, &storage), "$5\r\nHello\r\n")
+        assert_eq!(handle_command(&cmd, &server, &mut conn), "$5\r\nHello\r\n")
     }
 
     #[test]
     fn test_echo_command_works_with_simple_string() {
-        let storage = Storage::new();
+        let server = ServerState::new();
+        let mut conn = ConnectionState::new();
 
         let cmd = RespValue::Array(Some(vec![
             RespValue::BulkString(Some(b"ECHO".to_vec())),
             RespValue::SimpleString("Simple_hello".to_string()),
         ]));
-        assert_eq!(handle_command(&cmd, &storage), "$12\r\nSimple_hello\r\n")
+        assert_eq!(
+            handle_command(&cmd, &server, &mut conn),
+            "$12\r\nSimple_hello\r\n"
+        )
     }
 
     #[test]
     fn test_echo_command_returns_error_for_invalid_number_of_arguments() {
-        let storage = Storage::new();
+        let server = ServerState::new();
+        let mut conn = ConnectionState::new();
 
         let cmd = RespValue::Array(Some(vec![RespValue::BulkString(Some(b"ECHO".to_vec()))]));
         assert_eq!(
-            handle_command(&cmd, &storage),
+            handle_command(&cmd, &server, &mut conn),
             "-ERR wrong number of arguments for 'echo' command\r\n"
         )
     }
 
     #[test]
     fn test_set_command_works() {
-        let storage = Storage::new();
+        let server = ServerState::new();
+        let mut conn = ConnectionState::new();
 
         let cmd = RespValue::Array(Some(vec![
             RespValue::BulkString(Some(b"SET".to_vec())),
             RespValue::BulkString(Some(b"key".to_vec())),
             RespValue::BulkString(Some(b"value".to_vec())),
         ]));
-        assert_eq!(handle_command(&cmd, &storage), "+OK\r\n")
+        assert_eq!(handle_command(&cmd, &server, &mut conn), "+OK\r\n")
     }
 
     #[test]
     fn test_set_command_is_idempotent() {
-        let storage = Storage::new();
+        let server = ServerState::new();
+        let mut conn = ConnectionState::new();
 
         let cmd = RespValue::Array(Some(vec![
             RespValue::BulkString(Some(b"SET".to_vec())),
@@ -525,13 +2013,14 @@ mod tests {
             RespValue::BulkString(Some(b"value".to_vec())),
         ]));
 
-        handle_command(&cmd, &storage);
-        assert_eq!(handle_command(&cmd, &storage), "+OK\r\n")
+        handle_command(&cmd, &server, &mut conn);
+        assert_eq!(handle_command(&cmd, &server, &mut conn), "+OK\r\n")
     }
 
     #[test]
     fn test_set_command_changes_value_if_called_twice_with_different_values() {
-        let storage = Storage::new();
+        let server = ServerState::new();
+        let mut conn = ConnectionState::new();
 
         let cmd1 = RespValue::Array(Some(vec![
             RespValue::BulkString(Some(b"SET".to_vec())),
@@ -545,50 +2034,60 @@ mod tests {
             RespValue::BulkString(Some(b"value-new".to_vec())),
         ]));
 
-        assert_eq!(handle_command(&cmd1, &storage), "+OK\r\n");
-        assert_eq!(handle_command(&cmd2, &storage), "+OK\r\n");
+        assert_eq!(handle_command(&cmd1, &server, &mut conn), "+OK\r\n");
+        assert_eq!(handle_command(&cmd2, &server, &mut conn), "+OK\r\n");
 
         let cmd_get = RespValue::Array(Some(vec![
             RespValue::BulkString(Some(b"GET".to_vec())),
             RespValue::BulkString(Some(b"key".to_vec())),
         ]));
 
-        assert_eq!(handle_command(&cmd_get, &storage), "$9\r\nvalue-new\r\n");
+        assert_eq!(
+            handle_command(&cmd_get, &server, &mut conn),
+            "$9\r\nvalue-new\r\n"
+        );
     }
 
     #[test]
     fn test_get_command_works_for_existing_key() {
-        let storage = Storage::new();
+        let server = ServerState::new();
+        let mut conn = ConnectionState::new();
 
         let cmd_set = RespValue::Array(Some(vec![
             RespValue::BulkString(Some(b"SET".to_vec())),
             RespValue::BulkString(Some(b"key".to_vec())),
             RespValue::BulkString(Some(b"value".to_vec())),
         ]));
-        handle_command(&cmd_set, &storage);
+        handle_command(&cmd_set, &server, &mut conn);
         let cmd_get = RespValue::Array(Some(vec![
             RespValue::BulkString(Some(b"GET".to_vec())),
             RespValue::BulkString(Some(b"key".to_vec())),
         ]));
 
-        assert_eq!(handle_command(&cmd_get, &storage), "$5\r\nvalue\r\n")
+        assert_eq!(
+            handle_command(&cmd_get, &server, &mut conn),
+            "$5\r\nvalue\r\n"
+        )
     }
 
     #[test]
     fn test_get_command_returns_null_bulk_string_if_key_doesnt_exist() {
-        let storage = Storage::new();
+        let server = ServerState::new();
+        let mut conn = ConnectionState::new();
 
         let cmd_get = RespValue::Array(Some(vec![
             RespValue::BulkString(Some(b"GET".to_vec())),
             RespValue::BulkString(Some(b"key".to_vec())),
         ]));
 
-        assert_eq!(handle_command(&cmd_get, &storage), "$-1\r\n")
+        assert_eq!(handle_command(&cmd_get, &server, &mut conn), "$-1\r\n")
     }
 
     #[test]
     fn test_set_command_with_expiration_in_seconds() {
-        let storage = Storage::new();
+        let clock = TestClock::new();
+        let server = ServerState::new_with_clock(Arc::new(clock.clone()));
+        let mut conn = ConnectionState::new();
 
         let cmd_set = RespValue::Array(Some(vec![
             RespValue::BulkString(Some(b"SET".to_vec())),
@@ -598,23 +2097,27 @@ mod tests {
             RespValue::BulkString(Some(b"1".to_vec())),
         ]));
 
-        assert_eq!(handle_command(&cmd_set, &storage), "+OK\r\n");
+        assert_eq!(handle_command(&cmd_set, &server, &mut conn), "+OK\r\n");
 
         let cmd_get = RespValue::Array(Some(vec![
             RespValue::BulkString(Some(b"GET".to_vec())),
             RespValue::BulkString(Some(b"key".to_vec())),
         ]));
 
-        assert_eq!(handle_command(&cmd_get, &storage), "$5\r\nvalue\r\n");
+        assert_eq!(
+            handle_command(&cmd_get, &server, &mut conn),
+            "$5\r\nvalue\r\n"
+        );
 
-        sleep(Duration::from_millis(1100));
+        clock.advance(Duration::from_millis(1100));
 
-        assert_eq!(handle_command(&cmd_get, &storage), "$-1\r\n")
+        assert_eq!(handle_command(&cmd_get, &server, &mut conn), "$-1\r\n")
     }
 
     #[test]
     fn test_rpush_command_works() {
-        let storage = Storage::new();
+        let server = ServerState::new();
+        let mut conn = ConnectionState::new();
 
         let cmd_rpush = RespValue::Array(Some(vec![
             RespValue::BulkString(Some(b"RPUSH".to_vec())),
@@ -622,12 +2125,13 @@ mod tests {
             RespValue::BulkString(Some(b"\"element_one\"".to_vec())),
             RespValue::BulkString(Some(b"\"element_two\"".to_vec())),
         ]));
-        assert_eq!(handle_command(&cmd_rpush, &storage), ":2\r\n")
+        assert_eq!(handle_command(&cmd_rpush, &server, &mut conn), ":2\r\n")
     }
 
     #[test]
     fn test_rpush_command_appends_to_existing_list() {
-        let storage = Storage::new();
+        let server = ServerState::new();
+        let mut conn = ConnectionState::new();
 
         let cmd_rpush = RespValue::Array(Some(vec![
             RespValue::BulkString(Some(b"RPUSH".to_vec())),
@@ -635,7 +2139,7 @@ mod tests {
             RespValue::BulkString(Some(b"\"element_one\"".to_vec())),
             RespValue::BulkString(Some(b"\"element_two\"".to_vec())),
         ]));
-        assert_eq!(handle_command(&cmd_rpush, &storage), ":2\r\n");
+        assert_eq!(handle_command(&cmd_rpush, &server, &mut conn), ":2\r\n");
 
         let cmd_rpush_second = RespValue::Array(Some(vec![
             RespValue::BulkString(Some(b"RPUSH".to_vec())),
@@ -644,26 +2148,31 @@ mod tests {
             RespValue::BulkString(Some(b"\"element_four\"".to_vec())),
         ]));
 
-        assert_eq!(handle_command(&cmd_rpush_second, &storage), ":4\r\n");
+        assert_eq!(
+            handle_command(&cmd_rpush_second, &server, &mut conn),
+            ":4\r\n"
+        );
     }
 
     #[test]
     fn test_rpush_command_returns_error_on_wrong_number_of_arguments() {
-        let storage = Storage::new();
+        let server = ServerState::new();
+        let mut conn = ConnectionState::new();
 
         let cmd_rpush = RespValue::Array(Some(vec![
             RespValue::BulkString(Some(b"RPUSH".to_vec())),
             RespValue::BulkString(Some(b"list".to_vec())),
         ]));
         assert_eq!(
-            handle_command(&cmd_rpush, &storage),
-            "-ERR wrong number of arguments for command\r\n"
+            handle_command(&cmd_rpush, &server, &mut conn),
+            "-ERR wrong number of arguments for 'rpush' command\r\n"
         )
     }
 
     #[test]
     fn test_rpush_command_doesnt_work_on_keys() {
-        let storage = Storage::new();
+        let server = ServerState::new();
+        let mut conn = ConnectionState::new();
 
         let cmd_set = RespValue::Array(Some(vec![
             RespValue::BulkString(Some(b"SET".to_vec())),
@@ -671,7 +2180,7 @@ mod tests {
             RespValue::BulkString(Some(b"value".to_vec())),
         ]));
 
-        assert_eq!(handle_command(&cmd_set, &storage), "+OK\r\n");
+        assert_eq!(handle_command(&cmd_set, &server, &mut conn), "+OK\r\n");
 
         let cmd = RespValue::Array(Some(vec![
             RespValue::BulkString(Some(b"RPUSH".to_vec())),
@@ -680,14 +2189,15 @@ mod tests {
             RespValue::BulkString(Some(b"\"element_two\"".to_vec())),
         ]));
         assert_eq!(
-            handle_command(&cmd, &storage),
+            handle_command(&cmd, &server, &mut conn),
             "-WRONGTYPE Operation against a key holding the wrong kind of value\r\n"
         )
     }
 
     #[test]
     fn test_lpush_command_works() {
-        let storage = Storage::new();
+        let server = ServerState::new();
+        let mut conn = ConnectionState::new();
 
         let cmd_lpush = RespValue::Array(Some(vec![
             RespValue::BulkString(Some(b"LPUSH".to_vec())),
@@ -695,12 +2205,13 @@ mod tests {
             RespValue::BulkString(Some(b"a".to_vec())),
             RespValue::BulkString(Some(b"b".to_vec())),
         ]));
-        assert_eq!(handle_command(&cmd_lpush, &storage), ":2\r\n")
+        assert_eq!(handle_command(&cmd_lpush, &server, &mut conn), ":2\r\n")
     }
 
     #[test]
     fn test_lrange_after_lpush_returns_in_reverse_order() {
-        let storage = Storage::new();
+        let server = ServerState::new();
+        let mut conn = ConnectionState::new();
 
         let cmd_lpush = RespValue::Array(Some(vec![
             RespValue::BulkString(Some(b"LPUSH".to_vec())),
@@ -709,7 +2220,7 @@ mod tests {
             RespValue::BulkString(Some(b"b".to_vec())),
             RespValue::BulkString(Some(b"c".to_vec())),
         ]));
-        assert_eq!(handle_command(&cmd_lpush, &storage), ":3\r\n");
+        assert_eq!(handle_command(&cmd_lpush, &server, &mut conn), ":3\r\n");
 
         let cmd_lrange = RespValue::Array(Some(vec![
             RespValue::BulkString(Some(b"LRANGE".to_vec())),
@@ -719,14 +2230,15 @@ mod tests {
         ]));
 
         assert_eq!(
-            handle_command(&cmd_lrange, &storage),
+            handle_command(&cmd_lrange, &server, &mut conn),
             "*3\r\n$1\r\nc\r\n$1\r\nb\r\n$1\r\na\r\n"
         );
     }
 
     #[test]
     fn test_lpush_command_appends_to_existing_list() {
-        let storage = Storage::new();
+        let server = ServerState::new();
+        let mut conn = ConnectionState::new();
 
         let cmd_lpush = RespValue::Array(Some(vec![
             RespValue::BulkString(Some(b"LPUSH".to_vec())),
@@ -734,7 +2246,7 @@ mod tests {
             RespValue::BulkString(Some(b"a".to_vec())),
             RespValue::BulkString(Some(b"b".to_vec())),
         ]));
-        assert_eq!(handle_command(&cmd_lpush, &storage), ":2\r\n");
+        assert_eq!(handle_command(&cmd_lpush, &server, &mut conn), ":2\r\n");
 
         let cmd_lpush_second = RespValue::Array(Some(vec![
             RespValue::BulkString(Some(b"LPUSH".to_vec())),
@@ -743,26 +2255,31 @@ mod tests {
             RespValue::BulkString(Some(b"d".to_vec())),
         ]));
 
-        assert_eq!(handle_command(&cmd_lpush_second, &storage), ":4\r\n");
+        assert_eq!(
+            handle_command(&cmd_lpush_second, &server, &mut conn),
+            ":4\r\n"
+        );
     }
 
     #[test]
     fn test_lpush_command_returns_error_on_wrong_number_of_arguments() {
-        let storage = Storage::new();
+        let server = ServerState::new();
+        let mut conn = ConnectionState::new();
 
         let cmd_lpush = RespValue::Array(Some(vec![
             RespValue::BulkString(Some(b"LPUSH".to_vec())),
             RespValue::BulkString(Some(b"list".to_vec())),
         ]));
         assert_eq!(
-            handle_command(&cmd_lpush, &storage),
-            "-ERR wrong number of arguments for command\r\n"
+            handle_command(&cmd_lpush, &server, &mut conn),
+            "-ERR wrong number of arguments for 'lpush' command\r\n"
         )
     }
 
     #[test]
     fn test_llen_command_returns_len_for_list() {
-        let storage = Storage::new();
+        let server = ServerState::new();
+        let mut conn = ConnectionState::new();
 
         let cmd_lpush = RespValue::Array(Some(vec![
             RespValue::BulkString(Some(b"LPUSH".to_vec())),
@@ -771,31 +2288,33 @@ mod tests {
             RespValue::BulkString(Some(b"d".to_vec())),
         ]));
 
-        assert_eq!(handle_command(&cmd_lpush, &storage), ":2\r\n");
+        assert_eq!(handle_command(&cmd_lpush, &server, &mut conn), ":2\r\n");
 
         let cmd_llen = RespValue::Array(Some(vec![
             RespValue::BulkString(Some(b"LLEN".to_vec())),
             RespValue::BulkString(Some(b"list".to_vec())),
         ]));
 
-        assert_eq!(handle_command(&cmd_llen, &storage), ":2\r\n")
+        assert_eq!(handle_command(&cmd_llen, &server, &mut conn), ":2\r\n")
     }
 
     #[test]
     fn test_llen_command_returns_zero_for_nonexisting_list() {
-        let storage = Storage::new();
+        let server = ServerState::new();
+        let mut conn = ConnectionState::new();
 
         let cmd_llen = RespValue::Array(Some(vec![
             RespValue::BulkString(Some(b"LLEN".to_vec())),
             RespValue::BulkString(Some(b"list".to_vec())),
         ]));
 
-        assert_eq!(handle_command(&cmd_llen, &storage), ":0\r\n")
+        assert_eq!(handle_command(&cmd_llen, &server, &mut conn), ":0\r\n")
     }
 
     #[test]
     fn test_llen_command_returns_error_on_wrong_number_of_arguments() {
-        let storage = Storage::new();
+        let server = ServerState::new();
+        let mut conn = ConnectionState::new();
 
         let cmd_llen = RespValue::Array(Some(vec![
             RespValue::BulkString(Some(b"LLEN".to_vec())),
@@ -804,14 +2323,15 @@ mod tests {
         ]));
 
         assert_eq!(
-            handle_command(&cmd_llen, &storage),
-            "-ERR wrong number of arguments for command\r\n"
+            handle_command(&cmd_llen, &server, &mut conn),
+            "-ERR wrong number of arguments for 'llen' command\r\n"
         )
     }
 
     #[test]
     fn test_lpush_command_doesnt_work_on_keys() {
-        let storage = Storage::new();
+        let server = ServerState::new();
+        let mut conn = ConnectionState::new();
 
         let cmd_set = RespValue::Array(Some(vec![
             RespValue::BulkString(Some(b"SET".to_vec())),
@@ -819,7 +2339,7 @@ mod tests {
             RespValue::BulkString(Some(b"value".to_vec())),
         ]));
 
-        assert_eq!(handle_command(&cmd_set, &storage), "+OK\r\n");
+        assert_eq!(handle_command(&cmd_set, &server, &mut conn), "+OK\r\n");
 
         let cmd_lpush = RespValue::Array(Some(vec![
             RespValue::BulkString(Some(b"LPUSH".to_vec())),
@@ -828,14 +2348,15 @@ mod tests {
             RespValue::BulkString(Some(b"\"element_two\"".to_vec())),
         ]));
         assert_eq!(
-            handle_command(&cmd_lpush, &storage),
+            handle_command(&cmd_lpush, &server, &mut conn),
             "-WRONGTYPE Operation against a key holding the wrong kind of value\r\n"
         )
     }
 
     #[test]
     fn test_lrange_command_works() {
-        let storage = Storage::new();
+        let server = ServerState::new();
+        let mut conn = ConnectionState::new();
 
         let cmd_rpush = RespValue::Array(Some(vec![
             RespValue::BulkString(Some(b"RPUSH".to_vec())),
@@ -843,7 +2364,7 @@ mod tests {
             RespValue::BulkString(Some(b"\"element_one\"".to_vec())),
             RespValue::BulkString(Some(b"\"element_two\"".to_vec())),
         ]));
-        assert_eq!(handle_command(&cmd_rpush, &storage), ":2\r\n");
+        assert_eq!(handle_command(&cmd_rpush, &server, &mut conn), ":2\r\n");
 
         let cmd_lrange = RespValue::Array(Some(vec![
             RespValue::BulkString(Some(b"LRANGE".to_vec())),
@@ -853,14 +2374,15 @@ mod tests {
         ]));
 
         assert_eq!(
-            handle_command(&cmd_lrange, &storage),
+            handle_command(&cmd_lrange, &server, &mut conn),
             "*2\r\n$13\r\n\"element_one\"\r\n$13\r\n\"element_two\"\r\n"
         );
     }
 
     #[test]
     fn test_lrange_command_return_empty_array_if_list_doenst_exist() {
-        let storage = Storage::new();
+        let server = ServerState::new();
+        let mut conn = ConnectionState::new();
 
         let cmd_rpush = RespValue::Array(Some(vec![
             RespValue::BulkString(Some(b"RPUSH".to_vec())),
@@ -868,7 +2390,7 @@ mod tests {
             RespValue::BulkString(Some(b"\"element_one\"".to_vec())),
             RespValue::BulkString(Some(b"\"element_two\"".to_vec())),
         ]));
-        assert_eq!(handle_command(&cmd_rpush, &storage), ":2\r\n");
+        assert_eq!(handle_command(&cmd_rpush, &server, &mut conn), ":2\r\n");
 
         let cmd_lrange = RespValue::Array(Some(vec![
             RespValue::BulkString(Some(b"LRANGE".to_vec())),
@@ -877,12 +2399,13 @@ mod tests {
             RespValue::Integer(1),
         ]));
 
-        assert_eq!(handle_command(&cmd_lrange, &storage), "*0\r\n");
+        assert_eq!(handle_command(&cmd_lrange, &server, &mut conn), "*0\r\n");
     }
 
     #[test]
     fn test_lrange_command_return_empty_array_if_start_is_bigger_than_end() {
-        let storage = Storage::new();
+        let server = ServerState::new();
+        let mut conn = ConnectionState::new();
 
         let cmd_rpush = RespValue::Array(Some(vec![
             RespValue::BulkString(Some(b"RPUSH".to_vec())),
@@ -890,7 +2413,7 @@ mod tests {
             RespValue::BulkString(Some(b"\"element_one\"".to_vec())),
             RespValue::BulkString(Some(b"\"element_two\"".to_vec())),
         ]));
-        assert_eq!(handle_command(&cmd_rpush, &storage), ":2\r\n");
+        assert_eq!(handle_command(&cmd_rpush, &server, &mut conn), ":2\r\n");
 
         let cmd_lrange = RespValue::Array(Some(vec![
             RespValue::BulkString(Some(b"LRANGE".to_vec())),
@@ -899,19 +2422,20 @@ mod tests {
             RespValue::Integer(1),
         ]));
 
-        assert_eq!(handle_command(&cmd_lrange, &storage), "*0\r\n");
+        assert_eq!(handle_command(&cmd_lrange, &server, &mut conn), "*0\r\n");
     }
 
     #[test]
     fn test_lrange_command_return_empty_array_if_start_is_bigger_than_len() {
-        let storage = Storage::new();
+        let server = ServerState::new();
+        let mut conn = ConnectionState::new();
 
         let cmd_rpush = RespValue::Array(Some(vec![
             RespValue::BulkString(Some(b"RPUSH".to_vec())),
             RespValue::BulkString(Some(b"list".to_vec())),
             RespValue::BulkString(Some(b"\"element_one\"".to_vec())),
         ]));
-        assert_eq!(handle_command(&cmd_rpush, &storage), ":1\r\n");
+        assert_eq!(handle_command(&cmd_rpush, &server, &mut conn), ":1\r\n");
 
         let cmd_lrange = RespValue::Array(Some(vec![
             RespValue::BulkString(Some(b"LRANGE".to_vec())),
@@ -920,12 +2444,13 @@ mod tests {
             RespValue::Integer(3),
         ]));
 
-        assert_eq!(handle_command(&cmd_lrange, &storage), "*0\r\n");
+        assert_eq!(handle_command(&cmd_lrange, &server, &mut conn), "*0\r\n");
     }
 
     #[test]
     fn test_lrange_command_len_become_end_if_end_is_bigger() {
-        let storage = Storage::new();
+        let server = ServerState::new();
+        let mut conn = ConnectionState::new();
 
         let cmd_rpush = RespValue::Array(Some(vec![
             RespValue::BulkString(Some(b"RPUSH".to_vec())),
@@ -933,7 +2458,7 @@ mod tests {
             RespValue::BulkString(Some(b"\"element_one\"".to_vec())),
             RespValue::BulkString(Some(b"\"element_two\"".to_vec())),
         ]));
-        assert_eq!(handle_command(&cmd_rpush, &storage), ":2\r\n");
+        assert_eq!(handle_command(&cmd_rpush, &server, &mut conn), ":2\r\n");
 
         let cmd_lrange = RespValue::Array(Some(vec![
             RespValue::BulkString(Some(b"LRANGE".to_vec())),
@@ -943,14 +2468,15 @@ mod tests {
         ]));
 
         assert_eq!(
-            handle_command(&cmd_lrange, &storage),
+            handle_command(&cmd_lrange, &server, &mut conn),
             "*2\r\n$13\r\n\"element_one\"\r\n$13\r\n\"element_two\"\r\n"
         );
     }
 
     #[test]
     fn test_lrange_command_doesnt_work_on_keys() {
-        let storage = Storage::new();
+        let server = ServerState::new();
+        let mut conn = ConnectionState::new();
 
         let cmd_set = RespValue::Array(Some(vec![
             RespValue::BulkString(Some(b"SET".to_vec())),
@@ -958,7 +2484,7 @@ mod tests {
             RespValue::BulkString(Some(b"value".to_vec())),
         ]));
 
-        assert_eq!(handle_command(&cmd_set, &storage), "+OK\r\n");
+        assert_eq!(handle_command(&cmd_set, &server, &mut conn), "+OK\r\n");
 
         let cmd = RespValue::Array(Some(vec![
             RespValue::BulkString(Some(b"LRANGE".to_vec())),
@@ -967,27 +2493,29 @@ mod tests {
             RespValue::Integer(1),
         ]));
         assert_eq!(
-            handle_command(&cmd, &storage),
+            handle_command(&cmd, &server, &mut conn),
             "-WRONGTYPE Operation against a key holding the wrong kind of value\r\n"
         )
     }
 
     #[test]
     fn test_lrange_command_with_missing_arguments() {
-        let storage = Storage::new();
+        let server = ServerState::new();
+        let mut conn = ConnectionState::new();
         let cmd = RespValue::Array(Some(vec![
             RespValue::BulkString(Some(b"LRANGE".to_vec())),
             RespValue::BulkString(Some(b"list".to_vec())),
         ]));
         assert_eq!(
-            handle_command(&cmd, &storage),
-            "-ERR wrong number of arguments for command\r\n"
+            handle_command(&cmd, &server, &mut conn),
+            "-ERR wrong number of arguments for 'lrange' command\r\n"
         );
     }
 
     #[test]
     fn test_lpop_command_returns_popped_element() {
-        let storage = Storage::new();
+        let server = ServerState::new();
+        let mut conn = ConnectionState::new();
 
         let cmd_rpush = RespValue::Array(Some(vec![
             RespValue::BulkString(Some(b"RPUSH".to_vec())),
@@ -996,19 +2524,20 @@ mod tests {
             RespValue::BulkString(Some(b"b".to_vec())),
         ]));
 
-        assert_eq!(handle_command(&cmd_rpush, &storage), ":2\r\n");
+        assert_eq!(handle_command(&cmd_rpush, &server, &mut conn), ":2\r\n");
 
         let cmd_lpop = RespValue::Array(Some(vec![
             RespValue::BulkString(Some(b"LPOP".to_vec())),
             RespValue::BulkString(Some(b"list".to_vec())),
         ]));
 
-        assert_eq!(handle_command(&cmd_lpop, &storage), "$1\r\na\r\n");
+        assert_eq!(handle_command(&cmd_lpop, &server, &mut conn), "$1\r\na\r\n");
     }
 
     #[test]
     fn test_lpop_command_returns_number_of_elements_if_called_with_count() {
-        let storage = Storage::new();
+        let server = ServerState::new();
+        let mut conn = ConnectionState::new();
 
         let cmd_rpush = RespValue::Array(Some(vec![
             RespValue::BulkString(Some(b"RPUSH".to_vec())),
@@ -1017,7 +2546,7 @@ mod tests {
             RespValue::BulkString(Some(b"b".to_vec())),
         ]));
 
-        assert_eq!(handle_command(&cmd_rpush, &storage), ":2\r\n");
+        assert_eq!(handle_command(&cmd_rpush, &server, &mut conn), ":2\r\n");
 
         let cmd_lpop_multiple = RespValue::Array(Some(vec![
             RespValue::BulkString(Some(b"LPOP".to_vec())),
@@ -1026,14 +2555,15 @@ mod tests {
         ]));
 
         assert_eq!(
-            handle_command(&cmd_lpop_multiple, &storage),
+            handle_command(&cmd_lpop_multiple, &server, &mut conn),
             "*2\r\n$1\r\na\r\n$1\r\nb\r\n"
         );
     }
 
     #[test]
     fn test_lpop_command_returns_all_elements_if_count_more_than_length_of_array() {
-        let storage = Storage::new();
+        let server = ServerState::new();
+        let mut conn = ConnectionState::new();
 
         let cmd_rpush = RespValue::Array(Some(vec![
             RespValue::BulkString(Some(b"RPUSH".to_vec())),
@@ -1042,7 +2572,7 @@ mod tests {
             RespValue::BulkString(Some(b"b".to_vec())),
         ]));
 
-        assert_eq!(handle_command(&cmd_rpush, &storage), ":2\r\n");
+        assert_eq!(handle_command(&cmd_rpush, &server, &mut conn), ":2\r\n");
 
         let cmd_lpop_multiple = RespValue::Array(Some(vec![
             RespValue::BulkString(Some(b"LPOP".to_vec())),
@@ -1051,14 +2581,15 @@ mod tests {
         ]));
 
         assert_eq!(
-            handle_command(&cmd_lpop_multiple, &storage),
+            handle_command(&cmd_lpop_multiple, &server, &mut conn),
             "*2\r\n$1\r\na\r\n$1\r\nb\r\n"
         );
     }
 
     #[test]
     fn test_lpop_command_returns_empty_array_if_count_is_zero() {
-        let storage = Storage::new();
+        let server = ServerState::new();
+        let mut conn = ConnectionState::new();
 
         let cmd_rpush = RespValue::Array(Some(vec![
             RespValue::BulkString(Some(b"RPUSH".to_vec())),
@@ -1067,7 +2598,7 @@ mod tests {
             RespValue::BulkString(Some(b"b".to_vec())),
         ]));
 
-        assert_eq!(handle_command(&cmd_rpush, &storage), ":2\r\n");
+        assert_eq!(handle_command(&cmd_rpush, &server, &mut conn), ":2\r\n");
 
         let cmd_lpop_multiple = RespValue::Array(Some(vec![
             RespValue::BulkString(Some(b"LPOP".to_vec())),
@@ -1075,12 +2606,16 @@ mod tests {
             RespValue::BulkString(Some(b"0".to_vec())),
         ]));
 
-        assert_eq!(handle_command(&cmd_lpop_multiple, &storage), "*0\r\n");
+        assert_eq!(
+            handle_command(&cmd_lpop_multiple, &server, &mut conn),
+            "*0\r\n"
+        );
     }
 
     #[test]
     fn test_lpop_command_returns_null_string_for_non_existing() {
-        let storage = Storage::new();
+        let server = ServerState::new();
+        let mut conn = ConnectionState::new();
 
         let cmd_rpush = RespValue::Array(Some(vec![
             RespValue::BulkString(Some(b"RPUSH".to_vec())),
@@ -1088,26 +2623,27 @@ mod tests {
             RespValue::BulkString(Some(b"a".to_vec())),
         ]));
 
-        assert_eq!(handle_command(&cmd_rpush, &storage), ":1\r\n");
+        assert_eq!(handle_command(&cmd_rpush, &server, &mut conn), ":1\r\n");
 
         let cmd_lpop = RespValue::Array(Some(vec![
             RespValue::BulkString(Some(b"LPOP".to_vec())),
             RespValue::BulkString(Some(b"list".to_vec())),
         ]));
 
-        assert_eq!(handle_command(&cmd_lpop, &storage), "$1\r\na\r\n");
+        assert_eq!(handle_command(&cmd_lpop, &server, &mut conn), "$1\r\na\r\n");
 
         let cmd_lpop = RespValue::Array(Some(vec![
             RespValue::BulkString(Some(b"LPOP".to_vec())),
             RespValue::BulkString(Some(b"list".to_vec())),
         ]));
 
-        assert_eq!(handle_command(&cmd_lpop, &storage), "$-1\r\n");
+        assert_eq!(handle_command(&cmd_lpop, &server, &mut conn), "$-1\r\n");
     }
 
     #[test]
     fn test_lpop_command_returns_error_on_wrong_number_of_arguments() {
-        let storage = Storage::new();
+        let server = ServerState::new();
+        let mut conn = ConnectionState::new();
 
         let cmd_lpop = RespValue::Array(Some(vec![
             RespValue::BulkString(Some(b"LPOP".to_vec())),
@@ -1117,14 +2653,15 @@ mod tests {
         ]));
 
         assert_eq!(
-            handle_command(&cmd_lpop, &storage),
-            "-ERR wrong number of arguments for command\r\n"
+            handle_command(&cmd_lpop, &server, &mut conn),
+            "-ERR wrong number of arguments for 'lpop' command\r\n"
         )
     }
 
     #[test]
     fn test_lpop_command_doesnt_work_on_keys() {
-        let storage = Storage::new();
+        let server = ServerState::new();
+        let mut conn = ConnectionState::new();
 
         let cmd_set = RespValue::Array(Some(vec![
             RespValue::BulkString(Some(b"SET".to_vec())),
@@ -1132,21 +2669,22 @@ mod tests {
             RespValue::BulkString(Some(b"value".to_vec())),
         ]));
 
-        assert_eq!(handle_command(&cmd_set, &storage), "+OK\r\n");
+        assert_eq!(handle_command(&cmd_set, &server, &mut conn), "+OK\r\n");
 
         let cmd_lpop = RespValue::Array(Some(vec![
             RespValue::BulkString(Some(b"LPOP".to_vec())),
             RespValue::BulkString(Some(b"key".to_vec())),
         ]));
         assert_eq!(
-            handle_command(&cmd_lpop, &storage),
+            handle_command(&cmd_lpop, &server, &mut conn),
             "-WRONGTYPE Operation against a key holding the wrong kind of value\r\n"
         )
     }
 
     #[test]
     fn test_blpop_command_returns_element_immediately() {
-        let storage = Storage::new();
+        let server = ServerState::new();
+        let mut conn = ConnectionState::new();
 
         let cmd_rpush = RespValue::Array(Some(vec![
             RespValue::BulkString(Some(b"RPUSH".to_vec())),
@@ -1155,7 +2693,7 @@ mod tests {
             RespValue::BulkString(Some(b"b".to_vec())),
         ]));
 
-        handle_command(&cmd_rpush, &storage);
+        handle_command(&cmd_rpush, &server, &mut conn);
 
         let cmd_blpop = RespValue::Array(Some(vec![
             RespValue::BulkString(Some(b"BLPOP".to_vec())),
@@ -1164,14 +2702,15 @@ mod tests {
         ]));
 
         assert_eq!(
-            handle_command(&cmd_blpop, &storage),
+            handle_command(&cmd_blpop, &server, &mut conn),
             "*2\r\n$4\r\nlist\r\n$1\r\na\r\n"
         )
     }
 
     #[test]
     fn test_blpop_command_returns_element_after_push() {
-        let storage = Storage::new();
+        let server = ServerState::new();
+        let mut conn = ConnectionState::new();
 
         let cmd_blpop = RespValue::Array(Some(vec![
             RespValue::BulkString(Some(b"BLPOP".to_vec())),
@@ -1179,8 +2718,10 @@ mod tests {
             RespValue::BulkString(Some(b"5".to_vec())),
         ]));
 
-        let storage_clone = storage.clone();
-        let blpop_thread = std::thread::spawn(move || handle_command(&cmd_blpop, &storage_clone));
+        let server_clone = server.clone();
+        let blpop_thread = std::thread::spawn(move || {
+            handle_command(&cmd_blpop, &server_clone, &mut ConnectionState::new())
+        });
 
         std::thread::sleep(std::time::Duration::from_millis(100));
 
@@ -1190,7 +2731,7 @@ mod tests {
             RespValue::BulkString(Some(b"hello".to_vec())),
         ]));
 
-        let rpush_result = handle_command(&cmd_rpush, &storage);
+        let rpush_result = handle_command(&cmd_rpush, &server, &mut conn);
         assert_eq!(rpush_result, ":1\r\n");
 
         let blpop_result = blpop_thread.join().unwrap();
@@ -1203,13 +2744,14 @@ mod tests {
             RespValue::Integer(-1),
         ]));
 
-        let lrange_result = handle_command(&cmd_lrange, &storage);
+        let lrange_result = handle_command(&cmd_lrange, &server, &mut conn);
         assert_eq!(lrange_result, "*0\r\n");
     }
 
     #[test]
     fn test_blpop_command_returns_null_array_if_timeout_expires_early_that_element_pushed() {
-        let storage = Storage::new();
+        let server = ServerState::new();
+        let mut conn = ConnectionState::new();
 
         let cmd_blpop = RespValue::Array(Some(vec![
             RespValue::BulkString(Some(b"BLPOP".to_vec())),
@@ -1217,8 +2759,10 @@ mod tests {
             RespValue::BulkString(Some(b"0.01".to_vec())),
         ]));
 
-        let storage_clone = storage.clone();
-        let blpop_thread = std::thread::spawn(move || handle_command(&cmd_blpop, &storage_clone));
+        let server_clone = server.clone();
+        let blpop_thread = std::thread::spawn(move || {
+            handle_command(&cmd_blpop, &server_clone, &mut ConnectionState::new())
+        });
 
         std::thread::sleep(std::time::Duration::from_millis(50));
 
@@ -1231,7 +2775,7 @@ mod tests {
             RespValue::BulkString(Some(b"hello".to_vec())),
         ]));
 
-        let rpush_result = handle_command(&cmd_rpush, &storage);
+        let rpush_result = handle_command(&cmd_rpush, &server, &mut conn);
         assert_eq!(rpush_result, ":1\r\n");
 
         let cmd_lrange = RespValue::Array(Some(vec![
@@ -1241,13 +2785,14 @@ mod tests {
             RespValue::Integer(-1),
         ]));
 
-        let lrange_result = handle_command(&cmd_lrange, &storage);
+        let lrange_result = handle_command(&cmd_lrange, &server, &mut conn);
         assert_eq!(lrange_result, "*1\r\n$5\r\nhello\r\n");
     }
 
     #[test]
     fn test_blpop_command_returns_null_array_for_non_existing_list() {
-        let storage = Storage::new();
+        let server = ServerState::new();
+        let mut conn = ConnectionState::new();
 
         let cmd_blpop = RespValue::Array(Some(vec![
             RespValue::BulkString(Some(b"BLPOP".to_vec())),
@@ -1255,12 +2800,13 @@ mod tests {
             RespValue::BulkString(Some(b"1".to_vec())),
         ]));
 
-        assert_eq!(handle_command(&cmd_blpop, &storage), "*-1\r\n")
+        assert_eq!(handle_command(&cmd_blpop, &server, &mut conn), "*-1\r\n")
     }
 
     #[test]
     fn test_blpop_command_returns_error_on_wrong_number_of_arguments() {
-        let storage = Storage::new();
+        let server = ServerState::new();
+        let mut conn = ConnectionState::new();
 
         let cmd_blpop = RespValue::Array(Some(vec![
             RespValue::BulkString(Some(b"BLPOP".to_vec())),
@@ -1268,14 +2814,15 @@ mod tests {
         ]));
 
         assert_eq!(
-            handle_command(&cmd_blpop, &storage),
-            "-ERR wrong number of arguments for command\r\n"
+            handle_command(&cmd_blpop, &server, &mut conn),
+            "-ERR wrong number of arguments for 'blpop' command\r\n"
         )
     }
 
     #[test]
     fn test_type_command_returns_type_for_string() {
-        let storage = Storage::new();
+        let server = ServerState::new();
+        let mut conn = ConnectionState::new();
 
         let cmd_set = RespValue::Array(Some(vec![
             RespValue::BulkString(Some(b"SET".to_vec())),
@@ -1288,14 +2835,15 @@ mod tests {
             RespValue::BulkString(Some(b"key".to_vec())),
         ]));
 
-        assert_eq!(handle_command(&cmd_set, &storage), "+OK\r\n");
+        assert_eq!(handle_command(&cmd_set, &server, &mut conn), "+OK\r\n");
 
-        assert_eq!(handle_command(&cmd_type, &storage), "+string\r\n")
+        assert_eq!(handle_command(&cmd_type, &server, &mut conn), "+string\r\n")
     }
 
     #[test]
     fn test_type_command_returns_type_for_list() {
-        let storage = Storage::new();
+        let server = ServerState::new();
+        let mut conn = ConnectionState::new();
 
         let cmd_rpush = RespValue::Array(Some(vec![
             RespValue::BulkString(Some(b"RPUSH".to_vec())),
@@ -1303,31 +2851,33 @@ mod tests {
             RespValue::BulkString(Some(b"\"element_one\"".to_vec())),
             RespValue::BulkString(Some(b"\"element_two\"".to_vec())),
         ]));
-        assert_eq!(handle_command(&cmd_rpush, &storage), ":2\r\n");
+        assert_eq!(handle_command(&cmd_rpush, &server, &mut conn), ":2\r\n");
 
         let cmd_type = RespValue::Array(Some(vec![
             RespValue::BulkString(Some(b"TYPE".to_vec())),
             RespValue::BulkString(Some(b"list".to_vec())),
         ]));
 
-        assert_eq!(handle_command(&cmd_type, &storage), "+list\r\n")
+        assert_eq!(handle_command(&cmd_type, &server, &mut conn), "+list\r\n")
     }
 
     #[test]
     fn test_type_command_returns_none_for_non_existing_key() {
-        let storage = Storage::new();
+        let server = ServerState::new();
+        let mut conn = ConnectionState::new();
 
         let cmd_type = RespValue::Array(Some(vec![
             RespValue::BulkString(Some(b"TYPE".to_vec())),
             RespValue::BulkString(Some(b"key".to_vec())),
         ]));
 
-        assert_eq!(handle_command(&cmd_type, &storage), "+none\r\n");
+        assert_eq!(handle_command(&cmd_type, &server, &mut conn), "+none\r\n");
     }
 
     #[test]
     fn test_xadd_command_works_with_specified_id() {
-        let storage = Storage::new();
+        let server = ServerState::new();
+        let mut conn = ConnectionState::new();
 
         let cmd_xadd = RespValue::Array(Some(vec![
             RespValue::BulkString(Some(b"XADD".to_vec())),
@@ -1336,12 +2886,16 @@ mod tests {
             RespValue::BulkString(Some(b"field".to_vec())),
             RespValue::BulkString(Some(b"value".to_vec())),
         ]));
-        assert_eq!(handle_command(&cmd_xadd, &storage), "$3\r\n0-1\r\n")
+        assert_eq!(
+            handle_command(&cmd_xadd, &server, &mut conn),
+            "$3\r\n0-1\r\n"
+        )
     }
 
     #[test]
     fn test_xadd_command_works_with_generated_id() {
-        let storage = Storage::new();
+        let server = ServerState::new();
+        let mut conn = ConnectionState::new();
 
         let cmd_xadd = RespValue::Array(Some(vec![
             RespValue::BulkString(Some(b"XADD".to_vec())),
@@ -1356,7 +2910,7 @@ mod tests {
             .unwrap()
             .as_millis();
 
-        let result = handle_command(&cmd_xadd, &storage);
+        let result = handle_command(&cmd_xadd, &server, &mut conn);
 
         let after = SystemTime::now()
             .duration_since(UNIX_EPOCH)
@@ -1382,7 +2936,8 @@ mod tests {
 
     #[test]
     fn test_xadd_command_works_with_more_than_one_pair_of_values() {
-        let storage = Storage::new();
+        let server = ServerState::new();
+        let mut conn = ConnectionState::new();
 
         let cmd_xadd = RespValue::Array(Some(vec![
             RespValue::BulkString(Some(b"XADD".to_vec())),
@@ -1393,12 +2948,16 @@ mod tests {
             RespValue::BulkString(Some(b"field2".to_vec())),
             RespValue::BulkString(Some(b"value2".to_vec())),
         ]));
-        assert_eq!(handle_command(&cmd_xadd, &storage), "$3\r\n0-1\r\n")
+        assert_eq!(
+            handle_command(&cmd_xadd, &server, &mut conn),
+            "$3\r\n0-1\r\n"
+        )
     }
 
     #[test]
     fn test_xadd_command_returns_error_for_wrong_number_of_arguments() {
-        let storage = Storage::new();
+        let server = ServerState::new();
+        let mut conn = ConnectionState::new();
 
         let cmd_xadd = RespValue::Array(Some(vec![
             RespValue::BulkString(Some(b"XADD".to_vec())),
@@ -1409,14 +2968,15 @@ mod tests {
             RespValue::BulkString(Some(b"field2".to_vec())),
         ]));
         assert_eq!(
-            handle_command(&cmd_xadd, &storage),
-            "-ERR wrong number of arguments for command\r\n"
+            handle_command(&cmd_xadd, &server, &mut conn),
+            "-ERR wrong number of arguments for 'xadd' command\r\n"
         )
     }
 
     #[test]
     fn test_xadd_command_returns_error_for_invalid_provided_id() {
-        let storage = Storage::new();
+        let server = ServerState::new();
+        let mut conn = ConnectionState::new();
 
         let cmd_xadd = RespValue::Array(Some(vec![
             RespValue::BulkString(Some(b"XADD".to_vec())),
@@ -1426,7 +2986,7 @@ mod tests {
             RespValue::BulkString(Some(b"value".to_vec())),
         ]));
         assert_eq!(
-            handle_command(&cmd_xadd, &storage),
+            handle_command(&cmd_xadd, &server, &mut conn),
             "-ERR Invalid stream ID specified as stream command argument\r\n"
         );
 
@@ -1438,7 +2998,7 @@ mod tests {
             RespValue::BulkString(Some(b"value".to_vec())),
         ]));
         assert_eq!(
-            handle_command(&cmd_xadd2, &storage),
+            handle_command(&cmd_xadd2, &server, &mut conn),
             "-ERR The ID specified in XADD must be greater than 0-0\r\n"
         );
 
@@ -1450,14 +3010,15 @@ mod tests {
             RespValue::BulkString(Some(b"value".to_vec())),
         ]));
         assert_eq!(
-            handle_command(&cmd_xadd3, &storage),
+            handle_command(&cmd_xadd3, &server, &mut conn),
             "-ERR Invalid stream ID specified as stream command argument\r\n"
         )
     }
 
     #[test]
     fn test_xadd_command_returns_error_if_provided_id_is_smaller_than_existing() {
-        let storage = Storage::new();
+        let server = ServerState::new();
+        let mut conn = ConnectionState::new();
 
         let cmd_xadd = RespValue::Array(Some(vec![
             RespValue::BulkString(Some(b"XADD".to_vec())),
@@ -1467,7 +3028,10 @@ mod tests {
             RespValue::BulkString(Some(b"value".to_vec())),
         ]));
 
-        assert_eq!(handle_command(&cmd_xadd, &storage), "$3\r\n1-2\r\n");
+        assert_eq!(
+            handle_command(&cmd_xadd, &server, &mut conn),
+            "$3\r\n1-2\r\n"
+        );
 
         let cmd_xadd2 = RespValue::Array(Some(vec![
             RespValue::BulkString(Some(b"XADD".to_vec())),
@@ -1477,14 +3041,15 @@ mod tests {
             RespValue::BulkString(Some(b"value2".to_vec())),
         ]));
         assert_eq!(
-            handle_command(&cmd_xadd2, &storage),
+            handle_command(&cmd_xadd2, &server, &mut conn),
             "-ERR The ID specified in XADD is equal or smaller than the target stream top item\r\n"
         )
     }
 
     #[test]
     fn test_type_command_works_on_stream() {
-        let storage = Storage::new();
+        let server = ServerState::new();
+        let mut conn = ConnectionState::new();
 
         let cmd_xadd = RespValue::Array(Some(vec![
             RespValue::BulkString(Some(b"XADD".to_vec())),
@@ -1493,19 +3058,23 @@ mod tests {
             RespValue::BulkString(Some(b"field".to_vec())),
             RespValue::BulkString(Some(b"value".to_vec())),
         ]));
-        assert_eq!(handle_command(&cmd_xadd, &storage), "$3\r\n0-1\r\n");
+        assert_eq!(
+            handle_command(&cmd_xadd, &server, &mut conn),
+            "$3\r\n0-1\r\n"
+        );
 
         let cmd_type = RespValue::Array(Some(vec![
             RespValue::BulkString(Some(b"TYPE".to_vec())),
             RespValue::BulkString(Some(b"mystream".to_vec())),
         ]));
 
-        assert_eq!(handle_command(&cmd_type, &storage), "+stream\r\n")
+        assert_eq!(handle_command(&cmd_type, &server, &mut conn), "+stream\r\n")
     }
 
     #[test]
     fn test_xadd_command_doesnt_work_on_strings() {
-        let storage = Storage::new();
+        let server = ServerState::new();
+        let mut conn = ConnectionState::new();
 
         let cmd_set = RespValue::Array(Some(vec![
             RespValue::BulkString(Some(b"SET".to_vec())),
@@ -1513,7 +3082,7 @@ mod tests {
             RespValue::BulkString(Some(b"value".to_vec())),
         ]));
 
-        assert_eq!(handle_command(&cmd_set, &storage), "+OK\r\n");
+        assert_eq!(handle_command(&cmd_set, &server, &mut conn), "+OK\r\n");
 
         let cmd_xadd = RespValue::Array(Some(vec![
             RespValue::BulkString(Some(b"XADD".to_vec())),
@@ -1523,14 +3092,15 @@ mod tests {
             RespValue::BulkString(Some(b"b".to_vec())),
         ]));
         assert_eq!(
-            handle_command(&cmd_xadd, &storage),
+            handle_command(&cmd_xadd, &server, &mut conn),
             "-WRONGTYPE Operation against a key holding the wrong kind of value\r\n"
         )
     }
 
     #[test]
     fn test_xread_command_returns_entries_after_id() {
-        let storage = Storage::new();
+        let server = ServerState::new();
+        let mut conn = ConnectionState::new();
 
         let cmd_xadd1 = RespValue::Array(Some(vec![
             RespValue::BulkString(Some(b"XADD".to_vec())),
@@ -1539,7 +3109,10 @@ mod tests {
             RespValue::BulkString(Some(b"field".to_vec())),
             RespValue::BulkString(Some(b"value".to_vec())),
         ]));
-        assert_eq!(handle_command(&cmd_xadd1, &storage), "$3\r\n0-1\r\n");
+        assert_eq!(
+            handle_command(&cmd_xadd1, &server, &mut conn),
+            "$3\r\n0-1\r\n"
+        );
 
         let cmd_xadd2 = RespValue::Array(Some(vec![
             RespValue::BulkString(Some(b"XADD".to_vec())),
@@ -1548,7 +3121,10 @@ mod tests {
             RespValue::BulkString(Some(b"field".to_vec())),
             RespValue::BulkString(Some(b"value".to_vec())),
         ]));
-        assert_eq!(handle_command(&cmd_xadd2, &storage), "$3\r\n0-2\r\n");
+        assert_eq!(
+            handle_command(&cmd_xadd2, &server, &mut conn),
+            "$3\r\n0-2\r\n"
+        );
 
         let cmd_xread = RespValue::Array(Some(vec![
             RespValue::BulkString(Some(b"XREAD".to_vec())),
@@ -1569,12 +3145,13 @@ mod tests {
             "$5\r\nvalue\r\n"
         );
 
-        assert_eq!(handle_command(&cmd_xread, &storage), expected);
+        assert_eq!(handle_command(&cmd_xread, &server, &mut conn), expected);
     }
 
     #[test]
     fn test_xread_command_returns_empty_array_when_no_new_entries() {
-        let storage = Storage::new();
+        let server = ServerState::new();
+        let mut conn = ConnectionState::new();
 
         let cmd_xadd = RespValue::Array(Some(vec![
             RespValue::BulkString(Some(b"XADD".to_vec())),
@@ -1583,7 +3160,10 @@ mod tests {
             RespValue::BulkString(Some(b"field".to_vec())),
             RespValue::BulkString(Some(b"value".to_vec())),
         ]));
-        assert_eq!(handle_command(&cmd_xadd, &storage), "$3\r\n0-1\r\n");
+        assert_eq!(
+            handle_command(&cmd_xadd, &server, &mut conn),
+            "$3\r\n0-1\r\n"
+        );
 
         let cmd_xread = RespValue::Array(Some(vec![
             RespValue::BulkString(Some(b"XREAD".to_vec())),
@@ -1592,12 +3172,13 @@ mod tests {
             RespValue::BulkString(Some(b"0-1".to_vec())),
         ]));
 
-        assert_eq!(handle_command(&cmd_xread, &storage), "*0\r\n");
+        assert_eq!(handle_command(&cmd_xread, &server, &mut conn), "*0\r\n");
     }
 
     #[test]
     fn test_xread_command_returns_error_on_wrong_number_of_arguments() {
-        let storage = Storage::new();
+        let server = ServerState::new();
+        let mut conn = ConnectionState::new();
 
         let cmd_xread = RespValue::Array(Some(vec![
             RespValue::BulkString(Some(b"XREAD".to_vec())),
@@ -1606,14 +3187,15 @@ mod tests {
         ]));
 
         assert_eq!(
-            handle_command(&cmd_xread, &storage),
-            "-ERR wrong number of arguments for command\r\n"
+            handle_command(&cmd_xread, &server, &mut conn),
+            "-ERR wrong number of arguments for 'xread' command\r\n"
         );
     }
 
     #[test]
     fn test_xread_command_returns_entries_for_two_streams() {
-        let storage = Storage::new();
+        let server = ServerState::new();
+        let mut conn = ConnectionState::new();
 
         let cmd_xadd_stream1 = RespValue::Array(Some(vec![
             RespValue::BulkString(Some(b"XADD".to_vec())),
@@ -1622,7 +3204,10 @@ mod tests {
             RespValue::BulkString(Some(b"field".to_vec())),
             RespValue::BulkString(Some(b"value".to_vec())),
         ]));
-        assert_eq!(handle_command(&cmd_xadd_stream1, &storage), "$3\r\n0-1\r\n");
+        assert_eq!(
+            handle_command(&cmd_xadd_stream1, &server, &mut conn),
+            "$3\r\n0-1\r\n"
+        );
 
         let cmd_xadd_stream2 = RespValue::Array(Some(vec![
             RespValue::BulkString(Some(b"XADD".to_vec())),
@@ -1631,7 +3216,10 @@ mod tests {
             RespValue::BulkString(Some(b"field".to_vec())),
             RespValue::BulkString(Some(b"value2".to_vec())),
         ]));
-        assert_eq!(handle_command(&cmd_xadd_stream2, &storage), "$3\r\n0-1\r\n");
+        assert_eq!(
+            handle_command(&cmd_xadd_stream2, &server, &mut conn),
+            "$3\r\n0-1\r\n"
+        );
 
         let cmd_xread = RespValue::Array(Some(vec![
             RespValue::BulkString(Some(b"XREAD".to_vec())),
@@ -1662,12 +3250,13 @@ mod tests {
             "$6\r\nvalue2\r\n"
         );
 
-        assert_eq!(handle_command(&cmd_xread, &storage), expected);
+        assert_eq!(handle_command(&cmd_xread, &server, &mut conn), expected);
     }
 
     #[test]
     fn test_xread_command_omits_streams_with_no_new_entries() {
-        let storage = Storage::new();
+        let server = ServerState::new();
+        let mut conn = ConnectionState::new();
 
         let cmd_xadd_stream1 = RespValue::Array(Some(vec![
             RespValue::BulkString(Some(b"XADD".to_vec())),
@@ -1676,7 +3265,10 @@ mod tests {
             RespValue::BulkString(Some(b"field".to_vec())),
             RespValue::BulkString(Some(b"value".to_vec())),
         ]));
-        assert_eq!(handle_command(&cmd_xadd_stream1, &storage), "$3\r\n0-1\r\n");
+        assert_eq!(
+            handle_command(&cmd_xadd_stream1, &server, &mut conn),
+            "$3\r\n0-1\r\n"
+        );
 
         let cmd_xadd_stream2 = RespValue::Array(Some(vec![
             RespValue::BulkString(Some(b"XADD".to_vec())),
@@ -1685,7 +3277,10 @@ mod tests {
             RespValue::BulkString(Some(b"field".to_vec())),
             RespValue::BulkString(Some(b"value2".to_vec())),
         ]));
-        assert_eq!(handle_command(&cmd_xadd_stream2, &storage), "$3\r\n0-1\r\n");
+        assert_eq!(
+            handle_command(&cmd_xadd_stream2, &server, &mut conn),
+            "$3\r\n0-1\r\n"
+        );
 
         let cmd_xread = RespValue::Array(Some(vec![
             RespValue::BulkString(Some(b"XREAD".to_vec())),
@@ -1708,6 +3303,2570 @@ mod tests {
             "$5\r\nvalue\r\n"
         );
 
-        assert_eq!(handle_command(&cmd_xread, &storage), expected);
+        assert_eq!(handle_command(&cmd_xread, &server, &mut conn), expected);
+    }
+
+    fn extract_replid(info: &str) -> String {
+        info.lines()
+            .find_map(|line| line.strip_prefix("master_replid:"))
+            .expect("INFO should report master_replid")
+            .to_string()
+    }
+
+    #[test]
+    fn test_info_replication_reports_replid() {
+        let server = ServerState::new();
+        let mut conn = ConnectionState::new();
+
+        let cmd_info = RespValue::Array(Some(vec![
+            RespValue::BulkString(Some(b"INFO".to_vec())),
+            RespValue::BulkString(Some(b"replication".to_vec())),
+        ]));
+
+        let info = handle_command(&cmd_info, &server, &mut conn);
+        assert!(info.contains("# Replication\r\n"));
+        assert!(info.contains("role:master\r\n"));
+
+        let replid = extract_replid(&info);
+        assert_eq!(replid.len(), 40);
+        assert!(replid.chars().all(|c| c.is_ascii_hexdigit()));
+        assert_eq!(
+            extract_replid(&handle_command(&cmd_info, &server, &mut conn)),
+            replid
+        );
+    }
+
+    #[test]
+    fn test_debug_change_repl_id_changes_replid() {
+        let server = ServerState::new();
+        let mut conn = ConnectionState::new();
+
+        let cmd_info = RespValue::Array(Some(vec![
+            RespValue::BulkString(Some(b"INFO".to_vec())),
+            RespValue::BulkString(Some(b"replication".to_vec())),
+        ]));
+        let before = extract_replid(&handle_command(&cmd_info, &server, &mut conn));
+
+        let cmd_debug = RespValue::Array(Some(vec![
+            RespValue::BulkString(Some(b"DEBUG".to_vec())),
+            RespValue::BulkString(Some(b"change-repl-id".to_vec())),
+        ]));
+        assert_eq!(handle_command(&cmd_debug, &server, &mut conn), "+OK\r\n");
+
+        let after = extract_replid(&handle_command(&cmd_info, &server, &mut conn));
+        assert_eq!(after.len(), 40);
+        assert_ne!(before, after);
+    }
+
+    #[test]
+    fn test_select_command_switches_database() {
+        let server = ServerState::new();
+        let mut conn = ConnectionState::new();
+
+        let cmd_set = RespValue::Array(Some(vec![
+            RespValue::BulkString(Some(b"SET".to_vec())),
+            RespValue::BulkString(Some(b"key".to_vec())),
+            RespValue::BulkString(Some(b"value".to_vec())),
+        ]));
+        assert_eq!(handle_command(&cmd_set, &server, &mut conn), "+OK\r\n");
+
+        let cmd_select = RespValue::Array(Some(vec![
+            RespValue::BulkString(Some(b"SELECT".to_vec())),
+            RespValue::BulkString(Some(b"1".to_vec())),
+        ]));
+        assert_eq!(handle_command(&cmd_select, &server, &mut conn), "+OK\r\n");
+        assert_eq!(conn.db, 1);
+
+        let cmd_get = RespValue::Array(Some(vec![
+            RespValue::BulkString(Some(b"GET".to_vec())),
+            RespValue::BulkString(Some(b"key".to_vec())),
+        ]));
+        assert_eq!(handle_command(&cmd_get, &server, &mut conn), "$-1\r\n");
+
+        let mut other_conn = ConnectionState::new();
+        assert_eq!(
+            handle_command(&cmd_get, &server, &mut other_conn),
+            "$5\r\nvalue\r\n"
+        );
+    }
+
+    #[test]
+    fn test_select_command_rejects_out_of_range_index() {
+        let server = ServerState::new();
+        let mut conn = ConnectionState::new();
+
+        let cmd_select = RespValue::Array(Some(vec![
+            RespValue::BulkString(Some(b"SELECT".to_vec())),
+            RespValue::BulkString(Some(b"16".to_vec())),
+        ]));
+        assert_eq!(
+            handle_command(&cmd_select, &server, &mut conn),
+            "-ERR DB index is out of range\r\n"
+        );
+        assert_eq!(conn.db, 0);
+    }
+
+    #[test]
+    fn test_multi_command_queues_until_exec() {
+        let server = ServerState::new();
+        let mut conn = ConnectionState::new();
+
+        let cmd_multi =
+            RespValue::Array(Some(vec![RespValue::BulkString(Some(b"MULTI".to_vec()))]));
+        assert_eq!(handle_command(&cmd_multi, &server, &mut conn), "+OK\r\n");
+        assert!(conn.in_transaction());
+
+        let cmd_select = RespValue::Array(Some(vec![
+            RespValue::BulkString(Some(b"SELECT".to_vec())),
+            RespValue::BulkString(Some(b"2".to_vec())),
+        ]));
+        assert_eq!(
+            handle_command(&cmd_select, &server, &mut conn),
+            "+QUEUED\r\n"
+        );
+
+        let cmd_set = RespValue::Array(Some(vec![
+            RespValue::BulkString(Some(b"SET".to_vec())),
+            RespValue::BulkString(Some(b"key".to_vec())),
+            RespValue::BulkString(Some(b"value".to_vec())),
+        ]));
+        assert_eq!(handle_command(&cmd_set, &server, &mut conn), "+QUEUED\r\n");
+        assert_eq!(conn.db, 0);
+        assert_eq!(server.db(2).get("key"), None);
+
+        let cmd_exec = RespValue::Array(Some(vec![RespValue::BulkString(Some(b"EXEC".to_vec()))]));
+        assert_eq!(
+            handle_command(&cmd_exec, &server, &mut conn),
+            "*2\r\n+OK\r\n+OK\r\n"
+        );
+        assert!(!conn.in_transaction());
+        assert_eq!(conn.db, 2);
+        assert_eq!(server.db(2).get("key"), Some(b"value".to_vec()));
+    }
+
+    #[test]
+    fn test_multi_command_cannot_be_nested() {
+        let server = ServerState::new();
+        let mut conn = ConnectionState::new();
+
+        let cmd_multi =
+            RespValue::Array(Some(vec![RespValue::BulkString(Some(b"MULTI".to_vec()))]));
+        assert_eq!(handle_command(&cmd_multi, &server, &mut conn), "+OK\r\n");
+        assert_eq!(
+            handle_command(&cmd_multi, &server, &mut conn),
+            "-ERR MULTI calls can not be nested\r\n"
+        );
+    }
+
+    #[test]
+    fn test_discard_command_drops_queued_commands() {
+        let server = ServerState::new();
+        let mut conn = ConnectionState::new();
+
+        let cmd_multi =
+            RespValue::Array(Some(vec![RespValue::BulkString(Some(b"MULTI".to_vec()))]));
+        assert_eq!(handle_command(&cmd_multi, &server, &mut conn), "+OK\r\n");
+
+        let cmd_set = RespValue::Array(Some(vec![
+            RespValue::BulkString(Some(b"SET".to_vec())),
+            RespValue::BulkString(Some(b"key".to_vec())),
+            RespValue::BulkString(Some(b"value".to_vec())),
+        ]));
+        assert_eq!(handle_command(&cmd_set, &server, &mut conn), "+QUEUED\r\n");
+
+        let cmd_discard =
+            RespValue::Array(Some(vec![RespValue::BulkString(Some(b"DISCARD".to_vec()))]));
+        assert_eq!(handle_command(&cmd_discard, &server, &mut conn), "+OK\r\n");
+        assert!(!conn.in_transaction());
+        assert_eq!(server.db(0).get("key"), None);
+    }
+
+    #[test]
+    fn test_exec_and_discard_without_multi_return_errors() {
+        let server = ServerState::new();
+        let mut conn = ConnectionState::new();
+
+        let cmd_exec = RespValue::Array(Some(vec![RespValue::BulkString(Some(b"EXEC".to_vec()))]));
+        assert_eq!(
+            handle_command(&cmd_exec, &server, &mut conn),
+            "-ERR EXEC without MULTI\r\n"
+        );
+
+        let cmd_discard =
+            RespValue::Array(Some(vec![RespValue::BulkString(Some(b"DISCARD".to_vec()))]));
+        assert_eq!(
+            handle_command(&cmd_discard, &server, &mut conn),
+            "-ERR DISCARD without MULTI\r\n"
+        );
+    }
+
+    #[test]
+    fn test_command_info_reports_arity_and_flags_for_set() {
+        let server = ServerState::new();
+        let mut conn = ConnectionState::new();
+
+        let cmd = RespValue::Array(Some(vec![
+            RespValue::BulkString(Some(b"COMMAND".to_vec())),
+            RespValue::BulkString(Some(b"INFO".to_vec())),
+            RespValue::BulkString(Some(b"set".to_vec())),
+        ]));
+
+        let expected = concat!(
+            "*1\r\n",
+            "*6\r\n",
+            "$3\r\nset\r\n",
+            ":-3\r\n",
+            "*2\r\n",
+            "+write\r\n",
+            "+denyoom\r\n",
+            ":1\r\n",
+            ":1\r\n",
+            ":1\r\n"
+        );
+        assert_eq!(handle_command(&cmd, &server, &mut conn), expected);
+    }
+
+    #[test]
+    fn test_command_info_returns_null_for_unknown_command() {
+        let server = ServerState::new();
+        let mut conn = ConnectionState::new();
+
+        let cmd = RespValue::Array(Some(vec![
+            RespValue::BulkString(Some(b"COMMAND".to_vec())),
+            RespValue::BulkString(Some(b"INFO".to_vec())),
+            RespValue::BulkString(Some(b"nope".to_vec())),
+        ]));
+        assert_eq!(handle_command(&cmd, &server, &mut conn), "*1\r\n*-1\r\n");
+    }
+
+    #[test]
+    fn test_arity_is_validated_from_command_table() {
+        let server = ServerState::new();
+        let mut conn = ConnectionState::new();
+
+        let cmd_get = RespValue::Array(Some(vec![
+            RespValue::BulkString(Some(b"GET".to_vec())),
+            RespValue::BulkString(Some(b"key".to_vec())),
+            RespValue::BulkString(Some(b"extra".to_vec())),
+        ]));
+        assert_eq!(
+            handle_command(&cmd_get, &server, &mut conn),
+            "-ERR wrong number of arguments for 'get' command\r\n"
+        );
+
+        let cmd_set = RespValue::Array(Some(vec![
+            RespValue::BulkString(Some(b"SET".to_vec())),
+            RespValue::BulkString(Some(b"key".to_vec())),
+        ]));
+        assert_eq!(
+            handle_command(&cmd_set, &server, &mut conn),
+            "-ERR wrong number of arguments for 'set' command\r\n"
+        );
+    }
+
+    #[test]
+    fn test_parse_redis_float_accepts_infinities_and_exponents() {
+        assert_eq!(parse_redis_float(b"inf"), Ok(f64::INFINITY));
+        assert_eq!(parse_redis_float(b"+inf"), Ok(f64::INFINITY));
+        assert_eq!(parse_redis_float(b"-inf"), Ok(f64::NEG_INFINITY));
+        assert_eq!(parse_redis_float(b"1e3"), Ok(1000.0));
+        assert_eq!(parse_redis_float(b"-2.5"), Ok(-2.5));
+    }
+
+    #[test]
+    fn test_parse_redis_float_rejects_nan_empty_and_padding() {
+        let err = Err("ERR value is not a valid float".to_string());
+        assert_eq!(parse_redis_float(b"nan"), err);
+        assert_eq!(parse_redis_float(b""), err);
+        assert_eq!(parse_redis_float(b"  1.5 "), err);
+        assert_eq!(parse_redis_float(b"1.5 "), err);
+        assert_eq!(parse_redis_float(b"abc"), err);
+    }
+
+    #[test]
+    fn test_blpop_command_rejects_negative_and_infinite_timeout() {
+        let server = ServerState::new();
+        let mut conn = ConnectionState::new();
+
+        let cmd_blpop = RespValue::Array(Some(vec![
+            RespValue::BulkString(Some(b"BLPOP".to_vec())),
+            RespValue::BulkString(Some(b"list".to_vec())),
+            RespValue::BulkString(Some(b"-1".to_vec())),
+        ]));
+        assert_eq!(
+            handle_command(&cmd_blpop, &server, &mut conn),
+            "-ERR timeout is negative\r\n"
+        );
+
+        let cmd_blpop = RespValue::Array(Some(vec![
+            RespValue::BulkString(Some(b"BLPOP".to_vec())),
+            RespValue::BulkString(Some(b"list".to_vec())),
+            RespValue::BulkString(Some(b"inf".to_vec())),
+        ]));
+        assert_eq!(
+            handle_command(&cmd_blpop, &server, &mut conn),
+            "-ERR timeout is out of range\r\n"
+        );
+    }
+
+    #[test]
+    fn test_blocking_pops_reject_timeouts_too_large_for_a_duration() {
+        let server = ServerState::new();
+        let mut conn = ConnectionState::new();
+        assert_eq!(
+            run(&server, &mut conn, &["BLPOP", "k", "1e20"]),
+            "-ERR timeout is out of range\r\n"
+        );
+        assert_eq!(
+            run(&server, &mut conn, &["BLMPOP", "1e300", "1", "k", "LEFT"]),
+            "-ERR timeout is out of range\r\n"
+        );
+    }
+
+    #[test]
+    fn test_exists_command_counts_every_existing_key() {
+        let server = ServerState::new();
+        let mut conn = ConnectionState::new();
+
+        let cmd_set = RespValue::Array(Some(vec![
+            RespValue::BulkString(Some(b"SET".to_vec())),
+            RespValue::BulkString(Some(b"key".to_vec())),
+            RespValue::BulkString(Some(b"value".to_vec())),
+        ]));
+        assert_eq!(handle_command(&cmd_set, &server, &mut conn), "+OK\r\n");
+
+        let cmd_exists = RespValue::Array(Some(vec![
+            RespValue::BulkString(Some(b"EXISTS".to_vec())),
+            RespValue::BulkString(Some(b"key".to_vec())),
+            RespValue::BulkString(Some(b"missing".to_vec())),
+            RespValue::BulkString(Some(b"key".to_vec())),
+        ]));
+        assert_eq!(handle_command(&cmd_exists, &server, &mut conn), ":2\r\n");
+    }
+
+    #[test]
+    fn test_exists_and_type_treat_expired_key_as_absent() {
+        let clock = TestClock::new();
+        let server = ServerState::new_with_clock(Arc::new(clock.clone()));
+        let mut conn = ConnectionState::new();
+
+        let cmd_set = RespValue::Array(Some(vec![
+            RespValue::BulkString(Some(b"SET".to_vec())),
+            RespValue::BulkString(Some(b"key".to_vec())),
+            RespValue::BulkString(Some(b"value".to_vec())),
+            RespValue::BulkString(Some(b"EX".to_vec())),
+            RespValue::BulkString(Some(b"1".to_vec())),
+        ]));
+        assert_eq!(handle_command(&cmd_set, &server, &mut conn), "+OK\r\n");
+
+        clock.advance(Duration::from_millis(1100));
+
+        let cmd_exists = RespValue::Array(Some(vec![
+            RespValue::BulkString(Some(b"EXISTS".to_vec())),
+            RespValue::BulkString(Some(b"key".to_vec())),
+        ]));
+        assert_eq!(handle_command(&cmd_exists, &server, &mut conn), ":0\r\n");
+
+        let cmd_type = RespValue::Array(Some(vec![
+            RespValue::BulkString(Some(b"TYPE".to_vec())),
+            RespValue::BulkString(Some(b"key".to_vec())),
+        ]));
+        assert_eq!(handle_command(&cmd_type, &server, &mut conn), "+none\r\n");
+    }
+
+    #[test]
+    fn test_scan_command_filters_by_match() {
+        let server = ServerState::new();
+        let mut conn = ConnectionState::new();
+
+        for key in ["user:1", "user:2", "other"] {
+            let cmd_set = RespValue::Array(Some(vec![
+                RespValue::BulkString(Some(b"SET".to_vec())),
+                RespValue::BulkString(Some(key.as_bytes().to_vec())),
+                RespValue::BulkString(Some(b"value".to_vec())),
+            ]));
+            assert_eq!(handle_command(&cmd_set, &server, &mut conn), "+OK\r\n");
+        }
+
+        let cmd_scan = RespValue::Array(Some(vec![
+            RespValue::BulkString(Some(b"SCAN".to_vec())),
+            RespValue::BulkString(Some(b"0".to_vec())),
+            RespValue::BulkString(Some(b"MATCH".to_vec())),
+            RespValue::BulkString(Some(b"other".to_vec())),
+        ]));
+        assert_eq!(
+            handle_command(&cmd_scan, &server, &mut conn),
+            "*2\r\n$1\r\n0\r\n*1\r\n$5\r\nother\r\n"
+        );
+    }
+
+    #[test]
+    fn test_scan_command_rejects_invalid_cursor() {
+        let server = ServerState::new();
+        let mut conn = ConnectionState::new();
+
+        let cmd_scan = RespValue::Array(Some(vec![
+            RespValue::BulkString(Some(b"SCAN".to_vec())),
+            RespValue::BulkString(Some(b"abc".to_vec())),
+        ]));
+        assert_eq!(
+            handle_command(&cmd_scan, &server, &mut conn),
+            "-ERR invalid cursor\r\n"
+        );
+    }
+
+    fn run(server: &ServerState, conn: &mut ConnectionState, args: &[&str]) -> String {
+        let cmd = RespValue::Array(Some(
+            args.iter()
+                .map(|arg| RespValue::BulkString(Some(arg.as_bytes().to_vec())))
+                .collect(),
+        ));
+        handle_command(&cmd, server, conn)
+    }
+
+    #[test]
+    fn test_expire_command_rejects_overflowing_ttl() {
+        let server = ServerState::new();
+        let mut conn = ConnectionState::new();
+        run(&server, &mut conn, &["SET", "key", "value"]);
+
+        assert_eq!(
+            run(&server, &mut conn, &["EXPIRE", "key", "9999999999999999"]),
+            "-ERR invalid expire time in 'expire' command\r\n"
+        );
+        assert_eq!(
+            run(
+                &server,
+                &mut conn,
+                &["PEXPIRE", "key", "9223372036854775807"]
+            ),
+            "-ERR invalid expire time in 'pexpire' command\r\n"
+        );
+        assert_eq!(
+            run(
+                &server,
+                &mut conn,
+                &["SET", "key", "value", "EX", "9999999999999999"]
+            ),
+            "-ERR invalid expire time in 'set' command\r\n"
+        );
+        assert_eq!(run(&server, &mut conn, &["EXISTS", "key"]), ":1\r\n");
+    }
+
+    #[test]
+    fn test_expire_command_with_negative_ttl_deletes_key() {
+        let server = ServerState::new();
+        let mut conn = ConnectionState::new();
+        run(&server, &mut conn, &["SET", "key", "value"]);
+
+        assert_eq!(run(&server, &mut conn, &["EXPIRE", "key", "-1"]), ":1\r\n");
+        assert_eq!(run(&server, &mut conn, &["EXISTS", "key"]), ":0\r\n");
+        assert_eq!(run(&server, &mut conn, &["EXPIRE", "key", "0"]), ":0\r\n");
+    }
+
+    #[test]
+    fn test_pexpire_command_sets_ttl_on_existing_key() {
+        let clock = TestClock::new();
+        let server = ServerState::new_with_clock(Arc::new(clock.clone()));
+        let mut conn = ConnectionState::new();
+        run(&server, &mut conn, &["SET", "key", "value"]);
+
+        assert_eq!(
+            run(&server, &mut conn, &["PEXPIRE", "missing", "100"]),
+            ":0\r\n"
+        );
+        assert_eq!(
+            run(&server, &mut conn, &["PEXPIRE", "key", "100"]),
+            ":1\r\n"
+        );
+        assert_eq!(run(&server, &mut conn, &["EXISTS", "key"]), ":1\r\n");
+
+        clock.advance(Duration::from_millis(150));
+        assert_eq!(run(&server, &mut conn, &["GET", "key"]), "$-1\r\n");
+    }
+
+    #[test]
+    fn test_integer_reply_bytes() {
+        assert_eq!(integer(0), ":0\r\n");
+        assert_eq!(integer(i64::MAX), ":9223372036854775807\r\n");
+        assert_eq!(integer(-1), ":-1\r\n");
+        assert_ne!(integer(0), "$-1\r\n");
+    }
+
+    #[test]
+    fn test_integer_replies_distinguish_zero_from_null() {
+        let server = ServerState::new();
+        let mut conn = ConnectionState::new();
+
+        assert_eq!(run(&server, &mut conn, &["LLEN", "missing"]), ":0\r\n");
+        assert_eq!(run(&server, &mut conn, &["GET", "missing"]), "$-1\r\n");
+        assert_eq!(
+            run(&server, &mut conn, &["RPUSH", "list", "a", "b"]),
+            ":2\r\n"
+        );
+        assert_eq!(
+            run(&server, &mut conn, &["DEL", "list", "missing"]),
+            ":1\r\n"
+        );
+        assert_eq!(run(&server, &mut conn, &["DEL", "list"]), ":0\r\n");
+    }
+
+    #[test]
+    fn test_object_encoding_command() {
+        let server = ServerState::new();
+        let mut conn = ConnectionState::new();
+        run(&server, &mut conn, &["SET", "int", "123"]);
+        run(&server, &mut conn, &["SET", "str", "hello"]);
+        run(&server, &mut conn, &["RPUSH", "list", "a"]);
+
+        assert_eq!(
+            run(&server, &mut conn, &["OBJECT", "ENCODING", "int"]),
+            "$3\r\nint\r\n"
+        );
+        assert_eq!(
+            run(&server, &mut conn, &["OBJECT", "ENCODING", "str"]),
+            "$6\r\nembstr\r\n"
+        );
+        assert_eq!(
+            run(&server, &mut conn, &["OBJECT", "ENCODING", "list"]),
+            "$8\r\nlistpack\r\n"
+        );
+        assert_eq!(
+            run(&server, &mut conn, &["OBJECT", "ENCODING", "missing"]),
+            "-ERR no such key\r\n"
+        );
+    }
+
+    #[test]
+    fn test_popping_the_last_element_deletes_the_list() {
+        let server = ServerState::new();
+        let mut conn = ConnectionState::new();
+        let pops: [&[&str]; 3] = [
+            &["LPOP", "list"],
+            &["LPOP", "list", "5"],
+            &["LMPOP", "1", "list", "RIGHT"],
+        ];
+
+        for pop in pops {
+            run(&server, &mut conn, &["RPUSH", "list", "only"]);
+            assert_eq!(
+                run(&server, &mut conn, &["OBJECT", "ENCODING", "list"]),
+                "$8\r\nlistpack\r\n"
+            );
+
+            run(&server, &mut conn, pop);
+            assert_eq!(run(&server, &mut conn, &["EXISTS", "list"]), ":0\r\n");
+            assert_eq!(run(&server, &mut conn, &["TYPE", "list"]), "+none\r\n");
+            assert_eq!(
+                run(&server, &mut conn, &["OBJECT", "ENCODING", "list"]),
+                "-ERR no such key\r\n",
+                "after {:?}",
+                pop
+            );
+        }
+    }
+
+    #[test]
+    fn test_debug_object_reports_encoding() {
+        let server = ServerState::new();
+        let mut conn = ConnectionState::new();
+        run(&server, &mut conn, &["SET", "key", "0123"]);
+
+        let reply = run(&server, &mut conn, &["DEBUG", "OBJECT", "key"]);
+        assert!(reply.contains(" encoding:embstr "), "{}", reply);
+        assert_eq!(
+            run(&server, &mut conn, &["DEBUG", "OBJECT", "missing"]),
+            "-ERR no such key\r\n"
+        );
+    }
+
+    #[test]
+    fn test_info_stats_counts_lazily_expired_key_once() {
+        let clock = TestClock::new();
+        let server = ServerState::new_with_clock(Arc::new(clock.clone()));
+        let mut conn = ConnectionState::new();
+        run(&server, &mut conn, &["SET", "key", "value", "PX", "1"]);
+        clock.advance(Duration::from_millis(10));
+
+        assert!(run(&server, &mut conn, &["INFO", "stats"]).contains("expired_keys:0\r\n"));
+        assert_eq!(run(&server, &mut conn, &["GET", "key"]), "$-1\r\n");
+        assert_eq!(run(&server, &mut conn, &["GET", "key"]), "$-1\r\n");
+        assert!(run(&server, &mut conn, &["INFO", "stats"]).contains("expired_keys:1\r\n"));
+    }
+
+    #[test]
+    fn test_copy_command_into_another_db() {
+        let server = ServerState::new();
+        let mut conn = ConnectionState::new();
+        run(&server, &mut conn, &["SET", "key", "value"]);
+
+        assert_eq!(
+            run(&server, &mut conn, &["COPY", "key", "key", "DB", "1"]),
+            ":1\r\n"
+        );
+        assert_eq!(
+            run(&server, &mut conn, &["COPY", "key", "key", "DB", "1"]),
+            ":0\r\n"
+        );
+        assert_eq!(
+            run(
+                &server,
+                &mut conn,
+                &["COPY", "key", "key", "DB", "1", "REPLACE"]
+            ),
+            ":1\r\n"
+        );
+
+        run(&server, &mut conn, &["SELECT", "1"]);
+        assert_eq!(run(&server, &mut conn, &["GET", "key"]), "$5\r\nvalue\r\n");
+    }
+
+    #[test]
+    fn test_copy_is_independent_of_its_source() {
+        let server = ServerState::new();
+        let mut conn = ConnectionState::new();
+        run(&server, &mut conn, &["RPUSH", "list", "a"]);
+        run(&server, &mut conn, &["SET", "string", "abc"]);
+        run(&server, &mut conn, &["XADD", "stream", "1-1", "f", "v"]);
+        for key in ["list", "string", "stream"] {
+            let copy = format!("{}-copy", key);
+            assert_eq!(run(&server, &mut conn, &["COPY", key, &copy]), ":1\r\n");
+        }
+
+        run(&server, &mut conn, &["RPUSH", "list", "b"]);
+        run(&server, &mut conn, &["LPUSH", "list-copy", "z"]);
+        assert_eq!(
+            run(&server, &mut conn, &["LRANGE", "list", "0", "-1"]),
+            "*2\r\n$1\r\na\r\n$1\r\nb\r\n"
+        );
+        assert_eq!(
+            run(&server, &mut conn, &["LRANGE", "list-copy", "0", "-1"]),
+            "*2\r\n$1\r\nz\r\n$1\r\na\r\n"
+        );
+
+        run(&server, &mut conn, &["SETRANGE", "string", "0", "x"]);
+        run(&server, &mut conn, &["APPEND", "string-copy", "d"]);
+        assert_eq!(run(&server, &mut conn, &["GET", "string"]), "$3\r\nxbc\r\n");
+        assert_eq!(
+            run(&server, &mut conn, &["GET", "string-copy"]),
+            "$4\r\nabcd\r\n"
+        );
+
+        run(&server, &mut conn, &["XADD", "stream", "2-1", "f", "w"]);
+        assert_eq!(
+            run(&server, &mut conn, &["XRANGE", "stream-copy", "-", "+"]),
+            "*1\r\n*2\r\n$3\r\n1-1\r\n*2\r\n$1\r\nf\r\n$1\r\nv\r\n"
+        );
+    }
+
+    #[test]
+    fn test_copy_command_rejects_same_object() {
+        let server = ServerState::new();
+        let mut conn = ConnectionState::new();
+        run(&server, &mut conn, &["SET", "key", "value"]);
+
+        assert_eq!(
+            run(&server, &mut conn, &["COPY", "key", "key"]),
+            "-ERR source and destination objects are the same\r\n"
+        );
+        assert_eq!(
+            run(&server, &mut conn, &["COPY", "key", "key", "DB", "0"]),
+            "-ERR source and destination objects are the same\r\n"
+        );
+        assert_eq!(
+            run(&server, &mut conn, &["COPY", "key", "key", "DB", "16"]),
+            "-ERR DB index is out of range\r\n"
+        );
+    }
+
+    #[test]
+    fn test_echo_command_round_trips_invalid_utf8() {
+        let server = ServerState::new();
+        let mut conn = ConnectionState::new();
+
+        let cmd = RespValue::Array(Some(vec![
+            RespValue::BulkString(Some(b"ECHO".to_vec())),
+            RespValue::BulkString(Some(b"\xc3\x28".to_vec())),
+        ]));
+        assert_eq!(
+            super::handle_command(&cmd, &server, &mut conn),
+            b"$2\r\n\xc3\x28\r\n"
+        );
+    }
+
+    #[test]
+    fn test_setrange_command_rejects_huge_offset() {
+        let server = ServerState::new();
+        let mut conn = ConnectionState::new();
+
+        assert_eq!(
+            run(&server, &mut conn, &["SETRANGE", "key", "536870912", "x"]),
+            "-ERR string exceeds maximum allowed size (proto-max-bulk-len)\r\n"
+        );
+        assert_eq!(
+            run(&server, &mut conn, &["SETRANGE", "key", "-1", "x"]),
+            "-ERR offset is out of range\r\n"
+        );
+        assert_eq!(run(&server, &mut conn, &["EXISTS", "key"]), ":0\r\n");
+    }
+
+    #[test]
+    fn test_getrange_command_on_missing_key_returns_empty_string() {
+        let server = ServerState::new();
+        let mut conn = ConnectionState::new();
+
+        assert_eq!(
+            run(&server, &mut conn, &["GETRANGE", "missing", "0", "-1"]),
+            "$0\r\n\r\n"
+        );
+        run(&server, &mut conn, &["RPUSH", "list", "a"]);
+        assert_eq!(
+            run(&server, &mut conn, &["GETRANGE", "list", "0", "-1"]),
+            "-WRONGTYPE Operation against a key holding the wrong kind of value\r\n"
+        );
+    }
+
+    #[test]
+    fn test_getrange_single_byte_and_crossed_bounds() {
+        let server = ServerState::new();
+        let mut conn = ConnectionState::new();
+        run(&server, &mut conn, &["SET", "k", "Hello"]);
+
+        assert_eq!(
+            run(&server, &mut conn, &["GETRANGE", "k", "0", "0"]),
+            "$1\r\nH\r\n"
+        );
+        assert_eq!(
+            run(&server, &mut conn, &["GETRANGE", "k", "-1", "-1"]),
+            "$1\r\no\r\n"
+        );
+        assert_eq!(
+            run(&server, &mut conn, &["GETRANGE", "k", "2", "1"]),
+            "$0\r\n\r\n"
+        );
+        assert_eq!(
+            run(&server, &mut conn, &["SUBSTR", "k", "1", "-2"]),
+            "$3\r\nell\r\n"
+        );
+    }
+
+    #[test]
+    fn test_exec_embeds_runtime_errors_and_keeps_going() {
+        let server = ServerState::new();
+        let mut conn = ConnectionState::new();
+        run(&server, &mut conn, &["RPUSH", "list", "a"]);
+
+        run(&server, &mut conn, &["MULTI"]);
+        run(&server, &mut conn, &["SET", "key", "1"]);
+        run(&server, &mut conn, &["INCR", "list"]);
+        run(&server, &mut conn, &["INCR", "key"]);
+        assert_eq!(
+            run(&server, &mut conn, &["EXEC"]),
+            "*3\r\n+OK\r\n-WRONGTYPE Operation against a key holding the wrong kind of value\r\n:2\r\n"
+        );
+    }
+
+    #[test]
+    fn test_exec_aborts_after_queue_time_errors() {
+        let server = ServerState::new();
+        let mut conn = ConnectionState::new();
+
+        for bad in [&["SET", "key"][..], &["NOSUCHCOMMAND"][..]] {
+            run(&server, &mut conn, &["MULTI"]);
+            run(&server, &mut conn, &["SET", "key", "1"]);
+            assert!(run(&server, &mut conn, bad).starts_with("-ERR"));
+            assert_eq!(
+                run(&server, &mut conn, &["EXEC"]),
+                "-EXECABORT Transaction discarded because of previous errors.\r\n"
+            );
+            assert!(!conn.in_transaction());
+            assert_eq!(run(&server, &mut conn, &["GET", "key"]), "$-1\r\n");
+        }
+
+        // A fresh MULTI starts clean.
+        run(&server, &mut conn, &["MULTI"]);
+        run(&server, &mut conn, &["SET", "key", "1"]);
+        assert_eq!(run(&server, &mut conn, &["EXEC"]), "*1\r\n+OK\r\n");
+    }
+
+    #[test]
+    fn test_exec_aborts_when_watched_key_is_deleted_and_recreated() {
+        let server = ServerState::new();
+        let mut conn = ConnectionState::new();
+        let mut other = ConnectionState::new();
+        run(&server, &mut conn, &["SET", "key", "value"]);
+
+        assert_eq!(run(&server, &mut conn, &["WATCH", "key"]), "+OK\r\n");
+        run(&server, &mut other, &["DEL", "key"]);
+        run(&server, &mut other, &["SET", "key", "value"]);
+
+        run(&server, &mut conn, &["MULTI"]);
+        run(&server, &mut conn, &["SET", "key", "mine"]);
+        assert_eq!(run(&server, &mut conn, &["EXEC"]), "*-1\r\n");
+        assert_eq!(run(&server, &mut conn, &["GET", "key"]), "$5\r\nvalue\r\n");
+    }
+
+    #[test]
+    fn test_exec_aborts_when_missing_watched_key_is_set_and_deleted() {
+        let server = ServerState::new();
+        let mut conn = ConnectionState::new();
+        let mut other = ConnectionState::new();
+
+        assert_eq!(run(&server, &mut conn, &["WATCH", "key"]), "+OK\r\n");
+        run(&server, &mut other, &["SET", "key", "value"]);
+        run(&server, &mut other, &["DEL", "key"]);
+
+        run(&server, &mut conn, &["MULTI"]);
+        run(&server, &mut conn, &["SET", "key", "mine"]);
+        assert_eq!(run(&server, &mut conn, &["EXEC"]), "*-1\r\n");
+        assert_eq!(run(&server, &mut conn, &["EXISTS", "key"]), ":0\r\n");
+
+        // A flush counts too, and RESP3 gets its own null.
+        run(&server, &mut conn, &["HELLO", "3"]);
+        run(&server, &mut conn, &["WATCH", "key"]);
+        run(&server, &mut other, &["FLUSHDB"]);
+        run(&server, &mut conn, &["MULTI"]);
+        run(&server, &mut conn, &["SET", "key", "mine"]);
+        assert_eq!(run(&server, &mut conn, &["EXEC"]), "_\r\n");
+    }
+
+    #[test]
+    fn test_exec_runs_when_watched_key_is_untouched() {
+        let server = ServerState::new();
+        let mut conn = ConnectionState::new();
+
+        assert_eq!(run(&server, &mut conn, &["WATCH", "key"]), "+OK\r\n");
+        run(&server, &mut conn, &["MULTI"]);
+        assert_eq!(
+            run(&server, &mut conn, &["WATCH", "key"]),
+            "-ERR WATCH inside MULTI is not allowed\r\n"
+        );
+        run(&server, &mut conn, &["SET", "key", "mine"]);
+        assert_eq!(run(&server, &mut conn, &["EXEC"]), "*1\r\n+OK\r\n");
+        assert!(conn.watched.is_empty());
+    }
+
+    #[test]
+    fn test_flushdb_sync_only_empties_selected_db() {
+        let server = ServerState::new();
+        let mut conn = ConnectionState::new();
+        run(&server, &mut conn, &["SET", "key", "value"]);
+        run(&server, &mut conn, &["SELECT", "1"]);
+        run(&server, &mut conn, &["SET", "key", "value"]);
+
+        assert_eq!(run(&server, &mut conn, &["FLUSHDB", "SYNC"]), "+OK\r\n");
+        assert_eq!(run(&server, &mut conn, &["EXISTS", "key"]), ":0\r\n");
+        run(&server, &mut conn, &["SELECT", "0"]);
+        assert_eq!(run(&server, &mut conn, &["EXISTS", "key"]), ":1\r\n");
+        assert_eq!(
+            run(&server, &mut conn, &["FLUSHDB", "LATER"]),
+            "-ERR syntax error\r\n"
+        );
+    }
+
+    #[test]
+    fn test_flushall_async_empties_every_db() {
+        let server = ServerState::new();
+        let mut conn = ConnectionState::new();
+        run(&server, &mut conn, &["SET", "key", "value"]);
+        run(&server, &mut conn, &["SELECT", "1"]);
+        run(&server, &mut conn, &["SET", "key", "value"]);
+
+        assert_eq!(run(&server, &mut conn, &["FLUSHALL", "ASYNC"]), "+OK\r\n");
+        assert_eq!(run(&server, &mut conn, &["EXISTS", "key"]), ":0\r\n");
+        run(&server, &mut conn, &["SELECT", "0"]);
+        assert_eq!(run(&server, &mut conn, &["EXISTS", "key"]), ":0\r\n");
+    }
+
+    #[test]
+    fn test_mixed_case_dispatch_matches_upper_case() {
+        let cases: &[(&[&str], &[&str])] = &[
+            (&["set", "k", "v"], &["SET", "k", "v"]),
+            (&["Get", "k"], &["GET", "k"]),
+            (&["sTrLeN", "k"], &["STRLEN", "k"]),
+            (&["rpush", "list", "a"], &["RPUSH", "list", "a"]),
+            (
+                &["lRange", "list", "0", "-1"],
+                &["LRANGE", "list", "0", "-1"],
+            ),
+            (&["pInG"], &["PING"]),
+            (
+                &["client", "tracking", "off"],
+                &["CLIENT", "TRACKING", "OFF"],
+            ),
+            (
+                &["hello", "2", "setname", "conn"],
+                &["HELLO", "2", "SETNAME", "conn"],
+            ),
+            (
+                &["config", "get", "maxmemory"],
+                &["CONFIG", "GET", "maxmemory"],
+            ),
+            (&["Command", "count"], &["COMMAND", "COUNT"]),
+            (&["ObJeCt", "encoding", "k"], &["OBJECT", "ENCODING", "k"]),
+        ];
+        let upper_server = ServerState::new();
+        let mut upper_conn = ConnectionState::new();
+        let mixed_server = ServerState::new();
+        let mut mixed_conn = ConnectionState::new();
+        for (mixed, upper) in cases {
+            let expected = run(&upper_server, &mut upper_conn, upper);
+            assert!(!expected.starts_with("-ERR"), "{:?}: {}", upper, expected);
+            assert_eq!(
+                run(&mixed_server, &mut mixed_conn, mixed),
+                expected,
+                "{:?}",
+                mixed
+            );
+        }
+
+        for name in ["gEt", "client", "PunSubscribe", "pexpiretime"] {
+            let value = RespValue::BulkString(Some(name.as_bytes().to_vec()));
+            assert!(
+                matches!(dispatch_name(&value), Cow::Borrowed(_)),
+                "{}",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn test_unknown_and_malformed_command_names_fall_back() {
+        let server = ServerState::new();
+        let mut conn = ConnectionState::new();
+        assert_eq!(
+            run(&server, &mut conn, &["nOsUcHcOmMaNd"]),
+            "-ERR unknown command: 'NOSUCHCOMMAND'\r\n"
+        );
+        let long = "x".repeat(MAX_COMMAND_NAME_LEN + 1);
+        assert!(matches!(
+            dispatch_name(&RespValue::BulkString(Some(long.clone().into_bytes()))),
+            Cow::Owned(name) if name == long.to_ascii_uppercase()
+        ));
+        assert_eq!(dispatch_name(&RespValue::Integer(1)), "");
+        assert_eq!(dispatch_name(&RespValue::BulkString(None)), "");
+    }
+
+    #[test]
+    fn test_hot_commands_dispatch_case_insensitively() {
+        let server = ServerState::new();
+        let mut conn = ConnectionState::new();
+        run(&server, &mut conn, &["set", "key", "value"]);
+
+        for name in ["get", "GET", "Get"] {
+            assert_eq!(run(&server, &mut conn, &[name, "key"]), "$5\r\nvalue\r\n");
+        }
+        for name in ["ping", "PING", "PiNg"] {
+            assert_eq!(run(&server, &mut conn, &[name]), "+PONG\r\n");
+        }
+        assert!(matches!(
+            dispatch_name(&RespValue::BulkString(Some(b"gEt".to_vec()))),
+            Cow::Borrowed("GET")
+        ));
+    }
+
+    #[test]
+    fn test_xread_block_receives_entry_added_by_another_client() {
+        let server = ServerState::new();
+        let mut conn = ConnectionState::new();
+
+        let server_clone = server.clone();
+        let reader = std::thread::spawn(move || {
+            run(
+                &server_clone,
+                &mut ConnectionState::new(),
+                &["XREAD", "BLOCK", "5000", "STREAMS", "stream", "$"],
+            )
+        });
+
+        sleep(Duration::from_millis(100));
+        run(
+            &server,
+            &mut conn,
+            &["XADD", "stream", "1-1", "field", "value"],
+        );
+
+        assert_eq!(
+            reader.join().unwrap(),
+            "*1\r\n*2\r\n$6\r\nstream\r\n*1\r\n*2\r\n$3\r\n1-1\r\n*2\r\n$5\r\nfield\r\n$5\r\nvalue\r\n"
+        );
+    }
+
+    #[test]
+    fn test_xread_block_times_out_with_null_array() {
+        let server = ServerState::new();
+        let mut conn = ConnectionState::new();
+
+        assert_eq!(
+            run(
+                &server,
+                &mut conn,
+                &["XREAD", "BLOCK", "50", "STREAMS", "stream", "$"]
+            ),
+            "*-1\r\n"
+        );
+        assert_eq!(
+            run(&server, &mut conn, &["XREAD", "STREAMS", "a", "b", "0-0"]),
+            "-ERR Unbalanced 'xread' list of streams: for each stream key an ID or '$' must be specified.\r\n"
+        );
+    }
+
+    #[test]
+    fn test_scan_command_filters_by_type() {
+        let server = ServerState::new();
+        let mut conn = ConnectionState::new();
+        run(&server, &mut conn, &["SET", "string", "value"]);
+        run(&server, &mut conn, &["RPUSH", "list", "a"]);
+        run(
+            &server,
+            &mut conn,
+            &["XADD", "stream", "1-1", "field", "value"],
+        );
+
+        assert_eq!(
+            run(&server, &mut conn, &["SCAN", "0", "TYPE", "list"]),
+            "*2\r\n$1\r\n0\r\n*1\r\n$4\r\nlist\r\n"
+        );
+        assert_eq!(
+            run(&server, &mut conn, &["SCAN", "0", "TYPE", "STREAM"]),
+            "*2\r\n$1\r\n0\r\n*1\r\n$6\r\nstream\r\n"
+        );
+        assert_eq!(
+            run(&server, &mut conn, &["SCAN", "0", "TYPE", "hash"]),
+            "*2\r\n$1\r\n0\r\n*0\r\n"
+        );
+    }
+
+    #[test]
+    fn test_incr_rejects_non_canonical_integers() {
+        let server = ServerState::new();
+        let mut conn = ConnectionState::new();
+        let not_an_integer = "-ERR value is not an integer or out of range\r\n";
+
+        for value in [" 5", "5 ", "+5", "5\n"] {
+            run(&server, &mut conn, &["SET", "n", value]);
+            assert_eq!(run(&server, &mut conn, &["INCR", "n"]), not_an_integer);
+            assert_eq!(run(&server, &mut conn, &["DECR", "n"]), not_an_integer);
+            assert_eq!(
+                run(&server, &mut conn, &["INCRBY", "n", "2"]),
+                not_an_integer
+            );
+            assert_eq!(
+                run(&server, &mut conn, &["INCRBY", "other", value]),
+                not_an_integer
+            );
+        }
+
+        run(&server, &mut conn, &["SET", "n", "-5"]);
+        assert_eq!(run(&server, &mut conn, &["INCR", "n"]), ":-4\r\n");
+        assert_eq!(run(&server, &mut conn, &["DECRBY", "n", "10"]), ":-14\r\n");
+        assert_eq!(run(&server, &mut conn, &["INCRBY", "n", "-1"]), ":-15\r\n");
+        assert_eq!(run(&server, &mut conn, &["GET", "n"]), "$3\r\n-15\r\n");
+    }
+
+    #[test]
+    fn test_incr_on_missing_key_and_overflow() {
+        let server = ServerState::new();
+        let mut conn = ConnectionState::new();
+        assert_eq!(run(&server, &mut conn, &["DECR", "fresh"]), ":-1\r\n");
+        assert_eq!(
+            run(
+                &server,
+                &mut conn,
+                &["DECRBY", "fresh", "-9223372036854775808"]
+            ),
+            "-ERR value is not an integer or out of range\r\n"
+        );
+
+        run(&server, &mut conn, &["SET", "n", "9223372036854775807"]);
+        assert_eq!(
+            run(&server, &mut conn, &["INCR", "n"]),
+            "-ERR increment or decrement would overflow\r\n"
+        );
+
+        run(&server, &mut conn, &["RPUSH", "list", "a"]);
+        assert!(run(&server, &mut conn, &["INCR", "list"]).starts_with("-WRONGTYPE"));
+    }
+
+    #[test]
+    fn test_client_tracking_pushes_invalidation_to_reader() {
+        let server = ServerState::new();
+        let output = Arc::new(Mutex::new(Vec::new()));
+        let mut reader = ConnectionState::new();
+        reader.id = 1;
+        reader.push = Some(PushSink(output.clone()));
+        let mut writer = ConnectionState::new();
+        writer.id = 2;
+
+        assert!(run(&server, &mut reader, &["CLIENT", "TRACKING", "on"]).starts_with("-ERR"));
+        assert!(run(&server, &mut reader, &["HELLO", "3"]).starts_with("%7\r\n"));
+        assert_eq!(
+            run(&server, &mut reader, &["CLIENT", "TRACKING", "on"]),
+            "+OK\r\n"
+        );
+
+        run(&server, &mut writer, &["SET", "key", "v1"]);
+        run(&server, &mut reader, &["GET", "key"]);
+        run(&server, &mut writer, &["SET", "other", "v1"]);
+        assert!(output.lock().unwrap().is_empty());
+
+        run(&server, &mut writer, &["SET", "key", "v2"]);
+        assert_eq!(
+            output.lock().unwrap().as_slice(),
+            b">2\r\n$10\r\ninvalidate\r\n*1\r\n$3\r\nkey\r\n"
+        );
+
+        // Invalidation is one-shot until the key is read again.
+        output.lock().unwrap().clear();
+        run(&server, &mut writer, &["SET", "key", "v3"]);
+        assert!(output.lock().unwrap().is_empty());
+
+        run(&server, &mut reader, &["GET", "key"]);
+        assert_eq!(
+            run(&server, &mut reader, &["CLIENT", "TRACKING", "off"]),
+            "+OK\r\n"
+        );
+        run(&server, &mut writer, &["SET", "key", "v4"]);
+        assert!(output.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_unsubscribe_without_arguments_drops_everything() {
+        let server = ServerState::new();
+        let mut conn = ConnectionState::new();
+        assert_eq!(
+            run(&server, &mut conn, &["SUBSCRIBE", "a", "b"]),
+            "*3\r\n$9\r\nsubscribe\r\n$1\r\na\r\n:1\r\n\
+             *3\r\n$9\r\nsubscribe\r\n$1\r\nb\r\n:2\r\n"
+        );
+        run(&server, &mut conn, &["PSUBSCRIBE", "news.*"]);
+
+        assert_eq!(
+            run(&server, &mut conn, &["UNSUBSCRIBE"]),
+            "*3\r\n$11\r\nunsubscribe\r\n$1\r\na\r\n:2\r\n\
+             *3\r\n$11\r\nunsubscribe\r\n$1\r\nb\r\n:1\r\n"
+        );
+        assert_eq!(
+            run(&server, &mut conn, &["PUNSUBSCRIBE"]),
+            "*3\r\n$12\r\npunsubscribe\r\n$6\r\nnews.*\r\n:0\r\n"
+        );
+    }
+
+    #[test]
+    fn test_unsubscribe_with_no_subscriptions() {
+        let server = ServerState::new();
+        let mut conn = ConnectionState::new();
+        assert_eq!(
+            run(&server, &mut conn, &["UNSUBSCRIBE"]),
+            "*3\r\n$11\r\nunsubscribe\r\n$-1\r\n:0\r\n"
+        );
+        assert_eq!(
+            run(&server, &mut conn, &["PUNSUBSCRIBE"]),
+            "*3\r\n$12\r\npunsubscribe\r\n$-1\r\n:0\r\n"
+        );
+
+        run(&server, &mut conn, &["HELLO", "3"]);
+        assert_eq!(
+            run(&server, &mut conn, &["UNSUBSCRIBE"]),
+            ">3\r\n$11\r\nunsubscribe\r\n_\r\n:0\r\n"
+        );
+    }
+
+    #[test]
+    fn test_reused_reply_buffer_matches_fresh_replies() {
+        let commands: &[&[&str]] = &[
+            &["PING"],
+            &["SET", "key", "value"],
+            &["GET", "key"],
+            &["GET", "missing"],
+            &["ECHO", "hello"],
+            &["RPUSH", "list", "a", "b"],
+            &["LRANGE", "list", "0", "-1"],
+            &["MULTI"],
+            &["GET", "key"],
+            &["INCR", "counter"],
+            &["EXEC"],
+            &["GET", "list"],
+            &["NOPE"],
+        ];
+        let fresh = ServerState::new();
+        let pooled = ServerState::new();
+        let mut fresh_conn = ConnectionState::new();
+        let mut pooled_conn = ConnectionState::new();
+        let mut buffer = Vec::new();
+
+        for args in commands {
+            let value = RespValue::Array(Some(
+                args.iter()
+                    .map(|arg| RespValue::BulkString(Some(arg.as_bytes().to_vec())))
+                    .collect(),
+            ));
+            buffer.clear();
+            handle_command_into(&value, &pooled, &mut pooled_conn, &mut buffer);
+            assert_eq!(
+                buffer,
+                super::handle_command(&value, &fresh, &mut fresh_conn),
+                "{:?}",
+                args
+            );
+        }
+    }
+
+    #[test]
+    fn test_scan_match_bounds_work_per_call_on_sparse_keyspace() {
+        let server = ServerState::new();
+        let mut conn = ConnectionState::new();
+        let storage = server.db(0);
+        for i in 0..10_000 {
+            let key = if i % 2_000 == 0 {
+                format!("needle:{}", i)
+            } else {
+                format!("hay:{}", i)
+            };
+            storage.set(key, b"v".to_vec());
+        }
+
+        let mut found = Vec::new();
+        let mut cursor = "0".to_string();
+        let mut calls = 0;
+        loop {
+            let reply = super::handle_command(
+                &RespValue::Array(Some(
+                    ["SCAN", &cursor, "MATCH", "needle:*", "COUNT", "100"]
+                        .iter()
+                        .map(|arg| RespValue::BulkString(Some(arg.as_bytes().to_vec())))
+                        .collect(),
+                )),
+                &server,
+                &mut conn,
+            );
+            let mut parser = crate::RespParser::new();
+            parser.feed(&reply);
+            let crate::ParseResult::Complete(RespValue::Array(Some(parts)), _) = parser.parse()
+            else {
+                panic!("unexpected SCAN reply");
+            };
+            let RespValue::Array(Some(keys)) = &parts[1] else {
+                panic!("unexpected SCAN keys");
+            };
+            found.extend(keys.iter().map(extract_key));
+            cursor = extract_key(&parts[0]);
+            calls += 1;
+            if cursor == "0" {
+                break;
+            }
+        }
+
+        found.sort();
+        assert_eq!(
+            found,
+            [
+                "needle:0",
+                "needle:2000",
+                "needle:4000",
+                "needle:6000",
+                "needle:8000"
+            ]
+        );
+        assert!(calls >= 100, "only {} calls", calls);
+    }
+
+    #[test]
+    fn test_parse_memory_units() {
+        assert_eq!(parse_memory("100"), Some(100));
+        assert_eq!(parse_memory("1k"), Some(1000));
+        assert_eq!(parse_memory("1K"), Some(1000));
+        assert_eq!(parse_memory("1kb"), Some(1024));
+        assert_eq!(parse_memory("2mb"), Some(2 * 1024 * 1024));
+        assert_eq!(parse_memory("1gb"), Some(1024 * 1024 * 1024));
+        assert_eq!(parse_memory("kb"), None);
+        assert_eq!(parse_memory("-1"), None);
+        assert_eq!(parse_memory("1tb"), None);
+    }
+
+    #[test]
+    fn test_debug_quicklist_packed_threshold_forces_plain_nodes() {
+        let server = ServerState::new();
+        let mut conn = ConnectionState::new();
+        run(&server, &mut conn, &["RPUSH", "list", "aaaaa", "bbbbb"]);
+        assert_eq!(
+            run(&server, &mut conn, &["OBJECT", "ENCODING", "list"]),
+            "$8\r\nlistpack\r\n"
+        );
+
+        assert_eq!(
+            run(
+                &server,
+                &mut conn,
+                &["DEBUG", "QUICKLIST-PACKED-THRESHOLD", "1"]
+            ),
+            "+OK\r\n"
+        );
+        assert_eq!(
+            run(&server, &mut conn, &["OBJECT", "ENCODING", "list"]),
+            "$9\r\nquicklist\r\n"
+        );
+
+        run(
+            &server,
+            &mut conn,
+            &["DEBUG", "QUICKLIST-PACKED-THRESHOLD", "1K"],
+        );
+        assert_eq!(
+            run(&server, &mut conn, &["OBJECT", "ENCODING", "list"]),
+            "$8\r\nlistpack\r\n"
+        );
+
+        run(
+            &server,
+            &mut conn,
+            &["DEBUG", "QUICKLIST-PACKED-THRESHOLD", "1"],
+        );
+        run(
+            &server,
+            &mut conn,
+            &["DEBUG", "QUICKLIST-PACKED-THRESHOLD", "0"],
+        );
+        assert_eq!(
+            run(&server, &mut conn, &["OBJECT", "ENCODING", "list"]),
+            "$8\r\nlistpack\r\n"
+        );
+
+        assert!(run(
+            &server,
+            &mut conn,
+            &["DEBUG", "QUICKLIST-PACKED-THRESHOLD", "5gb"]
+        )
+        .starts_with("-ERR argument must be a memory value"));
+    }
+
+    #[test]
+    fn test_debug_listpack_dumps_small_lists_only() {
+        let server = ServerState::new();
+        let mut conn = ConnectionState::new();
+        run(&server, &mut conn, &["RPUSH", "small", "a", "bc"]);
+        assert_eq!(
+            run(&server, &mut conn, &["DEBUG", "LISTPACK", "small"]),
+            "$5\r\na\nbc\n\r\n"
+        );
+
+        let mut args = vec!["RPUSH", "large"];
+        args.extend(std::iter::repeat_n("x", 200));
+        run(&server, &mut conn, &args);
+        assert!(run(&server, &mut conn, &["DEBUG", "LISTPACK", "large"])
+            .starts_with("-ERR The value stored at the specified key is not represented"));
+        assert_eq!(
+            run(&server, &mut conn, &["DEBUG", "LISTPACK", "missing"]),
+            "-ERR no such key\r\n"
+        );
+        assert_eq!(
+            run(&server, &mut conn, &["DEBUG", "LISTPACK-ENTRIES"]),
+            "+OK\r\n"
+        );
+
+        let binary = |args: &[&[u8]]| {
+            RespValue::Array(Some(
+                args.iter()
+                    .map(|arg| RespValue::BulkString(Some(arg.to_vec())))
+                    .collect(),
+            ))
+        };
+        super::handle_command(
+            &binary(&[b"RPUSH", b"bin", b"\xff\x00"]),
+            &server,
+            &mut conn,
+        );
+        assert_eq!(
+            super::handle_command(
+                &binary(&[b"DEBUG", b"LISTPACK", b"bin"]),
+                &server,
+                &mut conn
+            ),
+            b"$3\r\n\xff\x00\n\r\n"
+        );
+    }
+
+    #[test]
+    fn test_cluster_stubs_in_standalone_mode() {
+        let server = ServerState::new();
+        let mut conn = ConnectionState::new();
+
+        assert!(run(&server, &mut conn, &["CLUSTER", "INFO"]).contains("cluster_enabled:0\r\n"));
+
+        let myid = run(&server, &mut conn, &["CLUSTER", "MYID"]);
+        let id = myid.strip_prefix("$40\r\n").unwrap().trim_end();
+        assert_eq!(id.len(), 40);
+        assert!(id.bytes().all(|b| b.is_ascii_hexdigit()));
+        assert!(run(&server, &mut conn, &["CLUSTER", "NODES"]).contains(id));
+
+        assert_eq!(run(&server, &mut conn, &["CLUSTER", "SLOTS"]), "*0\r\n");
+        assert_eq!(run(&server, &mut conn, &["CLUSTER", "SHARDS"]), "*0\r\n");
+    }
+
+    #[test]
+    fn test_lmpop_skips_empty_keys_and_validates_numkeys() {
+        let server = ServerState::new();
+        let mut conn = ConnectionState::new();
+        run(&server, &mut conn, &["RPUSH", "second", "a", "b", "c"]);
+
+        assert_eq!(
+            run(
+                &server,
+                &mut conn,
+                &["LMPOP", "2", "first", "second", "LEFT", "COUNT", "2"]
+            ),
+            "*2\r\n$6\r\nsecond\r\n*2\r\n$1\r\na\r\n$1\r\nb\r\n"
+        );
+        assert_eq!(
+            run(
+                &server,
+                &mut conn,
+                &["LMPOP", "2", "first", "second", "RIGHT"]
+            ),
+            "*2\r\n$6\r\nsecond\r\n*1\r\n$1\r\nc\r\n"
+        );
+        assert_eq!(
+            run(
+                &server,
+                &mut conn,
+                &["LMPOP", "2", "first", "second", "LEFT"]
+            ),
+            "*-1\r\n"
+        );
+
+        assert_eq!(
+            run(&server, &mut conn, &["LMPOP", "0", "first", "LEFT"]),
+            "-ERR numkeys should be greater than 0\r\n"
+        );
+        assert_eq!(
+            run(&server, &mut conn, &["LMPOP", "3", "first", "LEFT"]),
+            "-ERR syntax error\r\n"
+        );
+        assert_eq!(
+            run(&server, &mut conn, &["LMPOP", "1", "first", "UP"]),
+            "-ERR syntax error\r\n"
+        );
+        assert_eq!(
+            run(
+                &server,
+                &mut conn,
+                &["LMPOP", "1", "first", "LEFT", "COUNT", "0"]
+            ),
+            "-ERR count should be greater than 0\r\n"
+        );
+    }
+
+    #[test]
+    fn test_blmpop_wakes_up_when_a_watched_key_is_pushed() {
+        let server = ServerState::new();
+        let server_clone = server.clone();
+        let blocked = std::thread::spawn(move || {
+            run(
+                &server_clone,
+                &mut ConnectionState::new(),
+                &["BLMPOP", "5", "2", "first", "second", "RIGHT", "COUNT", "2"],
+            )
+        });
+
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        let mut conn = ConnectionState::new();
+        run(&server, &mut conn, &["RPUSH", "second", "a", "b", "c"]);
+
+        assert_eq!(
+            blocked.join().unwrap(),
+            "*2\r\n$6\r\nsecond\r\n*2\r\n$1\r\nc\r\n$1\r\nb\r\n"
+        );
+        assert_eq!(run(&server, &mut conn, &["LLEN", "second"]), ":1\r\n");
+        assert_eq!(
+            run(
+                &server,
+                &mut conn,
+                &["BLMPOP", "0.05", "1", "empty", "LEFT"]
+            ),
+            "*-1\r\n"
+        );
+    }
+
+    #[test]
+    fn test_dispatcher_and_command_table_agree() {
+        assert!(DISPATCHED_COMMANDS.len() > 40);
+        assert!(DISPATCHED_COMMANDS
+            .iter()
+            .all(|name| name.len() <= MAX_COMMAND_NAME_LEN));
+        for name in DISPATCHED_COMMANDS {
+            assert!(
+                command_table::lookup(name).is_some(),
+                "{} is dispatched but missing from the command table",
+                name
+            );
+        }
+
+        for spec in command_table::COMMANDS {
+            let name = spec.name.to_ascii_uppercase();
+            assert!(
+                DISPATCHED_COMMANDS.contains(&name.as_str()),
+                "{} is in the command table but not in DISPATCHED_COMMANDS",
+                name
+            );
+
+            // Running it proves the dispatcher has an arm for it.
+            let mut args = vec![name.as_str()];
+            args.resize(spec.arity.unsigned_abs() as usize, "x");
+            let reply = run(&ServerState::new(), &mut ConnectionState::new(), &args);
+            assert!(!reply.starts_with("-ERR unknown command"), "{}", reply);
+        }
+    }
+
+    #[test]
+    fn test_small_integers_are_shared_until_modified() {
+        let server = ServerState::new();
+        let mut conn = ConnectionState::new();
+        run(&server, &mut conn, &["SET", "small", "42"]);
+        run(&server, &mut conn, &["SET", "large", "70000"]);
+
+        assert_eq!(
+            run(&server, &mut conn, &["OBJECT", "REFCOUNT", "small"]),
+            ":2147483647\r\n"
+        );
+        assert_eq!(
+            run(&server, &mut conn, &["OBJECT", "REFCOUNT", "large"]),
+            ":1\r\n"
+        );
+
+        let usage = |conn: &mut ConnectionState, key: &str| {
+            let reply = run(&server, conn, &["MEMORY", "USAGE", key]);
+            reply[1..reply.len() - 2].parse::<usize>().unwrap()
+        };
+        let shared = usage(&mut conn, "small");
+        assert!(shared < usage(&mut conn, "large"));
+
+        run(&server, &mut conn, &["APPEND", "small", "1"]);
+        assert_eq!(
+            run(&server, &mut conn, &["OBJECT", "REFCOUNT", "small"]),
+            ":1\r\n"
+        );
+        assert!(usage(&mut conn, "small") > shared);
+
+        assert_eq!(
+            run(&server, &mut conn, &["MEMORY", "USAGE", "missing"]),
+            "$-1\r\n"
+        );
+    }
+
+    #[test]
+    fn test_ttl_family_over_every_key_state() {
+        let clock = TestClock::new();
+        let server = ServerState::new_with_clock(Arc::new(clock.clone()));
+        let mut conn = ConnectionState::new();
+        run(&server, &mut conn, &["SET", "persistent", "v"]);
+        run(&server, &mut conn, &["SET", "volatile", "v", "EX", "100"]);
+        run(&server, &mut conn, &["SET", "expired", "v", "PX", "1"]);
+        clock.advance(Duration::from_millis(5));
+
+        let now_ms = clock.now().duration_since(UNIX_EPOCH).unwrap().as_millis() as i64;
+        // (command, expected range for "volatile")
+        let commands: [(&str, std::ops::RangeInclusive<i64>); 4] = [
+            ("TTL", 99..=100),
+            ("PTTL", 99_000..=100_000),
+            ("EXPIRETIME", now_ms / 1000 + 99..=now_ms / 1000 + 101),
+            ("PEXPIRETIME", now_ms + 99_000..=now_ms + 100_100),
+        ];
+
+        for (command, volatile) in commands {
+            let reply = |conn: &mut ConnectionState, key: &str| {
+                let reply = run(&server, conn, &[command, key]);
+                reply[1..reply.len() - 2].parse::<i64>().unwrap()
+            };
+            assert_eq!(reply(&mut conn, "missing"), -2, "{}", command);
+            assert_eq!(reply(&mut conn, "persistent"), -1, "{}", command);
+            assert_eq!(reply(&mut conn, "expired"), -2, "{}", command);
+            let remaining = reply(&mut conn, "volatile");
+            assert!(volatile.contains(&remaining), "{} {}", command, remaining);
+        }
+        assert_eq!(run(&server, &mut conn, &["EXISTS", "expired"]), ":0\r\n");
+    }
+
+    #[test]
+    fn test_default_ttl_applies_only_to_plain_sets() {
+        let server = ServerState::new();
+        let mut conn = ConnectionState::new();
+        run(&server, &mut conn, &["SET", "persistent", "v"]);
+        run(
+            &server,
+            &mut conn,
+            &["CONFIG", "SET", "default-ttl-seconds", "100"],
+        );
+
+        run(&server, &mut conn, &["SET", "plain", "v"]);
+        assert_eq!(run(&server, &mut conn, &["TTL", "plain"]), ":100\r\n");
+        run(&server, &mut conn, &["SET", "explicit", "v", "EX", "5"]);
+        assert_eq!(run(&server, &mut conn, &["TTL", "explicit"]), ":5\r\n");
+
+        run(&server, &mut conn, &["SET", "persistent", "w", "KEEPTTL"]);
+        assert_eq!(run(&server, &mut conn, &["TTL", "persistent"]), ":-1\r\n");
+        assert_eq!(
+            run(&server, &mut conn, &["GET", "persistent"]),
+            "$1\r\nw\r\n"
+        );
+        run(&server, &mut conn, &["SET", "explicit", "w", "KEEPTTL"]);
+        assert_eq!(run(&server, &mut conn, &["TTL", "explicit"]), ":5\r\n");
+
+        assert_eq!(
+            run(&server, &mut conn, &["SET", "k", "v", "KEEPTTL", "EX", "1"]),
+            "-ERR syntax error\r\n"
+        );
+    }
+
+    #[test]
+    fn test_client_id_and_list() {
+        let server = ServerState::new();
+        let first = server.clients.register(None);
+        let second = server
+            .clients
+            .register(Some("127.0.0.1:6000".parse().unwrap()));
+        let mut conn = ConnectionState::new();
+        conn.id = second.id();
+
+        assert_eq!(
+            run(&server, &mut conn, &["CLIENT", "ID"]),
+            format!(":{}\r\n", second.id())
+        );
+        let list = format!(
+            "id={} addr=\nid={} addr=127.0.0.1:6000\n",
+            first.id(),
+            second.id()
+        );
+        assert_eq!(
+            run(&server, &mut conn, &["CLIENT", "LIST"]),
+            format!("${}\r\n{}\r\n", list.len(), list)
+        );
+    }
+
+    fn server_with_password() -> ServerState {
+        let server = ServerState::new();
+        server
+            .config
+            .set(&[("requirepass".to_string(), "secret".to_string())])
+            .unwrap();
+        server
+    }
+
+    #[test]
+    fn test_hello_with_inline_auth() {
+        let server = server_with_password();
+        let mut conn = ConnectionState::new();
+        assert_eq!(
+            run(&server, &mut conn, &["GET", "key"]),
+            "-NOAUTH Authentication required.\r\n"
+        );
+
+        let reply = run(
+            &server,
+            &mut conn,
+            &["HELLO", "3", "AUTH", "default", "secret"],
+        );
+        assert!(reply.starts_with("%7\r\n"), "{}", reply);
+        assert!(conn.authenticated);
+        assert_eq!(conn.protocol, 3);
+        assert_eq!(run(&server, &mut conn, &["GET", "key"]), "$-1\r\n");
+    }
+
+    #[test]
+    fn test_hello_with_wrong_password_changes_nothing() {
+        let server = server_with_password();
+        let mut conn = ConnectionState::new();
+        assert_eq!(
+            run(
+                &server,
+                &mut conn,
+                &["HELLO", "3", "AUTH", "default", "nope"]
+            ),
+            "-WRONGPASS invalid username-password pair or user is disabled.\r\n"
+        );
+        assert!(!conn.authenticated);
+        assert_eq!(conn.protocol, 2);
+        assert!(run(&server, &mut conn, &["HELLO", "3"]).starts_with("-NOAUTH"));
+    }
+
+    #[test]
+    fn test_hello_with_non_default_username() {
+        let server = server_with_password();
+        let mut conn = ConnectionState::new();
+        assert_eq!(
+            run(
+                &server,
+                &mut conn,
+                &["HELLO", "2", "AUTH", "alice", "secret"]
+            ),
+            "-WRONGPASS invalid username-password pair or user is disabled.\r\n"
+        );
+        assert!(!conn.authenticated);
+    }
+
+    #[test]
+    fn test_auth_and_config_set_requirepass() {
+        let server = ServerState::new();
+        let mut conn = ConnectionState::new();
+        // Connected while no password was required.
+        conn.authenticated = true;
+        assert!(run(&server, &mut conn, &["AUTH", "secret"])
+            .starts_with("-ERR AUTH <password> called without any password"));
+
+        assert_eq!(
+            run(
+                &server,
+                &mut conn,
+                &["CONFIG", "SET", "requirepass", "secret"]
+            ),
+            "+OK\r\n"
+        );
+        assert_eq!(
+            run(&server, &mut conn, &["CONFIG", "GET", "requirepass"]),
+            "*2\r\n$11\r\nrequirepass\r\n$6\r\nsecret\r\n"
+        );
+
+        let mut other = ConnectionState::new();
+        assert!(run(&server, &mut other, &["PING"]).starts_with("-NOAUTH"));
+        assert!(run(&server, &mut other, &["AUTH", "wrong"]).starts_with("-WRONGPASS"));
+        assert_eq!(run(&server, &mut other, &["AUTH", "secret"]), "+OK\r\n");
+        assert_eq!(run(&server, &mut other, &["PING"]), "+PONG\r\n");
+    }
+
+    #[test]
+    fn test_keys_refuses_results_over_the_scan_limit() {
+        let server = ServerState::new();
+        let mut conn = ConnectionState::new();
+        for i in 0..20 {
+            run(&server, &mut conn, &["SET", &format!("user:{}", i), "v"]);
+        }
+        run(&server, &mut conn, &["SET", "other", "v"]);
+
+        assert!(run(&server, &mut conn, &["KEYS", "user:*"]).starts_with("*20\r\n"));
+
+        run(
+            &server,
+            &mut conn,
+            &["CONFIG", "SET", "keys-scan-limit", "10"],
+        );
+        assert_eq!(
+            run(&server, &mut conn, &["KEYS", "user:*"]),
+            "-ERR KEYS result too large, use SCAN\r\n"
+        );
+        assert_eq!(
+            run(&server, &mut conn, &["KEYS", "oth*"]),
+            "*1\r\n$5\r\nother\r\n"
+        );
+    }
+
+    #[test]
+    fn test_info_commandstats_counts_calls() {
+        let server = ServerState::new();
+        let mut conn = ConnectionState::new();
+        run(&server, &mut conn, &["SET", "key", "value"]);
+        for _ in 0..5 {
+            run(&server, &mut conn, &["GET", "key"]);
+        }
+        // Rejected for arity before running, so not counted.
+        run(&server, &mut conn, &["GET"]);
+
+        let info = run(&server, &mut conn, &["INFO", "commandstats"]);
+        assert!(info.contains("# Commandstats\r\n"), "{}", info);
+        assert!(info.contains("cmdstat_get:calls=5,usec="), "{}", info);
+        assert!(info.contains("cmdstat_set:calls=1,usec="), "{}", info);
+    }
+
+    #[test]
+    fn test_info_keyspace_lists_each_non_empty_db() {
+        let server = ServerState::new();
+        let mut conn = ConnectionState::new();
+        run(&server, &mut conn, &["SET", "a", "1"]);
+        run(&server, &mut conn, &["SET", "b", "2", "EX", "100"]);
+        run(&server, &mut conn, &["SELECT", "1"]);
+        run(&server, &mut conn, &["SET", "c", "3"]);
+
+        assert_eq!(run(&server, &mut conn, &["DBSIZE"]), ":1\r\n");
+        let info = run(&server, &mut conn, &["INFO", "keyspace"]);
+        assert!(
+            info.contains("# Keyspace\r\ndb0:keys=2,expires=1,avg_ttl=0\r\ndb1:keys=1,expires=0,avg_ttl=0\r\n"),
+            "{}",
+            info
+        );
+        assert!(!info.contains("db2:"));
+    }
+
+    #[test]
+    fn test_reset_restores_just_connected_state() {
+        let server = server_with_password();
+        let output = Arc::new(Mutex::new(Vec::new()));
+        let mut conn = ConnectionState::new();
+        conn.id = 7;
+        conn.push = Some(PushSink(output.clone()));
+
+        run(
+            &server,
+            &mut conn,
+            &["HELLO", "3", "AUTH", "default", "secret", "SETNAME", "app"],
+        );
+        run(&server, &mut conn, &["CLIENT", "TRACKING", "on"]);
+        run(&server, &mut conn, &["GET", "key"]);
+        run(&server, &mut conn, &["SELECT", "3"]);
+        run(&server, &mut conn, &["WATCH", "key"]);
+        run(&server, &mut conn, &["SUBSCRIBE", "channel"]);
+        run(&server, &mut conn, &["PSUBSCRIBE", "pattern.*"]);
+        run(&server, &mut conn, &["MULTI"]);
+
+        assert_eq!(run(&server, &mut conn, &["RESET"]), "+RESET\r\n");
+
+        assert!(!conn.in_transaction());
+        assert!(conn.watched.is_empty());
+        assert_eq!(conn.subscription_count(), 0);
+        assert_eq!(conn.db, 0);
+        assert_eq!(conn.name, None);
+        assert!(!conn.authenticated);
+        assert!(!conn.tracking);
+        assert_eq!(conn.protocol, 2);
+        assert_eq!(conn.id, 7);
+        assert!(conn.push.is_some());
+
+        let mut writer = ConnectionState::new();
+        writer.authenticated = true;
+        run(&server, &mut writer, &["SET", "key", "value"]);
+        assert!(output.lock().unwrap().is_empty());
+        assert!(run(&server, &mut conn, &["PING"]).starts_with("-NOAUTH"));
+    }
+
+    #[test]
+    fn test_string_mutation_lifecycle_bytes_and_encoding() {
+        let server = ServerState::new();
+        let mut conn = ConnectionState::new();
+        let mut step = |args: &[&str], value: &str, encoding: &str| {
+            run(&server, &mut conn, args);
+            assert_eq!(
+                run(&server, &mut conn, &["GET", "s"]),
+                format!("${}\r\n{}\r\n", value.len(), value),
+                "after {:?}",
+                args
+            );
+            assert_eq!(
+                run(&server, &mut conn, &["OBJECT", "ENCODING", "s"]),
+                format!("${}\r\n{}\r\n", encoding.len(), encoding),
+                "after {:?}",
+                args
+            );
+        };
+
+        step(&["SET", "s", "99"], "99", "int");
+        step(&["INCR", "s"], "100", "int");
+        step(&["APPEND", "s", "x"], "100x", "raw");
+        step(&["SETRANGE", "s", "1", "23"], "123x", "raw");
+        step(&["SETRANGE", "s", "3", "4"], "1234", "raw");
+        step(&["INCRBY", "s", "-4"], "1230", "int");
+
+        assert_eq!(
+            run(&server, &mut conn, &["GETRANGE", "s", "1", "2"]),
+            "$2\r\n23\r\n"
+        );
+        assert_eq!(
+            run(&server, &mut conn, &["GETRANGE", "s", "-2", "-1"]),
+            "$2\r\n30\r\n"
+        );
+
+        // A string created by SETRANGE is raw from the start, one created by
+        // APPEND is encoded like a SET.
+        run(&server, &mut conn, &["SETRANGE", "fresh", "0", "12"]);
+        assert_eq!(
+            run(&server, &mut conn, &["OBJECT", "ENCODING", "fresh"]),
+            "$3\r\nraw\r\n"
+        );
+        run(&server, &mut conn, &["APPEND", "appended", "12"]);
+        assert_eq!(
+            run(&server, &mut conn, &["OBJECT", "ENCODING", "appended"]),
+            "$3\r\nint\r\n"
+        );
+    }
+
+    #[test]
+    fn test_append_makes_string_encoding_raw() {
+        let server = ServerState::new();
+        let mut conn = ConnectionState::new();
+        run(&server, &mut conn, &["SET", "n", "12"]);
+        assert_eq!(
+            run(&server, &mut conn, &["OBJECT", "ENCODING", "n"]),
+            "$3\r\nint\r\n"
+        );
+
+        assert_eq!(run(&server, &mut conn, &["APPEND", "n", "3"]), ":3\r\n");
+        assert_eq!(run(&server, &mut conn, &["GET", "n"]), "$3\r\n123\r\n");
+        assert_eq!(
+            run(&server, &mut conn, &["OBJECT", "ENCODING", "n"]),
+            "$3\r\nraw\r\n"
+        );
+
+        // INCR re-encodes as an integer, SET starts from scratch.
+        run(&server, &mut conn, &["INCR", "n"]);
+        assert_eq!(
+            run(&server, &mut conn, &["OBJECT", "ENCODING", "n"]),
+            "$3\r\nint\r\n"
+        );
+        run(&server, &mut conn, &["SETRANGE", "n", "0", "9"]);
+        assert_eq!(
+            run(&server, &mut conn, &["OBJECT", "ENCODING", "n"]),
+            "$3\r\nraw\r\n"
+        );
+        run(&server, &mut conn, &["SET", "n", "short"]);
+        assert_eq!(
+            run(&server, &mut conn, &["OBJECT", "ENCODING", "n"]),
+            "$6\r\nembstr\r\n"
+        );
+
+        assert_eq!(
+            run(&server, &mut conn, &["APPEND", "fresh", "abc"]),
+            ":3\r\n"
+        );
+        run(&server, &mut conn, &["RPUSH", "list", "a"]);
+        assert!(run(&server, &mut conn, &["APPEND", "list", "b"]).starts_with("-WRONGTYPE"));
+    }
+
+    #[test]
+    fn test_published_messages_use_the_subscribers_protocol() {
+        let server = ServerState::new();
+        let resp2_output = Arc::new(Mutex::new(Vec::new()));
+        let resp3_output = Arc::new(Mutex::new(Vec::new()));
+        let mut resp2 = ConnectionState::new();
+        resp2.id = 1;
+        resp2.push = Some(PushSink(resp2_output.clone()));
+        let mut resp3 = ConnectionState::new();
+        resp3.id = 2;
+        resp3.push = Some(PushSink(resp3_output.clone()));
+        let mut publisher = ConnectionState::new();
+
+        run(&server, &mut resp3, &["HELLO", "3"]);
+        assert_eq!(
+            run(&server, &mut resp2, &["SUBSCRIBE", "news"]),
+            "*3\r\n$9\r\nsubscribe\r\n$4\r\nnews\r\n:1\r\n"
+        );
+        assert_eq!(
+            run(&server, &mut resp3, &["SUBSCRIBE", "news"]),
+            ">3\r\n$9\r\nsubscribe\r\n$4\r\nnews\r\n:1\r\n"
+        );
+
+        assert_eq!(
+            run(&server, &mut publisher, &["PUBLISH", "news", "hi"]),
+            ":2\r\n"
+        );
+        assert_eq!(
+            resp2_output.lock().unwrap().as_slice(),
+            b"*3\r\n$7\r\nmessage\r\n$4\r\nnews\r\n$2\r\nhi\r\n"
+        );
+        assert_eq!(
+            resp3_output.lock().unwrap().as_slice(),
+            b">3\r\n$7\r\nmessage\r\n$4\r\nnews\r\n$2\r\nhi\r\n"
+        );
+
+        // Only the RESP3 subscriber can keep running ordinary commands.
+        assert!(run(&server, &mut resp2, &["GET", "key"])
+            .starts_with("-ERR Can't execute 'get': only (P|S)SUBSCRIBE"));
+        assert_eq!(run(&server, &mut resp3, &["GET", "key"]), "$-1\r\n");
+    }
+
+    #[test]
+    fn test_publish_to_patterns_and_after_unsubscribe() {
+        let server = ServerState::new();
+        let output = Arc::new(Mutex::new(Vec::new()));
+        let mut subscriber = ConnectionState::new();
+        subscriber.id = 1;
+        subscriber.push = Some(PushSink(output.clone()));
+        let mut publisher = ConnectionState::new();
+
+        run(&server, &mut subscriber, &["PSUBSCRIBE", "news.*"]);
+        assert_eq!(
+            run(&server, &mut publisher, &["PUBLISH", "news.tech", "x"]),
+            ":1\r\n"
+        );
+        assert_eq!(
+            output.lock().unwrap().as_slice(),
+            b"*4\r\n$8\r\npmessage\r\n$6\r\nnews.*\r\n$9\r\nnews.tech\r\n$1\r\nx\r\n"
+        );
+
+        run(&server, &mut subscriber, &["PUNSUBSCRIBE"]);
+        assert_eq!(
+            run(&server, &mut publisher, &["PUBLISH", "news.tech", "x"]),
+            ":0\r\n"
+        );
+    }
+
+    #[test]
+    fn test_getrange_returns_binary_ranges_byte_for_byte() {
+        let server = ServerState::new();
+        let mut conn = ConnectionState::new();
+        let raw = |conn: &mut ConnectionState, args: &[&[u8]]| {
+            let cmd = RespValue::Array(Some(
+                args.iter()
+                    .map(|arg| RespValue::BulkString(Some(arg.to_vec())))
+                    .collect(),
+            ));
+            super::handle_command(&cmd, &server, conn)
+        };
+
+        raw(&mut conn, &[b"SET", b"key", b"\xc3\xa9\x00\xff"]);
+        // Half of a two-byte character, then bytes that are not UTF-8 at all.
+        assert_eq!(
+            raw(&mut conn, &[b"GETRANGE", b"key", b"0", b"0"]),
+            b"$1\r\n\xc3\r\n"
+        );
+        assert_eq!(
+            raw(&mut conn, &[b"GETRANGE", b"key", b"1", b"-1"]),
+            b"$3\r\n\xa9\x00\xff\r\n"
+        );
+    }
+
+    #[test]
+    fn test_publish_delivers_binary_payloads_intact() {
+        use crate::parser::{ParseResult, RespParser};
+
+        let server = ServerState::new();
+        let output = Arc::new(Mutex::new(Vec::new()));
+        let mut subscriber = ConnectionState::new();
+        subscriber.id = 1;
+        subscriber.push = Some(PushSink(output.clone()));
+        let mut publisher = ConnectionState::new();
+        run(&server, &mut subscriber, &["SUBSCRIBE", "bin"]);
+
+        let payload = b"\x00\xff\r\n".to_vec();
+        let publish = RespValue::Array(Some(vec![
+            RespValue::BulkString(Some(b"PUBLISH".to_vec())),
+            RespValue::BulkString(Some(b"bin".to_vec())),
+            RespValue::BulkString(Some(payload.clone())),
+        ]));
+        assert_eq!(handle_command(&publish, &server, &mut publisher), ":1\r\n");
+
+        let frame = output.lock().unwrap().clone();
+        assert_eq!(
+            frame,
+            b"*3\r\n$7\r\nmessage\r\n$3\r\nbin\r\n$4\r\n\x00\xff\r\n\r\n"
+        );
+
+        // A client reading by the length header gets the payload back whole.
+        let mut parser = RespParser::new();
+        parser.feed(&frame);
+        match parser.parse() {
+            ParseResult::Complete(RespValue::Array(Some(parts)), consumed) => {
+                assert_eq!(consumed, frame.len());
+                assert_eq!(parts[2], RespValue::BulkString(Some(payload)));
+            }
+            other => panic!("unexpected parse result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_move_key_between_databases() {
+        let server = ServerState::new();
+        let mut conn = ConnectionState::new();
+        run(&server, &mut conn, &["SET", "key", "value"]);
+        run(&server, &mut conn, &["EXPIRE", "key", "100"]);
+
+        assert_eq!(run(&server, &mut conn, &["MOVE", "key", "1"]), ":1\r\n");
+        assert_eq!(run(&server, &mut conn, &["EXISTS", "key"]), ":0\r\n");
+        run(&server, &mut conn, &["SELECT", "1"]);
+        assert_eq!(run(&server, &mut conn, &["GET", "key"]), "$5\r\nvalue\r\n");
+    }
+
+    #[test]
+    fn test_move_blocked_by_existing_destination_key() {
+        let server = ServerState::new();
+        let mut conn = ConnectionState::new();
+        server.db(1).set("key".to_string(), b"theirs".to_vec());
+        run(&server, &mut conn, &["SET", "key", "ours"]);
+
+        assert_eq!(run(&server, &mut conn, &["MOVE", "key", "1"]), ":0\r\n");
+        assert_eq!(run(&server, &mut conn, &["GET", "key"]), "$4\r\nours\r\n");
+        assert_eq!(server.db(1).get("key"), Some(b"theirs".to_vec()));
+    }
+
+    #[test]
+    fn test_move_missing_key_and_bad_db() {
+        let server = ServerState::new();
+        let mut conn = ConnectionState::new();
+        assert_eq!(run(&server, &mut conn, &["MOVE", "missing", "1"]), ":0\r\n");
+        assert_eq!(
+            run(&server, &mut conn, &["MOVE", "missing", "0"]),
+            "-ERR source and destination objects are the same\r\n"
+        );
+        assert_eq!(
+            run(&server, &mut conn, &["MOVE", "missing", "16"]),
+            "-ERR DB index is out of range\r\n"
+        );
+    }
+
+    // Everything a client can observe about `key`, to show a rejected write
+    // left it alone.
+    fn snapshot(server: &ServerState, conn: &mut ConnectionState, key: &str) -> String {
+        let contents = match run(server, conn, &["TYPE", key]).as_str() {
+            "+string\r\n" => run(server, conn, &["GET", key]),
+            "+list\r\n" => run(server, conn, &["LRANGE", key, "0", "-1"]),
+            _ => run(server, conn, &["XRANGE", key, "-", "+"]),
+        };
+        format!("{}{}", run(server, conn, &["TYPE", key]), contents)
+    }
+
+    #[test]
+    fn test_write_commands_reject_every_other_type_without_mutating() {
+        let server = ServerState::new();
+        let mut conn = ConnectionState::new();
+        run(&server, &mut conn, &["SET", "string", "12"]);
+        run(&server, &mut conn, &["RPUSH", "list", "a", "b"]);
+        run(&server, &mut conn, &["XADD", "stream", "1-1", "f", "v"]);
+
+        let writes: &[(&str, &[&str])] = &[
+            ("string", &["APPEND", "{}", "x"]),
+            ("string", &["SETRANGE", "{}", "0", "x"]),
+            ("string", &["SETBIT", "{}", "0", "1"]),
+            ("string", &["INCR", "{}"]),
+            ("string", &["INCRBY", "{}", "2"]),
+            ("string", &["DECR", "{}"]),
+            ("string", &["DECRBY", "{}", "2"]),
+            ("list", &["RPUSH", "{}", "x"]),
+            ("list", &["LPUSH", "{}", "x"]),
+            ("list", &["LPOP", "{}"]),
+            ("list", &["LPOP", "{}", "2"]),
+            ("list", &["LMPOP", "1", "{}", "LEFT"]),
+            ("stream", &["XADD", "{}", "*", "f", "v"]),
+        ];
+        for (expected_type, template) in writes {
+            for key in ["string", "list", "stream"] {
+                if key == *expected_type {
+                    continue;
+                }
+                let args: Vec<&str> = template
+                    .iter()
+                    .map(|arg| if *arg == "{}" { key } else { arg })
+                    .collect();
+                let before = snapshot(&server, &mut conn, key);
+                assert!(
+                    run(&server, &mut conn, &args).starts_with("-WRONGTYPE"),
+                    "{:?} against a {}",
+                    args,
+                    key
+                );
+                assert_eq!(snapshot(&server, &mut conn, key), before, "{:?}", args);
+            }
+        }
+    }
+
+    #[test]
+    fn test_xadd_with_invalid_id_does_not_create_the_key() {
+        let server = ServerState::new();
+        let mut conn = ConnectionState::new();
+
+        assert!(run(&server, &mut conn, &["XADD", "stream", "0-0", "f", "v"]).starts_with("-ERR"));
+        assert_eq!(run(&server, &mut conn, &["EXISTS", "stream"]), ":0\r\n");
+    }
+
+    #[test]
+    fn test_bare_hello_replies_in_resp2_without_upgrading() {
+        let server = ServerState::new();
+        let mut conn = ConnectionState::new();
+        conn.id = 7;
+
+        let reply = run(&server, &mut conn, &["HELLO"]);
+        let expected = format!(
+            "*14\r\n$6\r\nserver\r\n$5\r\nredis\r\n$7\r\nversion\r\n${}\r\n{}\r\n\
+             $5\r\nproto\r\n:2\r\n$2\r\nid\r\n:7\r\n$4\r\nmode\r\n$10\r\nstandalone\r\n\
+             $4\r\nrole\r\n$6\r\nmaster\r\n$7\r\nmodules\r\n*0\r\n",
+            env!("CARGO_PKG_VERSION").len(),
+            env!("CARGO_PKG_VERSION")
+        );
+        assert_eq!(reply, expected);
+        assert_eq!(conn.protocol, 2);
+    }
+
+    #[test]
+    fn test_empty_string_value_is_distinct_from_missing_key() {
+        let server = ServerState::new();
+        let mut conn = ConnectionState::new();
+
+        assert_eq!(run(&server, &mut conn, &["SET", "k", ""]), "+OK\r\n");
+        assert_eq!(run(&server, &mut conn, &["GET", "k"]), "$0\r\n\r\n");
+        assert_eq!(run(&server, &mut conn, &["STRLEN", "k"]), ":0\r\n");
+        assert_eq!(run(&server, &mut conn, &["EXISTS", "k"]), ":1\r\n");
+        assert_eq!(
+            run(&server, &mut conn, &["GETRANGE", "k", "0", "-1"]),
+            "$0\r\n\r\n"
+        );
+        assert_eq!(run(&server, &mut conn, &["ECHO", ""]), "$0\r\n\r\n");
+
+        assert_eq!(run(&server, &mut conn, &["GET", "missing"]), "$-1\r\n");
+        assert_eq!(run(&server, &mut conn, &["STRLEN", "missing"]), ":0\r\n");
+
+        // Like Redis, APPEND of nothing still creates the key.
+        assert_eq!(run(&server, &mut conn, &["APPEND", "new", ""]), ":0\r\n");
+        assert_eq!(run(&server, &mut conn, &["GET", "new"]), "$0\r\n\r\n");
+        assert_eq!(run(&server, &mut conn, &["APPEND", "k", ""]), ":0\r\n");
+        assert_eq!(run(&server, &mut conn, &["GET", "k"]), "$0\r\n\r\n");
+    }
+
+    #[test]
+    fn test_strlen_rejects_other_types() {
+        let server = ServerState::new();
+        let mut conn = ConnectionState::new();
+        run(&server, &mut conn, &["SET", "k", "hello"]);
+        run(&server, &mut conn, &["RPUSH", "list", "a"]);
+
+        assert_eq!(run(&server, &mut conn, &["STRLEN", "k"]), ":5\r\n");
+        assert!(run(&server, &mut conn, &["STRLEN", "list"]).starts_with("-WRONGTYPE"));
+    }
+
+    #[test]
+    fn test_commands_past_the_rate_limit_are_refused() {
+        let server = ServerState::new();
+        let mut conn = ConnectionState::new();
+        run(
+            &server,
+            &mut conn,
+            &["CONFIG", "SET", "max-commands-per-sec", "3"],
+        );
+
+        assert_eq!(run(&server, &mut conn, &["SET", "k", "1"]), "+OK\r\n");
+        assert_eq!(run(&server, &mut conn, &["INCR", "k"]), ":2\r\n");
+        assert_eq!(run(&server, &mut conn, &["INCR", "k"]), ":3\r\n");
+        assert_eq!(
+            run(&server, &mut conn, &["INCR", "k"]),
+            "-ERR command rate limit exceeded, retry later\r\n"
+        );
+
+        // Other connections have buckets of their own.
+        let mut other = ConnectionState::new();
+        assert_eq!(run(&server, &mut other, &["GET", "k"]), "$1\r\n3\r\n");
+    }
+
+    #[test]
+    fn test_exec_does_not_charge_queued_commands_twice() {
+        let server = ServerState::new();
+        let mut conn = ConnectionState::new();
+        // MULTI, three queued commands and EXEC: one token each.
+        run(
+            &server,
+            &mut conn,
+            &["CONFIG", "SET", "max-commands-per-sec", "5"],
+        );
+
+        assert_eq!(run(&server, &mut conn, &["MULTI"]), "+OK\r\n");
+        for _ in 0..3 {
+            assert_eq!(run(&server, &mut conn, &["INCR", "k"]), "+QUEUED\r\n");
+        }
+        assert_eq!(
+            run(&server, &mut conn, &["EXEC"]),
+            "*3\r\n:1\r\n:2\r\n:3\r\n"
+        );
+        assert!(run(&server, &mut conn, &["GET", "k"]).contains("rate limit exceeded"));
+    }
+
+    #[test]
+    fn test_debug_sleep_is_gated_and_rejects_out_of_range_values() {
+        let mut server = ServerState::new();
+        let mut conn = ConnectionState::new();
+        assert!(run(&server, &mut conn, &["DEBUG", "SLEEP", "0"])
+            .starts_with("-ERR DEBUG SLEEP not allowed"));
+
+        server.config = Config::with_enable_debug_command(true);
+        assert_eq!(run(&server, &mut conn, &["DEBUG", "SLEEP", "0"]), "+OK\r\n");
+        for seconds in ["1e300", "-1", "inf", "nan", "abc"] {
+            assert_eq!(
+                run(&server, &mut conn, &["DEBUG", "SLEEP", seconds]),
+                "-ERR value is not a valid float\r\n",
+                "{}",
+                seconds
+            );
+        }
+    }
+
+    #[test]
+    fn test_debug_flushall_needs_enable_debug_command() {
+        let mut server = ServerState::new();
+        let mut conn = ConnectionState::new();
+        run(&server, &mut conn, &["SET", "a", "1"]);
+        run(&server, &mut conn, &["SELECT", "3"]);
+        run(&server, &mut conn, &["RPUSH", "b", "x"]);
+
+        assert!(run(&server, &mut conn, &["DEBUG", "FLUSHALL"]).starts_with("-ERR"));
+        // Clients can't unlock it for themselves.
+        assert!(run(
+            &server,
+            &mut conn,
+            &["CONFIG", "SET", "enable-debug-command", "yes"],
+        )
+        .contains("can't set immutable config"));
+        assert!(run(&server, &mut conn, &["DEBUG", "FLUSHALL"]).starts_with("-ERR"));
+        assert_eq!(run(&server, &mut conn, &["EXISTS", "b"]), ":1\r\n");
+
+        server.config = Config::with_enable_debug_command(true);
+        assert_eq!(run(&server, &mut conn, &["DEBUG", "FLUSHALL"]), "+OK\r\n");
+        assert_eq!(run(&server, &mut conn, &["DBSIZE"]), ":0\r\n");
+        run(&server, &mut conn, &["SELECT", "0"]);
+        assert_eq!(run(&server, &mut conn, &["DBSIZE"]), ":0\r\n");
+    }
+
+    #[test]
+    fn test_string_writes_against_a_list_leave_it_alone() {
+        let server = ServerState::new();
+        let mut conn = ConnectionState::new();
+        run(&server, &mut conn, &["RPUSH", "list", "a", "b"]);
+
+        for args in [
+            &["SETRANGE", "list", "0", "x"][..],
+            &["SETBIT", "list", "0", "1"],
+            &["APPEND", "list", "x"],
+        ] {
+            assert!(
+                run(&server, &mut conn, args).starts_with("-WRONGTYPE"),
+                "{:?}",
+                args
+            );
+            assert_eq!(
+                run(&server, &mut conn, &["LRANGE", "list", "0", "-1"]),
+                "*2\r\n$1\r\na\r\n$1\r\nb\r\n"
+            );
+        }
+    }
+
+    #[test]
+    fn test_string_writes_create_missing_keys() {
+        let server = ServerState::new();
+        let mut conn = ConnectionState::new();
+
+        assert_eq!(
+            run(&server, &mut conn, &["SETRANGE", "range", "2", "ab"]),
+            ":4\r\n"
+        );
+        assert_eq!(
+            run(&server, &mut conn, &["GET", "range"]),
+            "$4\r\n\0\0ab\r\n"
+        );
+
+        // Bit 1 and bit 7 of the first byte, then a bit in the third byte.
+        assert_eq!(
+            run(&server, &mut conn, &["SETBIT", "bits", "1", "1"]),
+            ":0\r\n"
+        );
+        assert_eq!(
+            run(&server, &mut conn, &["SETBIT", "bits", "7", "1"]),
+            ":0\r\n"
+        );
+        assert_eq!(
+            run(&server, &mut conn, &["SETBIT", "bits", "7", "0"]),
+            ":1\r\n"
+        );
+        assert_eq!(
+            run(&server, &mut conn, &["SETBIT", "bits", "23", "1"]),
+            ":0\r\n"
+        );
+        assert_eq!(
+            run(&server, &mut conn, &["GET", "bits"]),
+            "$3\r\n@\0\x01\r\n"
+        );
+        assert_eq!(
+            run(&server, &mut conn, &["OBJECT", "ENCODING", "bits"]),
+            "$3\r\nraw\r\n"
+        );
+
+        assert_eq!(
+            run(&server, &mut conn, &["APPEND", "appended", "hi"]),
+            ":2\r\n"
+        );
+        assert_eq!(
+            run(&server, &mut conn, &["GET", "appended"]),
+            "$2\r\nhi\r\n"
+        );
+    }
+
+    #[test]
+    fn test_setbit_rejects_bad_offsets_and_bits() {
+        let server = ServerState::new();
+        let mut conn = ConnectionState::new();
+
+        assert_eq!(
+            run(&server, &mut conn, &["SETBIT", "k", "-1", "1"]),
+            "-ERR bit offset is not an integer or out of range\r\n"
+        );
+        assert_eq!(
+            run(&server, &mut conn, &["SETBIT", "k", "4294967296", "1"]),
+            "-ERR bit offset is not an integer or out of range\r\n"
+        );
+        assert_eq!(
+            run(&server, &mut conn, &["SETBIT", "k", "0", "2"]),
+            "-ERR bit is not an integer or out of range\r\n"
+        );
+        assert_eq!(run(&server, &mut conn, &["EXISTS", "k"]), ":0\r\n");
+    }
+
+    #[test]
+    fn test_set_condition_get_and_ttl_combinations() {
+        let old = "$3\r\nold\r\n";
+        let new = "$3\r\nnew\r\n";
+        let null = "$-1\r\n";
+        // (key exists with a 100s TTL, options, reply, value after, TTL after
+        // in seconds with -2 for a missing key and -1 for no TTL)
+        let cases: &[(bool, &[&str], &str, &str, i64)] = &[
+            (true, &["NX", "EX", "10"], null, old, 100),
+            (false, &["NX", "EX", "10"], "+OK\r\n", new, 10),
+            (true, &["XX", "KEEPTTL"], "+OK\r\n", new, 100),
+            (false, &["XX", "KEEPTTL"], null, null, -2),
+            (true, &["NX", "GET"], old, old, 100),
+            (false, &["NX", "GET"], null, new, -1),
+            (true, &["XX", "PX", "5000", "GET"], old, new, 5),
+            (false, &["XX", "PX", "5000", "GET"], null, null, -2),
+            (true, &["GET", "XX"], old, new, -1),
+            (true, &["KEEPTTL", "GET"], old, new, 100),
+            (false, &["KEEPTTL"], "+OK\r\n", new, -1),
+        ];
+        for (exists, options, reply, value, ttl) in cases {
+            let server = ServerState::new();
+            let mut conn = ConnectionState::new();
+            if *exists {
+                run(&server, &mut conn, &["SET", "k", "old", "EX", "100"]);
+            }
+
+            let mut args = vec!["SET", "k", "new"];
+            args.extend_from_slice(options);
+            assert_eq!(run(&server, &mut conn, &args), *reply, "{:?}", args);
+            assert_eq!(run(&server, &mut conn, &["GET", "k"]), *value, "{:?}", args);
+            assert_eq!(
+                run(&server, &mut conn, &["TTL", "k"]),
+                format!(":{}\r\n", ttl),
+                "{:?}",
+                args
+            );
+        }
+    }
+
+    #[test]
+    fn test_set_rejects_conflicting_options() {
+        let server = ServerState::new();
+        let mut conn = ConnectionState::new();
+        run(&server, &mut conn, &["SET", "k", "old"]);
+
+        for options in [
+            &["NX", "XX"][..],
+            &["XX", "NX"],
+            &["EX", "10", "PX", "100"],
+            &["EX", "10", "EX", "10"],
+            &["KEEPTTL", "EX", "10"],
+            &["PX", "100", "KEEPTTL"],
+            &["NX", "PX"],
+        ] {
+            let mut args = vec!["SET", "k", "new"];
+            args.extend_from_slice(options);
+            assert_eq!(
+                run(&server, &mut conn, &args),
+                "-ERR syntax error\r\n",
+                "{:?}",
+                args
+            );
+        }
+        // An invalid TTL is reported even when NX would stop the write.
+        assert_eq!(
+            run(&server, &mut conn, &["SET", "k", "new", "NX", "EX", "0"]),
+            "-ERR invalid expire time in 'set' command\r\n"
+        );
+        assert_eq!(run(&server, &mut conn, &["GET", "k"]), "$3\r\nold\r\n");
+    }
+
+    #[test]
+    fn test_set_against_other_types() {
+        let server = ServerState::new();
+        let mut conn = ConnectionState::new();
+        run(&server, &mut conn, &["RPUSH", "list", "a"]);
+
+        assert!(run(&server, &mut conn, &["SET", "list", "v", "GET"]).starts_with("-WRONGTYPE"));
+        assert!(
+            run(&server, &mut conn, &["SET", "list", "v", "XX", "GET"]).starts_with("-WRONGTYPE")
+        );
+        assert_eq!(
+            run(&server, &mut conn, &["SET", "list", "v", "NX"]),
+            "$-1\r\n"
+        );
+        assert_eq!(run(&server, &mut conn, &["TYPE", "list"]), "+list\r\n");
+
+        assert_eq!(
+            run(&server, &mut conn, &["SET", "list", "v", "XX"]),
+            "+OK\r\n"
+        );
+        assert_eq!(run(&server, &mut conn, &["GET", "list"]), "$1\r\nv\r\n");
+    }
+
+    #[test]
+    fn test_failed_nx_keeps_the_key_despite_default_ttl() {
+        let server = ServerState::new();
+        let mut conn = ConnectionState::new();
+        run(&server, &mut conn, &["SET", "k", "old"]);
+        run(
+            &server,
+            &mut conn,
+            &["CONFIG", "SET", "default-ttl-seconds", "50"],
+        );
+
+        assert_eq!(
+            run(&server, &mut conn, &["SET", "k", "new", "NX"]),
+            "$-1\r\n"
+        );
+        assert_eq!(run(&server, &mut conn, &["TTL", "k"]), ":-1\r\n");
+        assert_eq!(
+            run(&server, &mut conn, &["SET", "k", "new", "XX"]),
+            "+OK\r\n"
+        );
+        assert_eq!(run(&server, &mut conn, &["TTL", "k"]), ":50\r\n");
+    }
+
+    #[test]
+    fn test_channels_are_shared_by_every_database() {
+        let server = ServerState::new();
+        let output = Arc::new(Mutex::new(Vec::new()));
+        let mut subscriber = ConnectionState::new();
+        subscriber.id = 1;
+        subscriber.push = Some(PushSink(output.clone()));
+        let mut publisher = ConnectionState::new();
+
+        run(&server, &mut subscriber, &["SUBSCRIBE", "news"]);
+        run(&server, &mut publisher, &["SELECT", "3"]);
+        assert_eq!(
+            run(&server, &mut publisher, &["PUBLISH", "news", "hi"]),
+            ":1\r\n"
+        );
+        assert_eq!(
+            output.lock().unwrap().as_slice(),
+            b"*3\r\n$7\r\nmessage\r\n$4\r\nnews\r\n$2\r\nhi\r\n"
+        );
+    }
+
+    #[test]
+    fn test_shard_channel_commands_alias_the_plain_ones() {
+        let server = ServerState::new();
+        let output = Arc::new(Mutex::new(Vec::new()));
+        let mut subscriber = ConnectionState::new();
+        subscriber.id = 1;
+        subscriber.push = Some(PushSink(output.clone()));
+        let mut publisher = ConnectionState::new();
+
+        assert_eq!(
+            run(&server, &mut subscriber, &["SSUBSCRIBE", "orders"]),
+            "*3\r\n$9\r\nsubscribe\r\n$6\r\norders\r\n:1\r\n"
+        );
+        assert_eq!(
+            run(&server, &mut publisher, &["SPUBLISH", "orders", "1"]),
+            ":1\r\n"
+        );
+        assert_eq!(
+            output.lock().unwrap().as_slice(),
+            b"*3\r\n$7\r\nmessage\r\n$6\r\norders\r\n$1\r\n1\r\n"
+        );
+
+        assert_eq!(
+            run(&server, &mut subscriber, &["SUNSUBSCRIBE", "orders"]),
+            "*3\r\n$11\r\nunsubscribe\r\n$6\r\norders\r\n:0\r\n"
+        );
+        assert_eq!(
+            run(&server, &mut publisher, &["SPUBLISH", "orders", "2"]),
+            ":0\r\n"
+        );
     }
 }
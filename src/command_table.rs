@@ -0,0 +1,149 @@
+/// Static description of a command, in the shape COMMAND INFO reports it.
+///
+/// `arity` follows the Redis convention: a positive value is the exact number
+/// of arguments including the command name, a negative value `-N` means "at
+/// least N".
+#[derive(Debug, PartialEq)]
+pub struct CommandSpec {
+    pub name: &'static str,
+    pub arity: i64,
+    pub flags: &'static [&'static str],
+    pub first_key: i64,
+    pub last_key: i64,
+    pub step: i64,
+}
+
+impl CommandSpec {
+    pub fn check_arity(&self, argc: usize) -> bool {
+        let argc = argc as i64;
+        if self.arity >= 0 {
+            argc == self.arity
+        } else {
+            argc >= -self.arity
+        }
+    }
+}
+
+const fn spec(
+    name: &'static str,
+    arity: i64,
+    flags: &'static [&'static str],
+    first_key: i64,
+    last_key: i64,
+    step: i64,
+) -> CommandSpec {
+    CommandSpec {
+        name,
+        arity,
+        flags,
+        first_key,
+        last_key,
+        step,
+    }
+}
+
+#[rustfmt::skip]
+pub const COMMANDS: &[CommandSpec] = &[
+    spec("ping",    -1, &["fast"], 0, 0, 0),
+    spec("echo",     2, &["fast"], 0, 0, 0),
+    spec("set",     -3, &["write", "denyoom"], 1, 1, 1),
+    spec("get",      2, &["readonly", "fast"], 1, 1, 1),
+    spec("getrange", 4, &["readonly"], 1, 1, 1),
+    spec("substr",   4, &["readonly"], 1, 1, 1),
+    spec("setrange", 4, &["write", "denyoom"], 1, 1, 1),
+    spec("setbit",   4, &["write", "denyoom"], 1, 1, 1),
+    spec("strlen",   2, &["readonly", "fast"], 1, 1, 1),
+    spec("append",   3, &["write", "denyoom", "fast"], 1, 1, 1),
+    spec("incr",     2, &["write", "denyoom", "fast"], 1, 1, 1),
+    spec("decr",     2, &["write", "denyoom", "fast"], 1, 1, 1),
+    spec("incrby",   3, &["write", "denyoom", "fast"], 1, 1, 1),
+    spec("decrby",   3, &["write", "denyoom", "fast"], 1, 1, 1),
+    spec("expire",   3, &["write", "fast"], 1, 1, 1),
+    spec("pexpire",  3, &["write", "fast"], 1, 1, 1),
+    spec("ttl",      2, &["readonly", "fast"], 1, 1, 1),
+    spec("pttl",     2, &["readonly", "fast"], 1, 1, 1),
+    spec("expiretime",  2, &["readonly", "fast"], 1, 1, 1),
+    spec("pexpiretime", 2, &["readonly", "fast"], 1, 1, 1),
+    spec("rpush",   -3, &["write", "denyoom", "fast"], 1, 1, 1),
+    spec("lpush",   -3, &["write", "denyoom", "fast"], 1, 1, 1),
+    spec("lrange",   4, &["readonly"], 1, 1, 1),
+    spec("llen",     2, &["readonly", "fast"], 1, 1, 1),
+    spec("lpop",    -2, &["write", "fast"], 1, 1, 1),
+    spec("lmpop",   -4, &["write", "movablekeys"], 0, 0, 0),
+    spec("blmpop",  -5, &["write", "blocking", "movablekeys"], 0, 0, 0),
+    spec("blpop",   -3, &["write", "noscript", "blocking"], 1, -2, 1),
+    spec("type",     2, &["readonly", "fast"], 1, 1, 1),
+    spec("exists",  -2, &["readonly", "fast"], 1, -1, 1),
+    spec("copy",    -3, &["write", "denyoom"], 1, 2, 1),
+    spec("move",     3, &["write", "fast"], 1, 1, 1),
+    spec("del",     -2, &["write"], 1, -1, 1),
+    spec("dbsize",   1, &["readonly", "fast"], 0, 0, 0),
+    spec("flushdb", -1, &["write"], 0, 0, 0),
+    spec("flushall",-1, &["write"], 0, 0, 0),
+    spec("scan",    -2, &["readonly"], 0, 0, 0),
+    spec("keys",     2, &["readonly"], 0, 0, 0),
+    spec("xadd",    -5, &["write", "denyoom", "fast"], 1, 1, 1),
+    spec("xrange",  -4, &["readonly"], 1, 1, 1),
+    spec("xread",   -4, &["readonly", "blocking", "movablekeys"], 0, 0, 0),
+    spec("info",    -1, &["loading", "stale"], 0, 0, 0),
+    spec("debug",   -2, &["admin", "noscript", "loading", "stale"], 0, 0, 0),
+    spec("object",  -2, &["readonly"], 2, 2, 1),
+    spec("memory",  -2, &["readonly"], 0, 0, 0),
+    spec("select",   2, &["loading", "stale", "fast"], 0, 0, 0),
+    spec("multi",    1, &["noscript", "loading", "stale", "fast"], 0, 0, 0),
+    spec("exec",     1, &["noscript", "loading", "stale", "skip_slowlog"], 0, 0, 0),
+    spec("discard",  1, &["noscript", "loading", "stale", "fast"], 0, 0, 0),
+    spec("watch",   -2, &["noscript", "loading", "stale", "fast"], 1, -1, 1),
+    spec("unwatch",  1, &["noscript", "loading", "stale", "fast"], 0, 0, 0),
+    spec("command", -1, &["loading", "stale"], 0, 0, 0),
+    spec("subscribe",    -2, &["pubsub", "noscript", "loading", "stale"], 0, 0, 0),
+    spec("psubscribe",   -2, &["pubsub", "noscript", "loading", "stale"], 0, 0, 0),
+    spec("unsubscribe",  -1, &["pubsub", "noscript", "loading", "stale"], 0, 0, 0),
+    spec("punsubscribe", -1, &["pubsub", "noscript", "loading", "stale"], 0, 0, 0),
+    spec("ssubscribe",   -2, &["pubsub", "noscript", "loading", "stale"], 0, 0, 0),
+    spec("sunsubscribe", -1, &["pubsub", "noscript", "loading", "stale"], 0, 0, 0),
+    spec("reset",    1, &["noscript", "loading", "stale", "fast", "no-auth"], 0, 0, 0),
+    spec("publish",  3, &["pubsub", "loading", "stale", "fast"], 0, 0, 0),
+    spec("spublish", 3, &["pubsub", "loading", "stale", "fast"], 0, 0, 0),
+    spec("hello",   -1, &["noscript", "loading", "stale", "fast", "no-auth"], 0, 0, 0),
+    spec("auth",    -2, &["noscript", "loading", "stale", "fast", "no-auth"], 0, 0, 0),
+    spec("config",  -2, &["admin", "noscript", "loading", "stale"], 0, 0, 0),
+    spec("client",  -2, &["admin", "noscript", "loading", "stale"], 0, 0, 0),
+    spec("cluster", -2, &[], 0, 0, 0),
+];
+
+pub fn lookup(name: &str) -> Option<&'static CommandSpec> {
+    lookup_indexed(name).map(|(_, spec)| spec)
+}
+
+/// Like `lookup`, also returning the command's position in `COMMANDS`.
+pub fn lookup_indexed(name: &str) -> Option<(usize, &'static CommandSpec)> {
+    COMMANDS
+        .iter()
+        .enumerate()
+        .find(|(_, spec)| spec.name.eq_ignore_ascii_case(name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lookup_is_case_insensitive() {
+        assert_eq!(lookup("SET").map(|s| s.name), Some("set"));
+        assert_eq!(lookup("Set").map(|s| s.name), Some("set"));
+        assert_eq!(lookup("nope"), None);
+    }
+
+    #[test]
+    fn test_check_arity_exact_and_minimum() {
+        let get = lookup("get").unwrap();
+        assert!(get.check_arity(2));
+        assert!(!get.check_arity(3));
+
+        let set = lookup("set").unwrap();
+        assert!(!set.check_arity(2));
+        assert!(set.check_arity(3));
+        assert!(set.check_arity(5));
+    }
+}
@@ -0,0 +1,246 @@
+use std::sync::{Arc, RwLock};
+
+use crate::glob::glob_match;
+
+/// Parameters CONFIG GET and CONFIG SET can read and change at runtime.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Settings {
+    /// Password of the default user. When set, connections must authenticate.
+    pub requirepass: Option<String>,
+    /// Most keys KEYS may return before it refuses and points at SCAN. 0
+    /// means unlimited.
+    pub keys_scan_limit: usize,
+    /// Percentage by which SET EX/PX randomly shortens or stretches a TTL. 0
+    /// keeps TTLs exact.
+    pub expire_jitter: u64,
+    /// TTL given to SETs without EX, PX or KEEPTTL. 0 disables it.
+    pub default_ttl_seconds: u64,
+    /// Commands each connection may run per second. 0 means unlimited.
+    pub max_commands_per_sec: u64,
+    /// Seconds a connection may sit idle before TCP keepalive probes start.
+    /// 0 leaves keepalive off. Applies to connections accepted afterwards.
+    pub tcp_keepalive: u64,
+    /// Whether DEBUG FLUSHALL and DEBUG SLEEP may run. Off by default, and
+    /// only set at startup through `Config::with_enable_debug_command`.
+    pub enable_debug_command: bool,
+}
+
+impl Settings {
+    fn set(&mut self, name: &str, value: &str) -> Result<(), String> {
+        match name.to_ascii_lowercase().as_str() {
+            "requirepass" => {
+                self.requirepass = (!value.is_empty()).then(|| value.to_string());
+            }
+            "keys-scan-limit" => self.keys_scan_limit = parse_number(name, value)?,
+            "default-ttl-seconds" => {
+                self.default_ttl_seconds = parse_number(name, value)? as u64
+            }
+            "max-commands-per-sec" => {
+                self.max_commands_per_sec = parse_number(name, value)? as u64
+            }
+            "tcp-keepalive" => self.tcp_keepalive = parse_number(name, value)? as u64,
+            // Immutable, like in Redis: a client must not be able to unlock
+            // DEBUG FLUSHALL for itself.
+            "enable-debug-command" => {
+                return Err(format!(
+                    "ERR CONFIG SET failed (possibly related to argument '{}') - can't set immutable config",
+                    name
+                ))
+            }
+            "expire-jitter" => match parse_number(name, value)? {
+                percent @ 0..=100 => self.expire_jitter = percent as u64,
+                _ => {
+                    return Err(format!(
+                        "ERR CONFIG SET failed (possibly related to argument '{}') - argument must be between 0 and 100 inclusive",
+                        name
+                    ))
+                }
+            },
+            _ => {
+                return Err(format!(
+                    "ERR Unknown option or number of arguments for CONFIG SET - '{}'",
+                    name
+                ))
+            }
+        }
+        Ok(())
+    }
+
+    fn values(&self) -> Vec<(&'static str, String)> {
+        vec![
+            ("requirepass", self.requirepass.clone().unwrap_or_default()),
+            ("keys-scan-limit", self.keys_scan_limit.to_string()),
+            ("expire-jitter", self.expire_jitter.to_string()),
+            ("default-ttl-seconds", self.default_ttl_seconds.to_string()),
+            (
+                "max-commands-per-sec",
+                self.max_commands_per_sec.to_string(),
+            ),
+            ("tcp-keepalive", self.tcp_keepalive.to_string()),
+            (
+                "enable-debug-command",
+                if self.enable_debug_command {
+                    "yes"
+                } else {
+                    "no"
+                }
+                .to_string(),
+            ),
+        ]
+    }
+}
+
+fn parse_number(name: &str, value: &str) -> Result<usize, String> {
+    value.parse().map_err(|_| {
+        format!(
+            "ERR CONFIG SET failed (possibly related to argument '{}') - argument couldn't be parsed into an integer",
+            name
+        )
+    })
+}
+
+/// Server configuration shared by every connection.
+#[derive(Clone, Default)]
+pub struct Config {
+    settings: Arc<RwLock<Settings>>,
+}
+
+impl Config {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Startup configuration with enable-debug-command set, which CONFIG SET
+    /// refuses to change.
+    pub fn with_enable_debug_command(enabled: bool) -> Self {
+        let config = Self::new();
+        config.settings.write().unwrap().enable_debug_command = enabled;
+        config
+    }
+
+    pub fn get(&self) -> Settings {
+        self.settings.read().unwrap().clone()
+    }
+
+    pub fn requirepass(&self) -> Option<String> {
+        self.settings.read().unwrap().requirepass.clone()
+    }
+
+    pub fn max_commands_per_sec(&self) -> u64 {
+        self.settings.read().unwrap().max_commands_per_sec
+    }
+
+    /// Applies every `(name, value)` pair, or none of them if any is invalid.
+    pub fn set(&self, pairs: &[(String, String)]) -> Result<(), String> {
+        let mut settings = self.settings.write().unwrap();
+        let mut updated = settings.clone();
+        for (name, value) in pairs {
+            updated.set(name, value)?;
+        }
+        *settings = updated;
+        Ok(())
+    }
+
+    /// Parameters whose name matches any of `patterns`, for CONFIG GET.
+    pub fn matching(&self, patterns: &[String]) -> Vec<(&'static str, String)> {
+        self.get()
+            .values()
+            .into_iter()
+            .filter(|(name, _)| {
+                patterns.iter().any(|pattern| {
+                    glob_match(pattern.to_ascii_lowercase().as_bytes(), name.as_bytes())
+                })
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_set_is_all_or_nothing() {
+        let config = Config::new();
+        let pairs = vec![
+            ("requirepass".to_string(), "secret".to_string()),
+            ("no-such-option".to_string(), "1".to_string()),
+        ];
+        assert!(config.set(&pairs).is_err());
+        assert_eq!(config.requirepass(), None);
+
+        config
+            .set(&[("REQUIREPASS".to_string(), "secret".to_string())])
+            .unwrap();
+        assert_eq!(config.requirepass(), Some("secret".to_string()));
+        assert_eq!(
+            config.matching(&["require*".to_string()]),
+            vec![("requirepass", "secret".to_string())]
+        );
+    }
+
+    #[test]
+    fn test_numeric_parameters_reject_garbage() {
+        let config = Config::new();
+        let set = |value: &str| config.set(&[("keys-scan-limit".to_string(), value.to_string())]);
+        assert!(set("ten").is_err());
+        assert!(set("-1").is_err());
+        set("10").unwrap();
+        assert_eq!(config.get().keys_scan_limit, 10);
+
+        let jitter = |value: &str| config.set(&[("expire-jitter".to_string(), value.to_string())]);
+        assert!(jitter("101").is_err());
+        jitter("10").unwrap();
+        assert_eq!(config.get().expire_jitter, 10);
+    }
+
+    #[test]
+    fn test_every_settable_parameter_round_trips_through_get() {
+        let settable = [
+            ("requirepass", "secret"),
+            ("keys-scan-limit", "7"),
+            ("expire-jitter", "5"),
+            ("default-ttl-seconds", "60"),
+            ("max-commands-per-sec", "100"),
+            ("tcp-keepalive", "300"),
+        ];
+        let config = Config::new();
+        for (name, value) in settable {
+            config
+                .set(&[(name.to_string(), value.to_string())])
+                .unwrap();
+            assert_eq!(
+                config.matching(&[name.to_string()]),
+                vec![(name, value.to_string())]
+            );
+        }
+
+        // Everything CONFIG GET reports is either settable or immutable.
+        let mut reported: Vec<_> = config.matching(&["*".to_string()]);
+        reported.retain(|(name, _)| *name != "enable-debug-command");
+        assert_eq!(reported.len(), settable.len());
+    }
+
+    #[test]
+    fn test_enable_debug_command_is_startup_only() {
+        let config = Config::new();
+        let pairs = [("enable-debug-command".to_string(), "yes".to_string())];
+        assert!(config
+            .set(&pairs)
+            .unwrap_err()
+            .contains("can't set immutable config"));
+        assert!(!config.get().enable_debug_command);
+        assert_eq!(
+            config.matching(&["enable-debug-command".to_string()]),
+            vec![("enable-debug-command", "no".to_string())]
+        );
+
+        let config = Config::with_enable_debug_command(true);
+        assert!(config.get().enable_debug_command);
+        assert!(config.set(&pairs).is_err());
+        assert_eq!(
+            config.matching(&["enable-debug-command".to_string()]),
+            vec![("enable-debug-command", "yes".to_string())]
+        );
+    }
+}
@@ -0,0 +1,510 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use crate::glob::glob_match;
+use crate::storage::KeyVersion;
+use crate::RespValue;
+
+/// Per-connection state, owned by the connection thread and handed to every
+/// command it runs.
+#[derive(Debug)]
+pub struct ConnectionState {
+    pub id: u64,
+    pub db: usize,
+    pub name: Option<String>,
+    pub authenticated: bool,
+    pub subscriptions: HashSet<String>,
+    pub pattern_subscriptions: HashSet<String>,
+    pub transaction: Option<Vec<RespValue>>,
+    /// Set when a command was rejected while queueing, so EXEC discards the
+    /// whole transaction instead of running it.
+    pub transaction_aborted: bool,
+    /// WATCHed keys as (db, key, version at WATCH time).
+    pub watched: Vec<(usize, String, KeyVersion)>,
+    pub protocol: u8,
+    pub peer_addr: Option<SocketAddr>,
+    /// Where out-of-band push frames for this connection are written.
+    pub push: Option<PushSink>,
+    pub tracking: bool,
+    /// Throttles this connection when max-commands-per-sec is set.
+    pub rate_limiter: RateLimiter,
+}
+
+/// Writer shared between a connection's own replies and pushes sent to it by
+/// other connections, so frames never interleave.
+#[derive(Clone)]
+pub struct PushSink(pub Arc<Mutex<dyn Write + Send>>);
+
+impl fmt::Debug for PushSink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("PushSink")
+    }
+}
+
+impl Default for ConnectionState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ConnectionState {
+    pub fn new() -> Self {
+        Self {
+            id: 0,
+            db: 0,
+            name: None,
+            authenticated: false,
+            subscriptions: HashSet::new(),
+            pattern_subscriptions: HashSet::new(),
+            transaction: None,
+            transaction_aborted: false,
+            watched: Vec::new(),
+            protocol: 2,
+            peer_addr: None,
+            push: None,
+            tracking: false,
+            rate_limiter: RateLimiter::default(),
+        }
+    }
+
+    pub fn in_transaction(&self) -> bool {
+        self.transaction.is_some()
+    }
+
+    /// Back to the just-connected state, keeping only the connection's
+    /// identity, push sink and rate limit bucket.
+    pub fn reset(&mut self, authenticated: bool) {
+        *self = Self {
+            id: self.id,
+            peer_addr: self.peer_addr,
+            push: self.push.take(),
+            rate_limiter: std::mem::take(&mut self.rate_limiter),
+            authenticated,
+            ..Self::new()
+        };
+    }
+
+    /// Channels plus patterns, the count Redis reports in (un)subscribe replies.
+    pub fn subscription_count(&self) -> usize {
+        self.subscriptions.len() + self.pattern_subscriptions.len()
+    }
+}
+
+/// Token bucket holding up to `rate` commands and refilling at `rate` per
+/// second. The caller passes the time in, so tests can move the clock.
+#[derive(Debug, Default)]
+pub struct RateLimiter {
+    /// Tokens left and when they were counted. Empty until the first
+    /// command, which finds the bucket full.
+    bucket: Option<(f64, Instant)>,
+}
+
+impl RateLimiter {
+    /// Takes a token for a command run at `now`, or returns false if the
+    /// bucket is empty.
+    pub fn try_acquire(&mut self, rate: u64, now: Instant) -> bool {
+        let capacity = rate as f64;
+        let tokens = match self.bucket {
+            Some((tokens, counted_at)) => {
+                let elapsed = now.saturating_duration_since(counted_at).as_secs_f64();
+                (tokens + elapsed * capacity).min(capacity)
+            }
+            None => capacity,
+        };
+        let allowed = tokens >= 1.0;
+        self.bucket = Some((if allowed { tokens - 1.0 } else { tokens }, now));
+        allowed
+    }
+}
+
+/// Connections currently open on the server, keyed by connection id.
+#[derive(Clone, Default)]
+pub struct ClientRegistry {
+    clients: Arc<Mutex<HashMap<u64, Option<SocketAddr>>>>,
+    next_id: Arc<AtomicU64>,
+    tracking: Arc<Mutex<TrackingTable>>,
+    pubsub: Arc<Mutex<PubSubTable>>,
+}
+
+/// Client-side caching state: the push sink of every client with tracking
+/// on, and which of them have read each key since it last changed.
+#[derive(Default)]
+struct TrackingTable {
+    sinks: HashMap<u64, PushSink>,
+    readers: HashMap<String, HashSet<u64>>,
+}
+
+/// Pub/sub routing: the push sink and protocol of every subscribed client,
+/// and who listens on each channel and pattern.
+#[derive(Default)]
+struct PubSubTable {
+    subscribers: HashMap<u64, (PushSink, u8)>,
+    channels: HashMap<String, HashSet<u64>>,
+    patterns: HashMap<String, HashSet<u64>>,
+}
+
+impl PubSubTable {
+    fn listeners(&mut self, pattern: bool) -> &mut HashMap<String, HashSet<u64>> {
+        if pattern {
+            &mut self.patterns
+        } else {
+            &mut self.channels
+        }
+    }
+}
+
+impl ClientRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers a new connection under a fresh id. The connection stays
+    /// registered until the returned guard is dropped, however the
+    /// connection thread exits.
+    pub fn register(&self, peer_addr: Option<SocketAddr>) -> ConnectionGuard {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+        self.clients.lock().unwrap().insert(id, peer_addr);
+        ConnectionGuard {
+            registry: self.clone(),
+            id,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.clients.lock().unwrap().len()
+    }
+
+    /// Open connections as (id, peer address), oldest first.
+    pub fn list(&self) -> Vec<(u64, Option<SocketAddr>)> {
+        let mut clients: Vec<_> = self
+            .clients
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(&id, &addr)| (id, addr))
+            .collect();
+        clients.sort_unstable_by_key(|&(id, _)| id);
+        clients
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn enable_tracking(&self, id: u64, sink: PushSink) {
+        self.tracking.lock().unwrap().sinks.insert(id, sink);
+    }
+
+    pub fn disable_tracking(&self, id: u64) {
+        let mut tracking = self.tracking.lock().unwrap();
+        tracking.sinks.remove(&id);
+        tracking.readers.retain(|_, readers| {
+            readers.remove(&id);
+            !readers.is_empty()
+        });
+    }
+
+    pub fn track_read(&self, id: u64, key: &str) {
+        let mut tracking = self.tracking.lock().unwrap();
+        if tracking.sinks.contains_key(&id) {
+            tracking
+                .readers
+                .entry(key.to_string())
+                .or_default()
+                .insert(id);
+        }
+    }
+
+    pub fn subscribe(&self, id: u64, sink: PushSink, protocol: u8, channel: &str, pattern: bool) {
+        let mut pubsub = self.pubsub.lock().unwrap();
+        pubsub.subscribers.insert(id, (sink, protocol));
+        pubsub
+            .listeners(pattern)
+            .entry(channel.to_string())
+            .or_default()
+            .insert(id);
+    }
+
+    pub fn unsubscribe(&self, id: u64, channel: &str, pattern: bool) {
+        let mut pubsub = self.pubsub.lock().unwrap();
+        let listeners = pubsub.listeners(pattern);
+        if let Some(ids) = listeners.get_mut(channel) {
+            ids.remove(&id);
+            if ids.is_empty() {
+                listeners.remove(channel);
+            }
+        }
+    }
+
+    pub fn unsubscribe_all(&self, id: u64) {
+        let mut pubsub = self.pubsub.lock().unwrap();
+        pubsub.subscribers.remove(&id);
+        for pattern in [false, true] {
+            pubsub.listeners(pattern).retain(|_, ids| {
+                ids.remove(&id);
+                !ids.is_empty()
+            });
+        }
+    }
+
+    /// Keeps message frames in step with a subscriber that switches protocol.
+    pub fn set_push_protocol(&self, id: u64, protocol: u8) {
+        if let Some((_, current)) = self.pubsub.lock().unwrap().subscribers.get_mut(&id) {
+            *current = protocol;
+        }
+    }
+
+    /// Delivers `message` to every subscriber of `channel` and of each
+    /// pattern matching it, and returns how many deliveries were made.
+    ///
+    /// Frames are built under the registry lock but written after it is
+    /// released, so a subscriber that stops reading only stalls this call.
+    pub fn publish(&self, channel: &str, message: &[u8]) -> usize {
+        let mut frames: Vec<(PushSink, Vec<u8>)> = Vec::new();
+        {
+            let pubsub = self.pubsub.lock().unwrap();
+            let mut deliver = |id: &u64, parts: &[&[u8]]| {
+                if let Some((sink, protocol)) = pubsub.subscribers.get(id) {
+                    frames.push((sink.clone(), push_frame(*protocol, parts)));
+                }
+            };
+            if let Some(ids) = pubsub.channels.get(channel) {
+                for id in ids {
+                    deliver(id, &[b"message", channel.as_bytes(), message]);
+                }
+            }
+            for (pattern, ids) in &pubsub.patterns {
+                if glob_match(pattern.as_bytes(), channel.as_bytes()) {
+                    for id in ids {
+                        let parts: [&[u8]; 4] =
+                            [b"pmessage", pattern.as_bytes(), channel.as_bytes(), message];
+                        deliver(id, &parts);
+                    }
+                }
+            }
+        }
+
+        for (PushSink(sink), frame) in &frames {
+            let mut sink = sink.lock().unwrap();
+            let _ = sink.write_all(frame).and_then(|_| sink.flush());
+        }
+        frames.len()
+    }
+
+    /// Sends an `invalidate` push for `key` to every client that read it,
+    /// then forgets them until they read it again.
+    ///
+    /// Like `publish`, the pushes are written only after the tracking lock is
+    /// released, so a client that stops reading cannot stall other writers.
+    pub fn invalidate(&self, key: &str) {
+        let sinks: Vec<PushSink> = {
+            let mut tracking = self.tracking.lock().unwrap();
+            let Some(readers) = tracking.readers.remove(key) else {
+                return;
+            };
+            readers
+                .iter()
+                .filter_map(|id| tracking.sinks.get(id).cloned())
+                .collect()
+        };
+
+        let frame = format!(
+            ">2\r\n$10\r\ninvalidate\r\n*1\r\n${}\r\n{}\r\n",
+            key.len(),
+            key
+        );
+        for PushSink(sink) in sinks {
+            let mut sink = sink.lock().unwrap();
+            // A failed push means the client is going away; its own
+            // connection thread will notice and clean up.
+            let _ = sink.write_all(frame.as_bytes()).and_then(|_| sink.flush());
+        }
+    }
+}
+
+/// An out-of-band frame of bulk strings: a RESP3 push, or the plain array
+/// RESP2 subscribers expect.
+fn push_frame(protocol: u8, parts: &[&[u8]]) -> Vec<u8> {
+    let header = if protocol >= 3 { '>' } else { '*' };
+    let mut frame = format!("{}{}\r\n", header, parts.len()).into_bytes();
+    for part in parts {
+        frame.extend_from_slice(format!("${}\r\n", part.len()).as_bytes());
+        frame.extend_from_slice(part);
+        frame.extend_from_slice(b"\r\n");
+    }
+    frame
+}
+
+pub struct ConnectionGuard {
+    registry: ClientRegistry,
+    id: u64,
+}
+
+impl ConnectionGuard {
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.registry.clients.lock().unwrap().remove(&self.id);
+        self.registry.disable_tracking(self.id);
+        self.registry.unsubscribe_all(self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn test_register_assigns_unique_ids() {
+        let registry = ClientRegistry::new();
+        let first = registry.register(None);
+        let second = registry.register(None);
+        assert_ne!(first.id(), second.id());
+        assert_eq!(registry.len(), 2);
+    }
+
+    #[test]
+    fn test_guard_unregisters_when_connection_thread_exits_early() {
+        let registry = ClientRegistry::new();
+
+        let handle = {
+            let registry = registry.clone();
+            thread::spawn(move || {
+                let _guard = registry.register(None);
+                panic!("connection dropped abruptly");
+            })
+        };
+
+        assert!(handle.join().is_err());
+        assert!(registry.is_empty());
+    }
+
+    #[test]
+    fn test_dropped_subscriber_no_longer_counts() {
+        let registry = ClientRegistry::new();
+
+        let handle = {
+            let registry = registry.clone();
+            thread::spawn(move || {
+                let guard = registry.register(None);
+                let sink = PushSink(Arc::new(Mutex::new(Vec::new())));
+                registry.subscribe(guard.id(), sink.clone(), 2, "news", false);
+                registry.subscribe(guard.id(), sink, 2, "n*", true);
+                assert_eq!(registry.publish("news", b"hello"), 2);
+                panic!("subscriber dropped abruptly");
+            })
+        };
+
+        assert!(handle.join().is_err());
+        assert_eq!(registry.publish("news", b"hello"), 0);
+    }
+
+    #[test]
+    fn test_invalidate_pushes_once_to_each_reader() {
+        let registry = ClientRegistry::new();
+        let output = Arc::new(Mutex::new(Vec::new()));
+        let guard = registry.register(None);
+        registry.enable_tracking(guard.id(), PushSink(output.clone()));
+
+        registry.track_read(guard.id(), "key");
+        registry.invalidate("key");
+        registry.invalidate("key");
+        assert_eq!(
+            output.lock().unwrap().as_slice(),
+            b">2\r\n$10\r\ninvalidate\r\n*1\r\n$3\r\nkey\r\n"
+        );
+
+        registry.track_read(guard.id(), "key");
+        drop(guard);
+        assert!(registry.tracking.lock().unwrap().readers.is_empty());
+    }
+
+    /// A sink that records whether the registry's pub/sub and tracking locks
+    /// were both free while it was being written to.
+    struct LockProbe {
+        registry: ClientRegistry,
+        lock_was_free: Arc<Mutex<Vec<bool>>>,
+    }
+
+    impl Write for LockProbe {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            let free = self.registry.pubsub.try_lock().is_ok()
+                && self.registry.tracking.try_lock().is_ok();
+            self.lock_was_free.lock().unwrap().push(free);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_publish_writes_after_releasing_the_registry_lock() {
+        let registry = ClientRegistry::new();
+        let lock_was_free = Arc::new(Mutex::new(Vec::new()));
+        let probe = LockProbe {
+            registry: registry.clone(),
+            lock_was_free: lock_was_free.clone(),
+        };
+        let guard = registry.register(None);
+        let sink = PushSink(Arc::new(Mutex::new(probe)));
+        registry.subscribe(guard.id(), sink.clone(), 2, "news", false);
+        registry.subscribe(guard.id(), sink, 2, "n*", true);
+
+        assert_eq!(registry.publish("news", b"hello"), 2);
+        let writes = lock_was_free.lock().unwrap();
+        assert!(!writes.is_empty());
+        assert!(writes.iter().all(|&free| free));
+    }
+
+    #[test]
+    fn test_invalidate_writes_after_releasing_the_tracking_lock() {
+        let registry = ClientRegistry::new();
+        let lock_was_free = Arc::new(Mutex::new(Vec::new()));
+        let probe = LockProbe {
+            registry: registry.clone(),
+            lock_was_free: lock_was_free.clone(),
+        };
+        let guard = registry.register(None);
+        registry.enable_tracking(guard.id(), PushSink(Arc::new(Mutex::new(probe))));
+
+        registry.track_read(guard.id(), "key");
+        registry.invalidate("key");
+        let writes = lock_was_free.lock().unwrap();
+        assert!(!writes.is_empty());
+        assert!(writes.iter().all(|&free| free));
+    }
+
+    #[test]
+    fn test_rate_limiter_refuses_a_burst_and_refills_over_time() {
+        let start = Instant::now();
+        let mut limiter = RateLimiter::default();
+        for _ in 0..3 {
+            assert!(limiter.try_acquire(3, start));
+        }
+        assert!(!limiter.try_acquire(3, start));
+
+        // A third of a second buys back one of the three tokens.
+        let later = start + std::time::Duration::from_millis(340);
+        assert!(limiter.try_acquire(3, later));
+        assert!(!limiter.try_acquire(3, later));
+
+        // Idle time never fills the bucket past its capacity.
+        let much_later = later + std::time::Duration::from_secs(60);
+        for _ in 0..3 {
+            assert!(limiter.try_acquire(3, much_later));
+        }
+        assert!(!limiter.try_acquire(3, much_later));
+    }
+}
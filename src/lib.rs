@@ -1,9 +1,22 @@
+pub mod clock;
 pub mod command;
+pub mod command_table;
+pub mod config;
+pub mod connection;
+pub mod glob;
 pub mod parser;
+pub mod replication;
+pub mod reply;
+pub mod rng;
 pub mod server;
+pub mod stats;
 pub mod storage;
 
-pub use command::handle_command;
+pub use command::{handle_command, handle_command_into};
+pub use config::Config;
+pub use connection::{ClientRegistry, ConnectionState, PushSink};
 pub use parser::{ParseResult, RespParser, RespValue};
-pub use server::RedisServer;
+pub use replication::Replication;
+pub use reply::Reply;
+pub use server::{RedisServer, ServerState};
 pub use storage::Storage;
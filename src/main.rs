@@ -8,9 +8,9 @@ use std::{
     thread,
 };
 
-use codecrafters_redis::RedisServer;
 use codecrafters_redis::Storage;
 use codecrafters_redis::{handle_command, server};
+use codecrafters_redis::{Config, RedisServer};
 use codecrafters_redis::{ParseResult, RespParser, RespValue};
 
 fn main() {
@@ -18,7 +18,12 @@ fn main() {
     println!("Logs from your program will appear here!");
 
     // Uncomment the code below to pass the first stage
-    let server = RedisServer::new("127.0.0.1:6379");
+    let args: Vec<String> = std::env::args().collect();
+    let enable_debug_command = args
+        .windows(2)
+        .any(|pair| pair[0] == "--enable-debug-command" && pair[1].eq_ignore_ascii_case("yes"));
+    let server = RedisServer::new("127.0.0.1:6379")
+        .with_config(Config::with_enable_debug_command(enable_debug_command));
     if let Err(e) = server.run() {
         eprintln!("Server error: {}", e);
         std::process::exit(1);
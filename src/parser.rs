@@ -1,5 +1,23 @@
+use std::fmt;
 use std::str;
 
+use crate::reply::quote_argv;
+
+/// proto-max-bulk-len: the largest bulk string accepted from a client, and the
+/// largest string value a command may build.
+pub const PROTO_MAX_BULK_LEN: usize = 512 * 1024 * 1024;
+
+/// proto-max-multibulk-len: the most elements an array header may declare.
+pub const PROTO_MAX_MULTIBULK_LEN: usize = 1024 * 1024;
+
+/// Most array slots reserved from a header alone. Larger arrays grow as their
+/// elements arrive, so a bare header cannot make every re-parse allocate the
+/// full declared size.
+const MAX_PREALLOCATED_ELEMENTS: usize = 1024;
+
+/// Longest inline command line buffered while waiting for its newline.
+pub const PROTO_INLINE_MAX_SIZE: usize = 64 * 1024;
+
 #[derive(Debug, Clone, PartialEq)]
 pub enum RespValue {
     SimpleString(String),
@@ -9,6 +27,39 @@ pub enum RespValue {
     Array(Option<Vec<RespValue>>),
 }
 
+/// Human readable rendering in the style of redis-cli, for logs and test
+/// failures. Bulk strings are quoted and escaped, so this is lossy and never
+/// meant for the wire.
+impl fmt::Display for RespValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RespValue::SimpleString(s) => write!(f, "{}", s),
+            RespValue::Error(e) => write!(f, "(error) {}", e),
+            RespValue::Integer(i) => write!(f, "(integer) {}", i),
+            RespValue::BulkString(Some(bytes)) => write!(f, "{}", quote_argv(&[bytes])),
+            RespValue::BulkString(None) | RespValue::Array(None) => write!(f, "(nil)"),
+            RespValue::Array(Some(items)) if items.is_empty() => write!(f, "(empty array)"),
+            RespValue::Array(Some(items)) => {
+                for (i, item) in items.iter().enumerate() {
+                    let prefix = format!("{}) ", i + 1);
+                    let rendered = item.to_string();
+                    for (n, line) in rendered.lines().enumerate() {
+                        if i > 0 || n > 0 {
+                            writeln!(f)?;
+                        }
+                        if n == 0 {
+                            write!(f, "{}{}", prefix, line)?;
+                        } else {
+                            write!(f, "{:width$}{}", "", line, width = prefix.len())?;
+                        }
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
 #[derive(Debug, PartialEq)]
 pub enum ParseResult {
     Complete(RespValue, usize),
@@ -18,25 +69,73 @@ pub enum ParseResult {
 
 pub struct RespParser {
     byte_buffer: Vec<u8>,
+    max_inline_size: usize,
+    max_multibulk_len: usize,
+    lenient_newlines: bool,
+}
+
+impl Default for RespParser {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl RespParser {
     pub fn new() -> Self {
         Self {
             byte_buffer: Vec::default(),
+            max_inline_size: PROTO_INLINE_MAX_SIZE,
+            max_multibulk_len: PROTO_MAX_MULTIBULK_LEN,
+            lenient_newlines: true,
         }
     }
 
+    /// Whether a bare `\n` ends a line. On by default; off requires `\r\n`.
+    pub fn set_lenient_newlines(&mut self, lenient: bool) {
+        self.lenient_newlines = lenient;
+    }
+
+    pub fn set_max_inline_size(&mut self, size: usize) {
+        self.max_inline_size = size;
+    }
+
+    pub fn set_max_multibulk_len(&mut self, len: usize) {
+        self.max_multibulk_len = len;
+    }
+
     pub fn feed(&mut self, data: &[u8]) {
         self.byte_buffer.extend_from_slice(data);
+        self.skip_blank_lines();
     }
 
+    /// Drops empty inline lines at the start of the buffer, such as a bare
+    /// Enter in telnet. Like Redis, they are not commands and get no reply.
+    fn skip_blank_lines(&mut self) {
+        while matches!(self.byte_buffer.first(), Some(b' ' | b'\t' | b'\r' | b'\n')) {
+            let Some(newline) = self.byte_buffer.iter().position(|&b| b == b'\n') else {
+                return;
+            };
+            let line = &self.byte_buffer[..newline];
+            let line = line.strip_suffix(b"\r").unwrap_or(line);
+            if !split_inline_args(line).is_some_and(|args| args.is_empty()) {
+                return;
+            }
+            self.byte_buffer.drain(..=newline);
+        }
+    }
+
+    /// Anything that doesn't start with a RESP type byte is an inline
+    /// command: a single line of space separated, optionally quoted words.
     pub fn parse(&self) -> ParseResult {
-        self.parse_value(0)
+        match self.byte_buffer.first() {
+            None | Some(b'+' | b'-' | b':' | b'$' | b'*') => self.parse_value(0),
+            Some(_) => self.parse_inline(),
+        }
     }
 
     pub fn consume(&mut self, n: usize) {
         self.byte_buffer.drain(..n);
+        self.skip_blank_lines();
     }
 
     pub fn has_data(&self) -> bool {
@@ -47,6 +146,14 @@ impl RespParser {
         self.byte_buffer.clear();
     }
 
+    /// Drops everything buffered so the parser can be reused after
+    /// `ParseResult::Error`. Settings are kept. The server doesn't do this:
+    /// after a protocol error it can't tell where the next frame starts, so
+    /// it replies with the error and closes the connection.
+    pub fn reset(&mut self) {
+        self.clear();
+    }
+
     fn parse_value(&self, pos: usize) -> ParseResult {
         if !self.has_bytes(pos, 1) {
             return ParseResult::Incomplete;
@@ -65,8 +172,8 @@ impl RespParser {
         if !self.has_bytes(pos, 1) {
             return ParseResult::Incomplete;
         }
-        let crlf_pos = match self.find_crlf(pos + 1) {
-            Some(p) => p,
+        let (crlf_pos, terminator) = match self.find_line_end(pos + 1) {
+            Some(end) => end,
             None => return ParseResult::Incomplete,
         };
 
@@ -80,7 +187,7 @@ impl RespParser {
             Err(_) => return ParseResult::Error("Invalid UTF-8".to_string()),
         };
 
-        let consumed_bytes = crlf_pos + 2 - pos;
+        let consumed_bytes = crlf_pos + terminator - pos;
         ParseResult::Complete(RespValue::SimpleString(simple_string), consumed_bytes)
     }
 
@@ -89,8 +196,8 @@ impl RespParser {
             return ParseResult::Incomplete;
         }
 
-        let crlf_pos = match self.find_crlf(pos + 1) {
-            Some(p) => p,
+        let (crlf_pos, terminator) = match self.find_line_end(pos + 1) {
+            Some(end) => end,
             None => return ParseResult::Incomplete,
         };
 
@@ -99,7 +206,7 @@ impl RespParser {
             None => return ParseResult::Error("Invalid slice range".to_string()),
         };
 
-        let consumed_bytes = crlf_pos + 2 - pos;
+        let consumed_bytes = crlf_pos + terminator - pos;
         if let Some(i) = Self::parse_i64(content) {
             ParseResult::Complete(RespValue::Integer(i), consumed_bytes)
         } else {
@@ -115,8 +222,8 @@ impl RespParser {
             return ParseResult::Incomplete;
         }
 
-        let crlf_pos = match self.find_crlf(pos + 1) {
-            Some(p) => p,
+        let (crlf_pos, terminator) = match self.find_line_end(pos + 1) {
+            Some(end) => end,
             None => return ParseResult::Incomplete,
         };
 
@@ -132,36 +239,34 @@ impl RespParser {
 
         match length {
             -1 => {
-                let consumed = crlf_pos + 2 - pos;
+                let consumed = crlf_pos + terminator - pos;
                 ParseResult::Complete(RespValue::BulkString(None), consumed)
             }
-            n if n < -1 => ParseResult::Error(format!("Invalid bulk string length: {}", n)),
+            n if n < -1 || n as u64 > PROTO_MAX_BULK_LEN as u64 => {
+                ParseResult::Error(format!("Invalid bulk string length: {}", n))
+            }
 
             n => {
                 let len = n as usize;
-                let content_start = crlf_pos + 2;
+                let content_start = crlf_pos + terminator;
                 let content_end = content_start + len;
-                if !self.has_bytes(content_end, 2) {
-                    return ParseResult::Incomplete;
-                }
+                let trailing = match self.byte_buffer.get(content_end) {
+                    Some(b'\n') if self.lenient_newlines => 1,
+                    _ if !self.has_bytes(content_end, 2) => return ParseResult::Incomplete,
+                    _ if self.get_slice(content_end, content_end + 2) == Some(b"\r\n") => 2,
+                    _ => return ParseResult::Error("Missing trailing CRLF".to_string()),
+                };
 
                 let content = match self.get_slice(content_start, content_end) {
                     Some(bytes) => bytes,
                     None => return ParseResult::Error("Invalid slice range".to_string()),
                 };
 
-                // 2 bytes checked, so unwrap is safe
-                let trailing_crlf = self.get_slice(content_end, content_end + 2).unwrap();
-
-                if trailing_crlf != b"\r\n" {
-                    return ParseResult::Error("Missing trailing CRLF".to_string());
-                }
-
-                let consumed_bytes = content_end + 2 - pos;
-                return ParseResult::Complete(
+                let consumed_bytes = content_end + trailing - pos;
+                ParseResult::Complete(
                     RespValue::BulkString(Some(content.to_vec())),
                     consumed_bytes,
-                );
+                )
             }
         }
     }
@@ -170,8 +275,8 @@ impl RespParser {
         if !self.has_bytes(pos, 1) {
             return ParseResult::Incomplete;
         }
-        let crlf_pos = match self.find_crlf(pos + 1) {
-            Some(p) => p,
+        let (crlf_pos, terminator) = match self.find_line_end(pos + 1) {
+            Some(end) => end,
             None => return ParseResult::Incomplete,
         };
 
@@ -187,19 +292,24 @@ impl RespParser {
 
         let element_count = match count {
             -1 => {
-                let consumed = crlf_pos + 2 - pos;
+                let consumed = crlf_pos + terminator - pos;
                 return ParseResult::Complete(RespValue::Array(None), consumed);
             }
-            n if n < -1 => return ParseResult::Error(format!("Invalid array length: {}", n)),
+            // Checked before anything is allocated for the elements.
+            n if n < -1 || n as u64 > self.max_multibulk_len as u64 => {
+                return ParseResult::Error(
+                    "ERR Protocol error: invalid multibulk length".to_string(),
+                )
+            }
             0 => {
-                let consumed = crlf_pos + 2 - pos;
+                let consumed = crlf_pos + terminator - pos;
                 return ParseResult::Complete(RespValue::Array(Some(vec![])), consumed);
             }
             n => n as usize,
         };
 
-        let mut elements = Vec::with_capacity(element_count);
-        let mut current_pos = crlf_pos + 2;
+        let mut elements = Vec::with_capacity(element_count.min(MAX_PREALLOCATED_ELEMENTS));
+        let mut current_pos = crlf_pos + terminator;
 
         for _ in 0..element_count {
             match self.parse_value(current_pos) {
@@ -216,13 +326,39 @@ impl RespParser {
         ParseResult::Complete(RespValue::Array(Some(elements)), total_consumed)
     }
 
+    fn parse_inline(&self) -> ParseResult {
+        let newline = match self.byte_buffer.iter().position(|&b| b == b'\n') {
+            Some(p) => p,
+            None if self.byte_buffer.len() > self.max_inline_size => {
+                return ParseResult::Error("ERR Protocol error: too big inline request".to_string())
+            }
+            None => return ParseResult::Incomplete,
+        };
+
+        let line = &self.byte_buffer[..newline];
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        match split_inline_args(line) {
+            Some(args) => ParseResult::Complete(
+                RespValue::Array(Some(
+                    args.into_iter()
+                        .map(|arg| RespValue::BulkString(Some(arg)))
+                        .collect(),
+                )),
+                newline + 1,
+            ),
+            None => {
+                ParseResult::Error("ERR Protocol error: unbalanced quotes in request".to_string())
+            }
+        }
+    }
+
     fn parse_error(&self, pos: usize) -> ParseResult {
         if !self.has_bytes(pos, 1) {
             return ParseResult::Incomplete;
         }
 
-        let crlf_pos = match self.find_crlf(pos + 1) {
-            Some(p) => p,
+        let (crlf_pos, terminator) = match self.find_line_end(pos + 1) {
+            Some(end) => end,
             None => return ParseResult::Incomplete,
         };
 
@@ -236,17 +372,27 @@ impl RespParser {
             Err(_) => return ParseResult::Error("Invalid UTF-8".to_string()),
         };
 
-        let consumed_bytes = crlf_pos + 2 - pos;
+        let consumed_bytes = crlf_pos + terminator - pos;
 
         ParseResult::Complete(RespValue::Error(error_string), consumed_bytes)
     }
 
-    fn find_crlf(&self, pos: usize) -> Option<usize> {
-        self.byte_buffer[pos..]
-            .windows(2)
-            .enumerate()
-            .find(|(_i, window)| window[0] == b'\r' && window[1] == b'\n')
-            .map(|(relative_index, _window)| pos + relative_index)
+    /// End of the line starting at `pos` and the length of its terminator.
+    /// Lenient parsing also takes a bare `\n`, like Redis does.
+    fn find_line_end(&self, pos: usize) -> Option<(usize, usize)> {
+        if !self.lenient_newlines {
+            return self.byte_buffer[pos..]
+                .windows(2)
+                .position(|window| window == b"\r\n")
+                .map(|relative_index| (pos + relative_index, 2));
+        }
+
+        let newline = pos + self.byte_buffer[pos..].iter().position(|&b| b == b'\n')?;
+        if newline > pos && self.byte_buffer[newline - 1] == b'\r' {
+            Some((newline - 1, 2))
+        } else {
+            Some((newline, 1))
+        }
     }
 
     fn has_bytes(&self, pos: usize, n: usize) -> bool {
@@ -267,24 +413,120 @@ impl RespParser {
     }
 }
 
+/// Splits an inline command like redis-cli does: words separated by
+/// whitespace, `"..."` with C-style escapes and `'...'` with only `\'`.
+/// Returns `None` for unbalanced quotes or a closing quote followed by
+/// anything but whitespace.
+fn split_inline_args(line: &[u8]) -> Option<Vec<Vec<u8>>> {
+    let mut args = Vec::new();
+    let mut i = 0;
+    loop {
+        while line.get(i).is_some_and(u8::is_ascii_whitespace) {
+            i += 1;
+        }
+        if i == line.len() {
+            return Some(args);
+        }
+
+        let mut arg = Vec::new();
+        match line[i] {
+            quote @ (b'"' | b'\'') => {
+                i += 1;
+                loop {
+                    match *line.get(i)? {
+                        b'\\' if quote == b'"' && i + 1 < line.len() => {
+                            let (byte, width) = unescape(&line[i + 1..]);
+                            arg.push(byte);
+                            i += 1 + width;
+                        }
+                        b'\\' if quote == b'\'' && line.get(i + 1) == Some(&b'\'') => {
+                            arg.push(b'\'');
+                            i += 2;
+                        }
+                        c if c == quote => {
+                            i += 1;
+                            if line.get(i).is_some_and(|c| !c.is_ascii_whitespace()) {
+                                return None;
+                            }
+                            break;
+                        }
+                        c => {
+                            arg.push(c);
+                            i += 1;
+                        }
+                    }
+                }
+            }
+            _ => {
+                while let Some(&c) = line.get(i).filter(|c| !c.is_ascii_whitespace()) {
+                    arg.push(c);
+                    i += 1;
+                }
+            }
+        }
+        args.push(arg);
+    }
+}
+
+/// Decodes the escape after a backslash, returning the byte and how many
+/// bytes of `rest` it used.
+fn unescape(rest: &[u8]) -> (u8, usize) {
+    if rest[0] == b'x' && rest.len() >= 3 {
+        if let Ok(byte) = u8::from_str_radix(str::from_utf8(&rest[1..3]).unwrap_or(""), 16) {
+            return (byte, 3);
+        }
+    }
+    let byte = match rest[0] {
+        b'n' => b'\n',
+        b'r' => b'\r',
+        b't' => b'\t',
+        b'b' => 0x08,
+        b'a' => 0x07,
+        other => other,
+    };
+    (byte, 1)
+}
+
 #[cfg(test)]
 mod tests {
     use crate::*;
 
     #[test]
-    fn test_find_crlf() {
+    fn test_find_line_end() {
         let mut parser = RespParser::new();
         parser.feed(b"+OK\r\nSOMETHING");
-        assert_eq!(parser.find_crlf(0), Some(3));
-        assert_eq!(parser.find_crlf(4), None);
+        assert_eq!(parser.find_line_end(0), Some((3, 2)));
+        assert_eq!(parser.find_line_end(5), None);
+    }
+
+    #[test]
+    fn test_parse_accepts_bare_newlines() {
+        let mut parser = RespParser::new();
+        parser.feed(b"*1\n$4\nPING\n");
+        let expected = RespValue::Array(Some(vec![RespValue::BulkString(Some(b"PING".to_vec()))]));
+        assert_eq!(parser.parse(), ParseResult::Complete(expected, 11));
+
+        // Payloads are read by length, so newlines inside them are data.
+        let mut parser = RespParser::new();
+        parser.feed(b"*2\r\n$3\na\nb\r\n:7\n");
+        let expected = RespValue::Array(Some(vec![
+            RespValue::BulkString(Some(b"a\nb".to_vec())),
+            RespValue::Integer(7),
+        ]));
+        assert_eq!(parser.parse(), ParseResult::Complete(expected, 15));
+
+        let mut parser = RespParser::new();
+        parser.set_lenient_newlines(false);
+        parser.feed(b"*1\n$4\nPING\n");
+        assert_eq!(parser.parse(), ParseResult::Incomplete);
     }
 
     #[test]
     fn test_has_bytes() {
         let mut parser = RespParser::new();
         parser.feed(b"+OK\r\nSOMETHING");
-        assert_eq!(parser.has_bytes(2, 5), true);
-        assert_eq!(parser.has_bytes(10, 5), false);
+        assert!(parser.has_bytes(2, 5));
+        assert!(!parser.has_bytes(10, 5));
     }
 
     #[test]
@@ -450,4 +692,220 @@ mod tests {
             other => panic!("Expected Error, got {:?}", other),
         }
     }
+
+    #[test]
+    fn test_display_nested_array() {
+        let value = RespValue::Array(Some(vec![
+            RespValue::BulkString(Some(b"a".to_vec())),
+            RespValue::Array(Some(vec![
+                RespValue::Integer(1),
+                RespValue::BulkString(None),
+            ])),
+            RespValue::Error("ERR boom".to_string()),
+        ]));
+
+        assert_eq!(
+            value.to_string(),
+            "1) \"a\"\n2) 1) (integer) 1\n   2) (nil)\n3) (error) ERR boom"
+        );
+    }
+
+    #[test]
+    fn test_display_escapes_binary_bulk_string() {
+        let value = RespValue::BulkString(Some(b"hi\n\xff\"".to_vec()));
+        assert_eq!(value.to_string(), r#""hi\n\xff\"""#);
+    }
+
+    #[test]
+    fn test_parse_inline_command() {
+        let mut parser = RespParser::new();
+        parser.feed(b"SET key \"hello world\\n\" 'it\\'s'\r\nPING");
+        let expected = RespValue::Array(Some(vec![
+            RespValue::BulkString(Some(b"SET".to_vec())),
+            RespValue::BulkString(Some(b"key".to_vec())),
+            RespValue::BulkString(Some(b"hello world\n".to_vec())),
+            RespValue::BulkString(Some(b"it's".to_vec())),
+        ]));
+        assert_eq!(parser.parse(), ParseResult::Complete(expected, 33));
+
+        parser.consume(33);
+        assert_eq!(parser.parse(), ParseResult::Incomplete);
+    }
+
+    #[test]
+    fn test_parse_inline_unbalanced_quotes() {
+        let mut parser = RespParser::new();
+        parser.feed(b"SET key \"oops\r\n");
+        assert_eq!(
+            parser.parse(),
+            ParseResult::Error("ERR Protocol error: unbalanced quotes in request".to_string())
+        );
+    }
+
+    #[test]
+    fn test_parse_inline_too_big_without_newline() {
+        let mut parser = RespParser::new();
+        parser.feed(&vec![b'a'; 70 * 1024]);
+        assert_eq!(
+            parser.parse(),
+            ParseResult::Error("ERR Protocol error: too big inline request".to_string())
+        );
+
+        let mut parser = RespParser::new();
+        parser.set_max_inline_size(16);
+        parser.feed(b"GET a-rather-long-key");
+        assert!(matches!(parser.parse(), ParseResult::Error(_)));
+    }
+
+    #[test]
+    fn test_reset_recovers_after_protocol_error() {
+        let mut parser = RespParser::new();
+        parser.feed(b"*1\r\n$4\r\nPINGxx*1\r\n$4\r\nPING\r\n");
+        assert!(matches!(parser.parse(), ParseResult::Error(_)));
+
+        parser.reset();
+        assert!(!parser.has_data());
+        parser.feed(b"*1\r\n$4\r\nPING\r\n");
+        let expected = RespValue::Array(Some(vec![RespValue::BulkString(Some(b"PING".to_vec()))]));
+        assert_eq!(parser.parse(), ParseResult::Complete(expected, 14));
+    }
+
+    #[test]
+    fn test_array_header_over_multibulk_limit_is_rejected() {
+        let mut parser = RespParser::new();
+        parser.feed(b"*1048577\r\n");
+        assert_eq!(
+            parser.parse(),
+            ParseResult::Error("ERR Protocol error: invalid multibulk length".to_string())
+        );
+
+        let mut parser = RespParser::new();
+        parser.set_max_multibulk_len(2);
+        parser.feed(b"*3\r\n");
+        assert!(matches!(parser.parse(), ParseResult::Error(_)));
+        parser.reset();
+        parser.feed(b"*2\r\n$1\r\na\r\n$1\r\nb\r\n");
+        assert!(matches!(parser.parse(), ParseResult::Complete(_, 18)));
+    }
+
+    #[test]
+    fn test_large_array_header_trickled_in_parses_once_complete() {
+        let mut parser = RespParser::new();
+        parser.feed(b"*1048576\r\n");
+        for _ in 0..100 {
+            assert_eq!(parser.parse(), ParseResult::Incomplete);
+            parser.feed(b"$1\r\n");
+            assert_eq!(parser.parse(), ParseResult::Incomplete);
+            parser.feed(b"a\r\n");
+        }
+
+        // Past the pre-allocation cap, the array still grows to fit.
+        let count = super::MAX_PREALLOCATED_ELEMENTS + 1;
+        let mut parser = RespParser::new();
+        parser.feed(format!("*{}\r\n", count).as_bytes());
+        for _ in 0..count {
+            parser.feed(b"$1\r\na\r\n");
+        }
+        match parser.parse() {
+            ParseResult::Complete(RespValue::Array(Some(elements)), _) => {
+                assert_eq!(elements.len(), count)
+            }
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_blank_inline_lines_are_skipped_without_a_reply() {
+        let mut parser = RespParser::new();
+        parser.feed(b"\r\n");
+        assert_eq!(parser.parse(), ParseResult::Incomplete);
+        assert!(!parser.has_data());
+
+        parser.feed(b"\n  \r\nPING\r\n\r\n*1\r\n$4\r\nPING\r\n");
+        let ping = RespValue::Array(Some(vec![RespValue::BulkString(Some(b"PING".to_vec()))]));
+        assert_eq!(parser.parse(), ParseResult::Complete(ping.clone(), 6));
+        parser.consume(6);
+        assert_eq!(parser.parse(), ParseResult::Complete(ping, 14));
+        parser.consume(14);
+        assert!(!parser.has_data());
+
+        // A blank line still waiting for its newline stays buffered.
+        parser.feed(b"  ");
+        assert_eq!(parser.parse(), ParseResult::Incomplete);
+        parser.feed(b"\r\n");
+        assert!(!parser.has_data());
+    }
+
+    #[test]
+    fn test_negative_array_lengths() {
+        let mut parser = RespParser::new();
+        parser.feed(b"*-1\r\n");
+        assert_eq!(
+            parser.parse(),
+            ParseResult::Complete(RespValue::Array(None), 5)
+        );
+
+        let mut parser = RespParser::new();
+        parser.feed(b"*-2\r\n");
+        assert_eq!(
+            parser.parse(),
+            ParseResult::Error("ERR Protocol error: invalid multibulk length".to_string())
+        );
+    }
+
+    #[test]
+    fn test_command_fed_one_byte_at_a_time() {
+        let frame = b"*1\r\n$4\r\nPING\r\n";
+        let mut parser = RespParser::new();
+        for (i, byte) in frame.iter().enumerate() {
+            parser.feed(&[*byte]);
+            if i + 1 < frame.len() {
+                assert_eq!(
+                    parser.parse(),
+                    ParseResult::Incomplete,
+                    "after {} bytes",
+                    i + 1
+                );
+            }
+        }
+        assert_eq!(
+            parser.parse(),
+            ParseResult::Complete(
+                RespValue::Array(Some(vec![RespValue::BulkString(Some(b"PING".to_vec()))])),
+                frame.len()
+            )
+        );
+    }
+
+    #[test]
+    fn test_every_split_point_of_each_frame_type() {
+        let frames: &[&[u8]] = &[
+            b"+OK\r\n",
+            b":-42\r\n",
+            b"$-1\r\n",
+            b"$6\r\nfoo\r\nx\r\n",
+            b"*2\r\n*1\r\n:1\r\n$0\r\n\r\n",
+            b"PING hello\r\n",
+        ];
+        for frame in frames {
+            let mut whole = RespParser::new();
+            whole.feed(frame);
+            let expected = whole.parse();
+            assert!(matches!(expected, ParseResult::Complete(_, n) if n == frame.len()));
+
+            for split in 1..frame.len() {
+                let mut parser = RespParser::new();
+                parser.feed(&frame[..split]);
+                assert_eq!(
+                    parser.parse(),
+                    ParseResult::Incomplete,
+                    "{:?} split at {}",
+                    String::from_utf8_lossy(frame),
+                    split
+                );
+                parser.feed(&frame[split..]);
+                assert_eq!(parser.parse(), expected);
+            }
+        }
+    }
 }
@@ -0,0 +1,74 @@
+use std::sync::{Arc, Mutex};
+
+use crate::rng;
+
+#[derive(Clone)]
+pub struct Replication {
+    replid: Arc<Mutex<String>>,
+}
+
+impl Default for Replication {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Replication {
+    pub fn new() -> Self {
+        Self {
+            replid: Arc::new(Mutex::new(generate_id())),
+        }
+    }
+
+    pub fn replid(&self) -> String {
+        self.replid.lock().unwrap().clone()
+    }
+
+    pub fn change_replid(&self) {
+        *self.replid.lock().unwrap() = generate_id();
+    }
+
+    pub fn info(&self) -> String {
+        format!(
+            "# Replication\r\nrole:master\r\nconnected_slaves:0\r\nmaster_replid:{}\r\nmaster_repl_offset:0\r\n",
+            self.replid()
+        )
+    }
+}
+
+/// Random 40 hex character id, the format Redis uses for run and replication ids.
+pub fn generate_id() -> String {
+    (0..40)
+        .map(|_| char::from_digit(rng::next_usize(16) as u32, 16).unwrap())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_generate_id_is_40_hex_chars() {
+        let id = generate_id();
+        assert_eq!(id.len(), 40);
+        assert!(id.chars().all(|c| c.is_ascii_hexdigit()));
+    }
+
+    #[test]
+    fn test_replid_is_stable_until_changed() {
+        let replication = Replication::new();
+        let first = replication.replid();
+        assert_eq!(replication.replid(), first);
+
+        replication.change_replid();
+        assert_ne!(replication.replid(), first);
+    }
+
+    #[test]
+    fn test_replid_is_shared_between_clones() {
+        let replication = Replication::new();
+        let clone = replication.clone();
+        clone.change_replid();
+        assert_eq!(replication.replid(), clone.replid());
+    }
+}
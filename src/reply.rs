@@ -0,0 +1,191 @@
+/// Logical shape of a reply, encoded for the protocol the connection speaks.
+///
+/// RESP3 has native map, double and null frames. RESP2 clients get the
+/// compatible encoding: a flat key/value array, a bulk string and `$-1`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Reply {
+    /// Text only, since `encode` builds a `String`. Binary values are written
+    /// as raw bytes by their command handlers instead.
+    Bulk(String),
+    Integer(i64),
+    Double(f64),
+    Array(Vec<Reply>),
+    Map(Vec<(Reply, Reply)>),
+    Null,
+}
+
+impl Reply {
+    pub fn encode(&self, protocol: u8) -> String {
+        let mut out = String::new();
+        self.encode_into(protocol, &mut out);
+        out
+    }
+
+    fn encode_into(&self, protocol: u8, out: &mut String) {
+        match self {
+            Reply::Bulk(text) => out.push_str(&format!("${}\r\n{}\r\n", text.len(), text)),
+            Reply::Integer(n) => out.push_str(&format!(":{}\r\n", n)),
+            Reply::Double(d) if protocol >= 3 => {
+                out.push_str(&format!(",{}\r\n", format_score(*d)))
+            }
+            Reply::Double(d) => {
+                let d = format_score(*d);
+                out.push_str(&format!("${}\r\n{}\r\n", d.len(), d));
+            }
+            Reply::Array(items) => {
+                out.push_str(&format!("*{}\r\n", items.len()));
+                for item in items {
+                    item.encode_into(protocol, out);
+                }
+            }
+            Reply::Map(pairs) => {
+                if protocol >= 3 {
+                    out.push_str(&format!("%{}\r\n", pairs.len()));
+                } else {
+                    out.push_str(&format!("*{}\r\n", pairs.len() * 2));
+                }
+                for (key, value) in pairs {
+                    key.encode_into(protocol, out);
+                    value.encode_into(protocol, out);
+                }
+            }
+            Reply::Null if protocol >= 3 => out.push_str("_\r\n"),
+            Reply::Null => out.push_str("$-1\r\n"),
+        }
+    }
+}
+
+/// Formats a score the way Redis does: the shortest digits that round-trip,
+/// laid out like `%.17g`. Integral values have no decimal point, large and
+/// tiny ones switch to exponent form, and infinities are `inf`/`-inf`.
+pub fn format_score(f: f64) -> String {
+    if f.is_nan() {
+        return "nan".to_string();
+    }
+    if f.is_infinite() {
+        return if f > 0.0 { "inf" } else { "-inf" }.to_string();
+    }
+
+    // `{:e}` yields the shortest round-tripping digits, e.g. `-3.14e0`.
+    let sci = format!("{:e}", f);
+    let (mantissa, exponent) = sci.split_once('e').unwrap();
+    let exponent: i32 = exponent.parse().unwrap();
+
+    if !(-4..17).contains(&exponent) {
+        let sign = if exponent < 0 { '-' } else { '+' };
+        return format!("{}e{}{:02}", mantissa, sign, exponent.abs());
+    }
+
+    let (sign, mantissa) = match mantissa.strip_prefix('-') {
+        Some(rest) => ("-", rest),
+        None => ("", mantissa),
+    };
+    let digits = mantissa.replace('.', "");
+    let formatted = if exponent < 0 {
+        format!("0.{}{}", "0".repeat((-exponent - 1) as usize), digits)
+    } else {
+        let int_len = exponent as usize + 1;
+        if digits.len() <= int_len {
+            format!("{}{}", digits, "0".repeat(int_len - digits.len()))
+        } else {
+            format!("{}.{}", &digits[..int_len], &digits[int_len..])
+        }
+    };
+    format!("{}{}", sign, formatted)
+}
+
+/// Renders command arguments the way MONITOR and SLOWLOG show them: each one
+/// double quoted, with quotes, backslashes and non-printable bytes escaped.
+pub fn quote_argv(args: &[&[u8]]) -> String {
+    args.iter()
+        .map(|arg| quote_arg(arg))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn quote_arg(arg: &[u8]) -> String {
+    let mut out = String::from("\"");
+    for &byte in arg {
+        match byte {
+            b'\\' => out.push_str("\\\\"),
+            b'"' => out.push_str("\\\""),
+            b'\n' => out.push_str("\\n"),
+            b'\r' => out.push_str("\\r"),
+            b'\t' => out.push_str("\\t"),
+            0x07 => out.push_str("\\a"),
+            0x08 => out.push_str("\\b"),
+            b if b.is_ascii_graphic() || b == b' ' => out.push(b as char),
+            b => out.push_str(&format!("\\x{:02x}", b)),
+        }
+    }
+    out.push('"');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hgetall_reply() -> Reply {
+        Reply::Map(vec![
+            (
+                Reply::Bulk("name".to_string()),
+                Reply::Bulk("bob".to_string()),
+            ),
+            (
+                Reply::Bulk("age".to_string()),
+                Reply::Bulk("42".to_string()),
+            ),
+        ])
+    }
+
+    #[test]
+    fn test_map_is_flat_array_in_resp2() {
+        assert_eq!(
+            hgetall_reply().encode(2),
+            "*4\r\n$4\r\nname\r\n$3\r\nbob\r\n$3\r\nage\r\n$2\r\n42\r\n"
+        );
+    }
+
+    #[test]
+    fn test_map_is_native_in_resp3() {
+        assert_eq!(
+            hgetall_reply().encode(3),
+            "%2\r\n$4\r\nname\r\n$3\r\nbob\r\n$3\r\nage\r\n$2\r\n42\r\n"
+        );
+    }
+
+    #[test]
+    fn test_double_and_null_per_protocol() {
+        assert_eq!(Reply::Double(1.5).encode(2), "$3\r\n1.5\r\n");
+        assert_eq!(Reply::Double(1.5).encode(3), ",1.5\r\n");
+        assert_eq!(Reply::Double(f64::NEG_INFINITY).encode(3), ",-inf\r\n");
+        assert_eq!(Reply::Null.encode(2), "$-1\r\n");
+        assert_eq!(Reply::Null.encode(3), "_\r\n");
+    }
+
+    #[test]
+    #[allow(clippy::approx_constant)]
+    fn test_format_score_matches_redis() {
+        assert_eq!(format_score(3.0), "3");
+        assert_eq!(format_score(3.14), "3.14");
+        assert_eq!(format_score(-2.5), "-2.5");
+        assert_eq!(format_score(f64::INFINITY), "inf");
+        assert_eq!(format_score(f64::NEG_INFINITY), "-inf");
+        assert_eq!(format_score(0.1 + 0.2), "0.30000000000000004");
+        assert_eq!(format_score(1234567890123456.0), "1234567890123456");
+        assert_eq!(format_score(1e17), "1e+17");
+        assert_eq!(format_score(0.0001), "0.0001");
+        assert_eq!(format_score(1.5e-7), "1.5e-07");
+        assert_eq!(format_score(0.0), "0");
+    }
+
+    #[test]
+    fn test_quote_argv_escapes_like_redis() {
+        let args: &[&[u8]] = &[b"set", b"a key", b"say \"hi\"", b"line\n", b"\x01\xff"];
+        assert_eq!(
+            quote_argv(args),
+            r#""set" "a key" "say \"hi\"" "line\n" "\x01\xff""#
+        );
+    }
+}
@@ -0,0 +1,74 @@
+use std::cell::Cell;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+
+thread_local! {
+    static STATE: Cell<u64> = Cell::new(seed());
+}
+
+// RandomState is keyed from the OS once per thread, which gives every thread
+// its own starting point without pulling in a dependency.
+fn seed() -> u64 {
+    let seed = RandomState::new().build_hasher().finish();
+    if seed == 0 {
+        0x9E37_79B9_7F4A_7C15
+    } else {
+        seed
+    }
+}
+
+/// xorshift64* step over the thread-local state.
+pub fn next_u64() -> u64 {
+    STATE.with(|state| {
+        let mut x = state.get();
+        x ^= x >> 12;
+        x ^= x << 25;
+        x ^= x >> 27;
+        state.set(x);
+        x.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    })
+}
+
+/// Returns a value in `0..bound`. `bound` must be non-zero.
+pub fn next_usize(bound: usize) -> usize {
+    assert!(bound > 0, "next_usize called with zero bound");
+
+    // Reject the tail of the u64 range so every value is equally likely.
+    let bound = bound as u64;
+    let zone = u64::MAX - (u64::MAX % bound);
+    loop {
+        let x = next_u64();
+        if x < zone {
+            return (x % bound) as usize;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_next_usize_stays_within_bound() {
+        for _ in 0..10_000 {
+            assert!(next_usize(7) < 7);
+        }
+        assert_eq!(next_usize(1), 0);
+    }
+
+    #[test]
+    fn test_next_usize_hits_every_element() {
+        let mut hits = [0usize; 10];
+        for _ in 0..100_000 {
+            hits[next_usize(10)] += 1;
+        }
+        assert!(hits.iter().all(|&h| h > 0), "hits: {:?}", hits);
+    }
+
+    #[test]
+    fn test_successive_calls_differ() {
+        let first = next_u64();
+        let second = next_u64();
+        assert_ne!(first, second);
+    }
+}
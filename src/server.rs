@@ -1,38 +1,139 @@
 use std::{
-    io::{Read, Write},
-    net::{TcpListener, TcpStream},
-    thread,
+    io::{self, BufWriter, Read, Write},
+    net::{Shutdown, TcpListener, TcpStream},
+    sync::{
+        mpsc::{self, Receiver, RecvTimeoutError, Sender, TryRecvError},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
 };
 
+use socket2::{SockRef, TcpKeepalive};
+
 use crate::{
-    command::handle_command,
-    parser::{ParseResult, RespParser},
+    clock::{Clock, SystemClock},
+    command::handle_command_into,
+    command_table,
+    config::Config,
+    connection::{ClientRegistry, ConnectionState, PushSink},
+    parser::{ParseResult, RespParser, RespValue},
+    replication::{generate_id, Replication},
+    stats::CommandStats,
     storage::Storage,
 };
 
+pub const DATABASES: usize = 16;
+
+/// How often `run_with_shutdown` checks for a shutdown signal while no
+/// connection is arriving.
+const ACCEPT_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// How often the active expiry sweeper wakes, Redis' default hz of 10.
+const ACTIVE_EXPIRE_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Keys with a TTL examined per active expiry sample, as in Redis.
+const ACTIVE_EXPIRE_SAMPLE: usize = 20;
+
+/// Most samples taken from one database per wake-up.
+const ACTIVE_EXPIRE_MAX_ROUNDS: usize = 16;
+
+/// How long `run_with_shutdown` lets open connections finish on their own
+/// before closing them.
+pub const SHUTDOWN_GRACE_PERIOD: Duration = Duration::from_secs(1);
+
+/// State shared by every connection of a running server.
+#[derive(Clone)]
+pub struct ServerState {
+    pub databases: Vec<Storage>,
+    pub replication: Replication,
+    pub clients: ClientRegistry,
+    pub config: Config,
+    pub command_stats: CommandStats,
+    /// Identity reported by CLUSTER MYID and CLUSTER NODES.
+    pub node_id: String,
+}
+
+impl Default for ServerState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ServerState {
+    pub fn new() -> Self {
+        Self::new_with_clock(Arc::new(SystemClock))
+    }
+
+    /// Server state whose databases all expire keys by `clock`.
+    pub fn new_with_clock(clock: Arc<dyn Clock>) -> Self {
+        Self {
+            databases: (0..DATABASES)
+                .map(|_| Storage::new_with_clock(clock.clone()))
+                .collect(),
+            replication: Replication::new(),
+            clients: ClientRegistry::new(),
+            config: Config::new(),
+            command_stats: CommandStats::new(),
+            node_id: generate_id(),
+        }
+    }
+
+    pub fn db(&self, index: usize) -> &Storage {
+        &self.databases[index]
+    }
+
+    pub fn stats_info(&self) -> String {
+        let expired_keys: u64 = self.databases.iter().map(Storage::expired_keys).sum();
+        format!("# Stats\r\nexpired_keys:{}\r\n", expired_keys)
+    }
+
+    /// One line per non-empty database, like Redis' `# Keyspace` section.
+    pub fn keyspace_info(&self) -> String {
+        let mut info = "# Keyspace\r\n".to_string();
+        for (index, db) in self.databases.iter().enumerate() {
+            let (keys, expires) = db.key_counts();
+            if keys > 0 {
+                info.push_str(&format!(
+                    "db{}:keys={},expires={},avg_ttl=0\r\n",
+                    index, keys, expires
+                ));
+            }
+        }
+        info
+    }
+}
+
 pub struct RedisServer {
     addr: String,
-    storage: Storage,
+    state: ServerState,
 }
 
 impl RedisServer {
     pub fn new(addr: impl Into<String>) -> Self {
         Self {
             addr: addr.into(),
-            storage: Storage::new(),
+            state: ServerState::new(),
         }
     }
 
+    /// Replaces the default configuration, for settings only given at startup.
+    pub fn with_config(mut self, config: Config) -> Self {
+        self.state.config = config;
+        self
+    }
+
     pub fn run(&self) -> std::io::Result<()> {
         let listener = TcpListener::bind(&self.addr)?;
         println!("Redis server listening on {}", self.addr);
+        let _stop_active_expiry = self.spawn_active_expiry();
 
         for stream in listener.incoming() {
             match stream {
                 Ok(stream) => {
-                    let storage_clone = self.storage.clone();
+                    let state_clone = self.state.clone();
                     thread::spawn(move || {
-                        handle_connection(stream, storage_clone);
+                        handle_connection(stream, state_clone);
                     });
                 }
                 Err(e) => {
@@ -43,18 +144,101 @@ impl RedisServer {
 
         Ok(())
     }
+
+    /// Like `run`, but stops accepting connections once a message arrives on
+    /// `shutdown` or its sender is dropped. Open connections then get
+    /// `SHUTDOWN_GRACE_PERIOD` to finish before their sockets are closed.
+    /// A connection blocked inside a command sees its socket closed, but its
+    /// thread only exits when that command returns.
+    pub fn run_with_shutdown(&self, shutdown: Receiver<()>) -> io::Result<()> {
+        let listener = TcpListener::bind(&self.addr)?;
+        // Polled so the loop can notice the shutdown signal between accepts.
+        listener.set_nonblocking(true)?;
+        println!("Redis server listening on {}", self.addr);
+        let _stop_active_expiry = self.spawn_active_expiry();
+
+        let mut connections: Vec<(TcpStream, JoinHandle<()>)> = Vec::new();
+        while let Err(TryRecvError::Empty) = shutdown.try_recv() {
+            match listener.accept() {
+                Ok((stream, _)) => match self.spawn_connection(stream) {
+                    Ok(connection) => connections.push(connection),
+                    Err(e) => println!("error: {}", e),
+                },
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    thread::sleep(ACCEPT_POLL_INTERVAL)
+                }
+                Err(e) => println!("error: {}", e),
+            }
+            connections.retain(|(_, handle)| !handle.is_finished());
+        }
+        drop(listener);
+
+        let deadline = Instant::now() + SHUTDOWN_GRACE_PERIOD;
+        while Instant::now() < deadline && connections.iter().any(|(_, h)| !h.is_finished()) {
+            thread::sleep(ACCEPT_POLL_INTERVAL);
+        }
+        for (stream, _) in connections {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+        Ok(())
+    }
+
+    /// Starts the active expiry sweeper, which runs until the returned
+    /// sender is dropped.
+    fn spawn_active_expiry(&self) -> Sender<()> {
+        let (stop_tx, stop_rx) = mpsc::channel();
+        let databases = self.state.databases.clone();
+        thread::spawn(move || active_expiry(&databases, stop_rx));
+        stop_tx
+    }
+
+    /// Serves `stream` on its own thread, returning a handle to close it with.
+    fn spawn_connection(&self, stream: TcpStream) -> io::Result<(TcpStream, JoinHandle<()>)> {
+        // Accepted sockets may inherit the listener's non-blocking mode.
+        stream.set_nonblocking(false)?;
+        let closer = stream.try_clone()?;
+        let state = self.state.clone();
+        let handle = thread::spawn(move || handle_connection(stream, state));
+        Ok((closer, handle))
+    }
 }
 
-fn handle_connection(mut stream: TcpStream, storage: Storage) -> () {
-    println!("accepted new connection");
+fn handle_connection(mut stream: TcpStream, state: ServerState) {
+    let mut conn = ConnectionState::new();
+    conn.peer_addr = stream.peer_addr().ok();
+    // Unregisters the connection on every exit path below.
+    let guard = state.clients.register(conn.peer_addr);
+    conn.id = guard.id();
+    let id = conn.id;
+    let log = move |message: &str| println!("[conn {}] {}", id, message);
+    log("accepted new connection");
+    configure_socket(&stream, state.config.get().tcp_keepalive, &log);
+
+    // Replies to a pipelined batch are buffered and written together once
+    // the parser runs dry, so a slow reader costs one write_all per batch.
+    // The writer is shared with other connections for tracking pushes.
+    let writer = match stream.try_clone() {
+        Ok(clone) => Arc::new(Mutex::new(BufWriter::new(clone))),
+        Err(e) => {
+            log(&format!("failed to clone stream: {}", e));
+            return;
+        }
+    };
+
+    conn.push = Some(PushSink(writer.clone()));
+    // Like Redis, a connection made while no password is required stays
+    // authenticated if one is set later.
+    conn.authenticated = state.config.requirepass().is_none();
 
     let mut parser = RespParser::new();
     let mut buffer = [0; 512];
+    // Reused for every reply on this connection and never shared with another.
+    let mut reply = Vec::new();
 
     loop {
         match stream.read(&mut buffer) {
             Ok(0) => {
-                println!("connection closed");
+                log("connection closed");
                 break;
             }
             Ok(n) => {
@@ -63,28 +247,263 @@ fn handle_connection(mut stream: TcpStream, storage: Storage) -> () {
                 loop {
                     match parser.parse() {
                         ParseResult::Complete(value, consumed) => {
-                            let response = handle_command(&value, &storage);
-                            if let Err(e) = stream.write_all(response.as_bytes()) {
-                                println!("failed to write: {}", e);
+                            // Don't hold earlier replies back while this one blocks.
+                            if is_blocking(&value) {
+                                if let Err(e) = writer.lock().unwrap().flush() {
+                                    log(&format!("failed to write: {}", e));
+                                    return;
+                                }
+                            }
+
+                            reply.clear();
+                            handle_command_into(&value, &state, &mut conn, &mut reply);
+                            if let Err(e) = writer.lock().unwrap().write_all(&reply) {
+                                log(&format!("failed to write: {}", e));
                                 return;
                             }
 
                             parser.consume(consumed);
                         }
                         ParseResult::Incomplete => {
+                            if let Err(e) = writer.lock().unwrap().flush() {
+                                log(&format!("failed to write: {}", e));
+                                return;
+                            }
                             break;
                         }
                         ParseResult::Error(e) => {
-                            let _ = stream.write_all(e.as_bytes());
+                            let mut writer = writer.lock().unwrap();
+                            let _ = writer.write_all(format!("-{}\r\n", e).as_bytes());
+                            let _ = writer.flush();
                             return;
                         }
                     }
                 }
             }
             Err(e) => {
-                println!("Error reading stream: {}", e);
+                log(&format!("error reading stream: {}", e));
                 break;
             }
         }
     }
 }
+
+/// Removes expired keys that nobody reads, until `stop` gets a message or
+/// its sender is dropped. Like Redis, a database is sampled again at once
+/// while more than a quarter of a sample turned out expired.
+fn active_expiry(databases: &[Storage], stop: Receiver<()>) {
+    while let Err(RecvTimeoutError::Timeout) = stop.recv_timeout(ACTIVE_EXPIRE_INTERVAL) {
+        for db in databases {
+            for _ in 0..ACTIVE_EXPIRE_MAX_ROUNDS {
+                if db.clear_expired_sample(ACTIVE_EXPIRE_SAMPLE) <= ACTIVE_EXPIRE_SAMPLE / 4 {
+                    break;
+                }
+            }
+        }
+    }
+}
+
+/// Turns off Nagle's algorithm, as Redis does, so small replies aren't
+/// held back, and enables keepalive probes after `keepalive_secs` idle
+/// seconds unless it is 0. A socket option that can't be set is logged and
+/// the connection carries on without it.
+fn configure_socket(stream: &TcpStream, keepalive_secs: u64, log: &impl Fn(&str)) {
+    if let Err(e) = stream.set_nodelay(true) {
+        log(&format!("warning: failed to set TCP_NODELAY: {}", e));
+    }
+    if keepalive_secs > 0 {
+        let keepalive = TcpKeepalive::new().with_time(Duration::from_secs(keepalive_secs));
+        if let Err(e) = SockRef::from(stream).set_tcp_keepalive(&keepalive) {
+            log(&format!("warning: failed to enable TCP keepalive: {}", e));
+        }
+    }
+}
+
+fn is_blocking(value: &RespValue) -> bool {
+    let name = match value {
+        RespValue::Array(Some(elements)) => match elements.first() {
+            Some(RespValue::BulkString(Some(name))) => String::from_utf8_lossy(name),
+            _ => return false,
+        },
+        _ => return false,
+    };
+
+    command_table::lookup(&name).is_some_and(|spec| spec.flags.contains(&"blocking"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::TestClock;
+
+    #[test]
+    fn test_pipelined_replies_reach_slow_reader_in_order() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            handle_connection(stream, ServerState::new());
+        });
+
+        let payloads: Vec<String> = (0..200)
+            .map(|i| format!("{:0>4}", i).repeat(2048))
+            .collect();
+        let mut expected = Vec::new();
+        let mut request = Vec::new();
+        for payload in &payloads {
+            request.extend_from_slice(
+                format!("*2\r\n$4\r\nECHO\r\n${}\r\n{}\r\n", payload.len(), payload).as_bytes(),
+            );
+            expected.extend_from_slice(format!("${}\r\n{}\r\n", payload.len(), payload).as_bytes());
+        }
+
+        let mut reader = TcpStream::connect(addr).unwrap();
+        let mut sender = reader.try_clone().unwrap();
+        thread::spawn(move || sender.write_all(&request).unwrap());
+
+        let mut received = Vec::new();
+        let mut chunk = [0; 4096];
+        while received.len() < expected.len() {
+            thread::sleep(Duration::from_micros(200));
+            let n = reader.read(&mut chunk).unwrap();
+            assert!(n > 0, "connection closed early");
+            received.extend_from_slice(&chunk[..n]);
+        }
+
+        assert!(received == expected);
+    }
+
+    #[test]
+    fn test_sequential_connections_get_consecutive_ids() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let state = ServerState::new();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let state = state.clone();
+                thread::spawn(move || handle_connection(stream.unwrap(), state));
+            }
+        });
+
+        let client_id = || {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            stream
+                .write_all(b"*2\r\n$6\r\nCLIENT\r\n$2\r\nID\r\n")
+                .unwrap();
+            let mut reply = [0; 32];
+            let n = stream.read(&mut reply).unwrap();
+            let reply = std::str::from_utf8(&reply[..n]).unwrap();
+            reply[1..reply.len() - 2].parse::<u64>().unwrap()
+        };
+
+        let first = client_id();
+        let second = client_id();
+        assert_eq!(second, first + 1);
+    }
+
+    #[test]
+    fn test_debug_sleep_does_not_hold_up_other_connections() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut state = ServerState::new();
+        state.config = Config::with_enable_debug_command(true);
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let state = state.clone();
+                thread::spawn(move || handle_connection(stream.unwrap(), state));
+            }
+        });
+
+        let mut sleeper = TcpStream::connect(addr).unwrap();
+        sleeper
+            .write_all(b"*3\r\n$5\r\nDEBUG\r\n$5\r\nSLEEP\r\n$1\r\n1\r\n")
+            .unwrap();
+        thread::sleep(Duration::from_millis(50));
+
+        let started = std::time::Instant::now();
+        let mut other = TcpStream::connect(addr).unwrap();
+        other.write_all(b"*1\r\n$4\r\nPING\r\n").unwrap();
+        let mut reply = [0; 16];
+        let n = other.read(&mut reply).unwrap();
+        assert_eq!(&reply[..n], b"+PONG\r\n");
+        assert!(started.elapsed() < Duration::from_millis(500));
+
+        let n = sleeper.read(&mut reply).unwrap();
+        assert_eq!(&reply[..n], b"+OK\r\n");
+    }
+
+    #[test]
+    fn test_run_with_shutdown_returns_and_closes_connections() {
+        // Find a free port for the server to bind by name.
+        let addr = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let (shutdown_tx, shutdown_rx) = mpsc::channel();
+        let (done_tx, done_rx) = mpsc::channel();
+        thread::spawn(move || {
+            let result = RedisServer::new(addr.to_string()).run_with_shutdown(shutdown_rx);
+            done_tx.send(result.is_ok()).unwrap();
+        });
+
+        let mut client = loop {
+            match TcpStream::connect(addr) {
+                Ok(stream) => break stream,
+                Err(_) => thread::sleep(Duration::from_millis(10)),
+            }
+        };
+        client.write_all(b"*1\r\n$4\r\nPING\r\n").unwrap();
+        let mut reply = [0; 16];
+        let n = client.read(&mut reply).unwrap();
+        assert_eq!(&reply[..n], b"+PONG\r\n");
+
+        shutdown_tx.send(()).unwrap();
+        let returned = done_rx.recv_timeout(SHUTDOWN_GRACE_PERIOD + Duration::from_secs(1));
+        assert_eq!(returned, Ok(true));
+
+        // The idle client was closed once the grace period ran out.
+        assert_eq!(client.read(&mut reply).unwrap(), 0);
+        assert!(TcpStream::connect(addr).is_err());
+    }
+
+    #[test]
+    fn test_configure_socket_sets_nodelay_and_keepalive() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let _client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        let warnings = Mutex::new(Vec::new());
+        let log = |message: &str| warnings.lock().unwrap().push(message.to_string());
+
+        configure_socket(&stream, 0, &log);
+        assert!(stream.nodelay().unwrap());
+        assert!(!SockRef::from(&stream).keepalive().unwrap());
+
+        configure_socket(&stream, 60, &log);
+        assert!(SockRef::from(&stream).keepalive().unwrap());
+        assert!(warnings.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_active_expiry_removes_keys_nobody_reads() {
+        let clock = TestClock::new();
+        let state = ServerState::new_with_clock(Arc::new(clock.clone()));
+        for i in 0..50 {
+            state
+                .db(0)
+                .set_px(format!("key:{}", i), b"v".to_vec(), 1)
+                .unwrap();
+        }
+        state.db(0).set("persistent".to_string(), b"v".to_vec());
+        clock.advance(Duration::from_millis(5));
+
+        let (stop_tx, stop_rx) = mpsc::channel();
+        let databases = state.databases.clone();
+        let sweeper = thread::spawn(move || active_expiry(&databases, stop_rx));
+        thread::sleep(ACTIVE_EXPIRE_INTERVAL * 3);
+        drop(stop_tx);
+        sweeper.join().unwrap();
+
+        assert_eq!(state.db(0).key_counts(), (1, 0));
+        assert_eq!(state.db(0).expired_keys(), 50);
+    }
+}
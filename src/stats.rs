@@ -0,0 +1,72 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::command_table::COMMANDS;
+
+/// Per-command call counts and cumulative run time, indexed like
+/// `command_table::COMMANDS` so recording a call never takes a lock.
+#[derive(Clone)]
+pub struct CommandStats {
+    calls: Arc<[AtomicU64]>,
+    usec: Arc<[AtomicU64]>,
+}
+
+impl Default for CommandStats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CommandStats {
+    pub fn new() -> Self {
+        Self {
+            calls: COMMANDS.iter().map(|_| AtomicU64::new(0)).collect(),
+            usec: COMMANDS.iter().map(|_| AtomicU64::new(0)).collect(),
+        }
+    }
+
+    pub fn record(&self, index: usize, elapsed: Duration) {
+        self.calls[index].fetch_add(1, Ordering::Relaxed);
+        self.usec[index].fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    /// The `# Commandstats` INFO section, listing commands called at least once.
+    pub fn info(&self) -> String {
+        let mut out = String::from("# Commandstats\r\n");
+        for (i, spec) in COMMANDS.iter().enumerate() {
+            let calls = self.calls[i].load(Ordering::Relaxed);
+            if calls == 0 {
+                continue;
+            }
+            let usec = self.usec[i].load(Ordering::Relaxed);
+            out.push_str(&format!(
+                "cmdstat_{}:calls={},usec={},usec_per_call={:.2}\r\n",
+                spec.name,
+                calls,
+                usec,
+                usec as f64 / calls as f64
+            ));
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command_table;
+
+    #[test]
+    fn test_info_lists_called_commands_only() {
+        let stats = CommandStats::new();
+        let (get, _) = command_table::lookup_indexed("get").unwrap();
+        stats.record(get, Duration::from_micros(3));
+        stats.record(get, Duration::from_micros(4));
+
+        assert_eq!(
+            stats.info(),
+            "# Commandstats\r\ncmdstat_get:calls=2,usec=7,usec_per_call=3.50\r\n"
+        );
+    }
+}
@@ -1,9 +1,14 @@
-use std::collections::{HashMap, VecDeque};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use std::hash::{Hash, Hasher};
 use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 use std::sync::mpsc::{self, Sender};
-use std::sync::{Arc, Mutex};
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
-use std::{u128, u64};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::clock::{Clock, SystemClock};
+use crate::glob::glob_match;
 
 #[derive(Clone, Debug)]
 enum StoredData {
@@ -12,6 +17,49 @@ enum StoredData {
     Stream(Vec<Entry>),
 }
 
+/// When SET writes, from its NX and XX options.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SetCondition {
+    Always,
+    IfMissing,
+    IfExists,
+}
+
+/// The TTL SET leaves on the key it writes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SetTtl {
+    Clear,
+    Keep,
+    Millis(u64),
+}
+
+/// What a key holds, for commands that only work on one kind of value.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum DataType {
+    String,
+    List,
+    Stream,
+}
+
+impl StoredData {
+    fn data_type(&self) -> DataType {
+        match self {
+            StoredData::String(_) => DataType::String,
+            StoredData::List(_) => DataType::List,
+            StoredData::Stream(_) => DataType::Stream,
+        }
+    }
+
+    /// Name reported by TYPE and matched by SCAN's TYPE filter.
+    fn type_name(&self) -> &'static str {
+        match self.data_type() {
+            DataType::String => "string",
+            DataType::List => "list",
+            DataType::Stream => "stream",
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 #[allow(dead_code)]
 struct Entry {
@@ -19,7 +67,7 @@ struct Entry {
     values: HashMap<String, Vec<u8>>,
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
 struct EntryId {
     ms: u128,
     seq: u64,
@@ -36,12 +84,6 @@ impl EntryId {
     }
 }
 
-impl Default for EntryId {
-    fn default() -> Self {
-        Self { ms: 0, seq: 0 }
-    }
-}
-
 impl FromStr for EntryId {
     type Err = String;
 
@@ -89,6 +131,11 @@ enum IdSpec {
 struct StoredValue {
     data: StoredData,
     expired_at: Option<SystemTime>,
+    // Bumped from the storage-wide counter on every write, see Storage::version.
+    version: u64,
+    // Set when a string is modified in place (APPEND, SETRANGE), which makes
+    // Redis drop the int/embstr encoding for good.
+    raw: bool,
 }
 
 impl StoredValue {
@@ -96,6 +143,8 @@ impl StoredValue {
         Self {
             data,
             expired_at: None,
+            version: 0,
+            raw: false,
         }
     }
 
@@ -103,16 +152,129 @@ impl StoredValue {
         Self {
             data,
             expired_at: Some(expires_at),
+            version: 0,
+            raw: false,
         }
     }
 
-    fn is_expired(&self) -> bool {
+    fn is_expired(&self, now: SystemTime) -> bool {
         match self.expired_at {
-            Some(expire) => SystemTime::now() >= expire,
+            Some(expire) => now >= expire,
             _ => false,
         }
     }
 
+    /// Encoding Redis would pick for this value, as reported by OBJECT ENCODING.
+    /// A list element longer than `packed_threshold` needs a plain quicklist
+    /// node, so the list can't be a listpack.
+    fn encoding(&self, packed_threshold: usize) -> &'static str {
+        match &self.data {
+            StoredData::String(_) => self.string_encoding(),
+            StoredData::List(list)
+                if list.len() <= LIST_MAX_LISTPACK_SIZE
+                    && list.iter().all(|element| element.len() <= packed_threshold) =>
+            {
+                "listpack"
+            }
+            StoredData::List(_) => "quicklist",
+            StoredData::Stream(_) => self.data.type_name(),
+        }
+    }
+
+    /// `int` for canonical 64-bit integers, `embstr` for other strings of up
+    /// to 44 bytes and `raw` for anything longer or modified in place.
+    fn string_encoding(&self) -> &'static str {
+        let bytes = match &self.data {
+            StoredData::String(bytes) => bytes,
+            _ => return "",
+        };
+
+        if self.raw {
+            "raw"
+        } else if parse_strict_i64(bytes).is_some() {
+            "int"
+        } else if bytes.len() <= EMBSTR_MAX_BYTES {
+            "embstr"
+        } else {
+            "raw"
+        }
+    }
+
+    /// Whether Redis would point this key at its shared integer object
+    /// rather than allocate a value. Modifying the string in place copies it
+    /// out of the pool for good.
+    fn is_shared_integer(&self) -> bool {
+        match &self.data {
+            StoredData::String(bytes) if !self.raw => {
+                parse_strict_i64(bytes).is_some_and(|n| (0..SHARED_INTEGERS).contains(&n))
+            }
+            _ => false,
+        }
+    }
+
+    fn refcount(&self) -> i64 {
+        if self.is_shared_integer() {
+            SHARED_REFCOUNT
+        } else {
+            1
+        }
+    }
+
+    /// Modelled allocation size of the value, without its key.
+    fn memory_usage(&self) -> usize {
+        let sds = |bytes: &[u8]| bytes.len() + SDS_OVERHEAD_BYTES;
+        match &self.data {
+            _ if self.is_shared_integer() => 0,
+            StoredData::String(_) if self.string_encoding() == "int" => OBJECT_BYTES,
+            StoredData::String(bytes) => OBJECT_BYTES + sds(bytes),
+            // A listpack spends a byte of length prefix and one of backlen
+            // per element, plus a 7-byte header and terminator per node.
+            StoredData::List(list) => {
+                let nodes = list.len().div_ceil(LIST_MAX_LISTPACK_SIZE);
+                OBJECT_BYTES
+                    + nodes * 7
+                    + list.iter().map(|element| element.len() + 2).sum::<usize>()
+            }
+            StoredData::Stream(entries) => {
+                OBJECT_BYTES
+                    + entries
+                        .iter()
+                        .map(|entry| {
+                            OBJECT_BYTES
+                                + entry
+                                    .values
+                                    .iter()
+                                    .map(|(field, value)| sds(field.as_bytes()) + sds(value))
+                                    .sum::<usize>()
+                        })
+                        .sum::<usize>()
+            }
+        }
+    }
+
+    // Typed access for writes that went through `Storage::typed_mut`, which
+    // has already checked the type.
+    fn string_mut(&mut self) -> &mut Vec<u8> {
+        match &mut self.data {
+            StoredData::String(bytes) => bytes,
+            _ => unreachable!("type checked by typed_mut"),
+        }
+    }
+
+    fn list_mut(&mut self) -> &mut Vec<Vec<u8>> {
+        match &mut self.data {
+            StoredData::List(list) => list,
+            _ => unreachable!("type checked by typed_mut"),
+        }
+    }
+
+    fn stream_mut(&mut self) -> &mut Vec<Entry> {
+        match &mut self.data {
+            StoredData::Stream(entries) => entries,
+            _ => unreachable!("type checked by typed_mut"),
+        }
+    }
+
     fn as_string(&self) -> Option<&Vec<u8>> {
         match &self.data {
             StoredData::String(bytes) => Some(bytes),
@@ -121,314 +283,644 @@ impl StoredValue {
     }
 }
 
+/// A stream name with the entries read from it, as returned by XREAD.
+pub type StreamRead = (String, Vec<Vec<Vec<u8>>>);
+
+/// A list name with the elements popped from it, as returned by LMPOP.
+pub type ListPop = (String, Vec<Vec<u8>>);
+
+const WRONGTYPE: &str = "WRONGTYPE Operation against a key holding the wrong kind of value";
+const INVALID_EXPIRE: &str = "invalid expire time";
+const NOT_AN_INTEGER: &str = "ERR value is not an integer or out of range";
+const EMBSTR_MAX_BYTES: usize = 44;
+// list-max-listpack-size: lists are modelled as a quicklist of listpack nodes
+// holding up to this many elements each. One node is reported as listpack.
+const LIST_MAX_LISTPACK_SIZE: usize = 128;
+
+// stream-node-max-entries: stream entries per radix tree node.
+const STREAM_NODE_MAX_ENTRIES: usize = 100;
+// OBJ_SHARED_INTEGERS: integers below this are one shared, immortal object.
+const SHARED_INTEGERS: i64 = 10_000;
+const SHARED_REFCOUNT: i64 = i32::MAX as i64;
+// Allocation sizes MEMORY USAGE adds up: a dict entry, an object header and
+// the header plus terminator of an sds string.
+const DICT_ENTRY_BYTES: usize = 24;
+const OBJECT_BYTES: usize = 16;
+const SDS_OVERHEAD_BYTES: usize = 4;
+
+/// Elements larger than this get a plain quicklist node of their own.
+pub const DEFAULT_PACKED_THRESHOLD: usize = 1 << 30;
+
+/// How many keys an active expiry sample may walk past per key with a TTL
+/// it is asked to examine, so a mostly persistent keyspace stays cheap.
+const EXPIRE_SAMPLE_VISIT_FACTOR: usize = 10;
+
+/// The key map plus an index of every key by its SCAN position, so a SCAN
+/// step can seek to its cursor and only touch the keys it visits. Both share
+/// one allocation per key name.
+#[derive(Default)]
+struct Keyspace {
+    values: HashMap<Arc<str>, StoredValue>,
+    positions: BTreeSet<(u64, Arc<str>)>,
+    // Where the next active expiry sample starts, as a SCAN position.
+    expire_cursor: u64,
+    // Keys removed so far, including by flushes. See `KeyVersion::Missing`.
+    removals: u64,
+}
+
+impl Keyspace {
+    fn get(&self, key: &str) -> Option<&StoredValue> {
+        self.values.get(key)
+    }
+
+    fn get_mut(&mut self, key: &str) -> Option<&mut StoredValue> {
+        self.values.get_mut(key)
+    }
+
+    fn insert(&mut self, key: String, value: StoredValue) {
+        match self.values.get_mut(key.as_str()) {
+            Some(existing) => *existing = value,
+            None => {
+                let key: Arc<str> = key.into();
+                self.positions.insert((scan_position(&key), key.clone()));
+                self.values.insert(key, value);
+            }
+        }
+    }
+
+    fn get_or_insert_with(
+        &mut self,
+        key: String,
+        default: impl FnOnce() -> StoredValue,
+    ) -> &mut StoredValue {
+        if !self.values.contains_key(key.as_str()) {
+            self.insert(key.clone(), default());
+        }
+        self.values.get_mut(key.as_str()).unwrap()
+    }
+
+    fn remove(&mut self, key: &str) -> Option<StoredValue> {
+        let (key, value) = self.values.remove_entry(key)?;
+        self.positions.remove(&(scan_position(&key), key));
+        self.removals += 1;
+        Some(value)
+    }
+}
+
+/// A key's state as WATCH sees it. A missing key has no version of its own,
+/// so it is tracked by how many keys its database has removed: a key created
+/// and removed again in between still moves that count. So does removing any
+/// other key, which at worst aborts a transaction that could have run.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KeyVersion {
+    Exists(u64),
+    Missing { removals: u64 },
+}
+
 struct Waiter {
+    id: u64,
     keys: Vec<String>,
-    sender: Sender<(String, Vec<u8>)>,
+    from_left: bool,
+    count: usize,
+    sender: Sender<ListPop>,
 }
 
 #[derive(Clone)]
 pub struct Storage {
-    inner: Arc<Mutex<HashMap<String, StoredValue>>>,
+    inner: Arc<Mutex<Keyspace>>,
+    // Blocked list pops in arrival order. Scanning it for a key yields that
+    // key's waiters oldest first. Lock order: `waiters` before `inner`.
     waiters: Arc<Mutex<VecDeque<Waiter>>>,
+    next_waiter_id: Arc<AtomicU64>,
+    expired_keys: Arc<AtomicU64>,
+    next_version: Arc<AtomicU64>,
+    // Signalled on every XADD; waited on with the `inner` guard by XREAD BLOCK.
+    stream_added: Arc<Condvar>,
+    packed_threshold: Arc<AtomicUsize>,
+    // Every expiry is set and checked against this.
+    clock: Arc<dyn Clock>,
+}
+
+impl Default for Storage {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Storage {
     pub fn new() -> Self {
+        Self::new_with_clock(Arc::new(SystemClock))
+    }
+
+    /// Storage whose expiration runs on `clock`, e.g. a `TestClock`.
+    pub fn new_with_clock(clock: Arc<dyn Clock>) -> Self {
         Self {
-            inner: Arc::new(Mutex::new(HashMap::new())),
+            inner: Arc::new(Mutex::new(Keyspace::default())),
             waiters: Arc::new(Mutex::new(VecDeque::new())),
+            next_waiter_id: Arc::new(AtomicU64::new(0)),
+            expired_keys: Arc::new(AtomicU64::new(0)),
+            next_version: Arc::new(AtomicU64::new(1)),
+            stream_added: Arc::new(Condvar::new()),
+            packed_threshold: Arc::new(AtomicUsize::new(DEFAULT_PACKED_THRESHOLD)),
+            clock,
         }
     }
 
+    /// The current time on this storage's clock.
+    pub fn now(&self) -> SystemTime {
+        self.clock.now()
+    }
+
     pub fn set(&self, key: String, value: Vec<u8>) {
         let mut store = self.inner.lock().unwrap();
-        store.insert(key, StoredValue::new(StoredData::String(value)));
+        self.insert(&mut store, key, StoredValue::new(StoredData::String(value)));
     }
 
-    pub fn set_ex(&self, key: String, value: Vec<u8>, seconds: u64) {
-        let expires_at = SystemTime::now() + Duration::from_secs(seconds);
+    pub fn set_ex(&self, key: String, value: Vec<u8>, seconds: u64) -> Result<(), String> {
+        let milliseconds = seconds.checked_mul(1000).ok_or(INVALID_EXPIRE)?;
+        self.set_px(key, value, milliseconds)
+    }
+
+    /// SET with all its options, under one lock. Writes `value` only if
+    /// `condition` holds, and returns whether it did along with the old
+    /// string when `get` asks for it. With `get`, a key holding another type
+    /// is a WRONGTYPE error and nothing is written.
+    pub fn set_with(
+        &self,
+        key: String,
+        value: Vec<u8>,
+        condition: SetCondition,
+        ttl: SetTtl,
+        get: bool,
+    ) -> Result<(bool, Option<Vec<u8>>), String> {
+        let expires_at = match ttl {
+            SetTtl::Millis(milliseconds) => {
+                let milliseconds = i64::try_from(milliseconds).map_err(|_| INVALID_EXPIRE)?;
+                Some(self.expires_in(milliseconds)?)
+            }
+            SetTtl::Clear | SetTtl::Keep => None,
+        };
+
         let mut store = self.inner.lock().unwrap();
-        store.insert(
+        let existing = self.live_value(&mut store, &key);
+        let old = match &existing {
+            Some(stored_value) if get => Some(stored_value.as_string().cloned().ok_or(WRONGTYPE)?),
+            _ => None,
+        };
+        let write = match condition {
+            SetCondition::Always => true,
+            SetCondition::IfMissing => existing.is_none(),
+            SetCondition::IfExists => existing.is_some(),
+        };
+        if write {
+            let kept = match ttl {
+                SetTtl::Keep => existing.and_then(|stored_value| stored_value.expired_at),
+                _ => None,
+            };
+            let mut stored_value = StoredValue::new(StoredData::String(value));
+            stored_value.expired_at = expires_at.or(kept);
+            self.insert(&mut store, key, stored_value);
+        }
+        Ok((write, old))
+    }
+
+    /// Like `set_ex`, with the TTL moved by up to `jitter_pct` percent either
+    /// way so keys written in one burst don't all expire together.
+    pub fn set_ex_jittered(
+        &self,
+        key: String,
+        value: Vec<u8>,
+        seconds: u64,
+        jitter_pct: u64,
+        random: impl FnOnce() -> u64,
+    ) -> Result<(), String> {
+        let milliseconds = seconds.checked_mul(1000).ok_or(INVALID_EXPIRE)?;
+        self.set_px(
             key,
-            StoredValue::with_expiration(StoredData::String(value), expires_at),
-        );
+            value,
+            jittered_millis(milliseconds, jitter_pct, random()),
+        )
     }
 
-    pub fn set_px(&self, key: String, value: Vec<u8>, milliseconds: u64) {
-        let expires_at = SystemTime::now() + Duration::from_millis(milliseconds);
+    pub fn set_px(&self, key: String, value: Vec<u8>, milliseconds: u64) -> Result<(), String> {
+        let milliseconds = i64::try_from(milliseconds).map_err(|_| INVALID_EXPIRE)?;
+        let expires_at = self.expires_in(milliseconds)?;
         let mut store = self.inner.lock().unwrap();
-        store.insert(
+        self.insert(
+            &mut store,
             key,
             StoredValue::with_expiration(StoredData::String(value), expires_at),
         );
+        Ok(())
     }
 
-    pub fn get(&self, key: &str) -> Option<Vec<u8>> {
+    /// Sets a TTL of `milliseconds` on an existing key, returning whether the
+    /// key existed. A zero or negative TTL deletes the key straight away.
+    pub fn expire(&self, key: &str, milliseconds: i64) -> Result<bool, String> {
+        if milliseconds <= 0 {
+            return Ok(self.delete(key));
+        }
+
+        let expires_at = self.expires_in(milliseconds)?;
         let mut store = self.inner.lock().unwrap();
-        if let Some(stored_value) = store.get(key) {
-            if stored_value.is_expired() {
-                store.remove(key);
-                return None;
+        match self.live_value(&mut store, key) {
+            Some(stored_value) => {
+                stored_value.expired_at = Some(expires_at);
+                stored_value.version = self.bump_version();
+                Ok(true)
             }
-            return stored_value.as_string().cloned();
+            None => Ok(false),
         }
-        None
     }
 
-    pub fn rpush(&self, key: String, values: Vec<Vec<u8>>) -> Result<usize, String> {
+    /// Absolute expiry of `key` in Unix milliseconds, backing TTL, PTTL,
+    /// EXPIRETIME and PEXPIRETIME. `None` if the key is missing (an expired
+    /// key is purged first), `Some(None)` if it has no TTL.
+    pub fn expire_time(&self, key: &str) -> Option<Option<u64>> {
         let mut store = self.inner.lock().unwrap();
+        self.live_value(&mut store, key).map(|stored_value| {
+            stored_value.expired_at.map(|at| {
+                at.duration_since(UNIX_EPOCH)
+                    .map_or(0, |since_epoch| since_epoch.as_millis() as u64)
+            })
+        })
+    }
 
-        if let Some(stored_value) = store.get_mut(&key) {
-            if stored_value.is_expired() {
-                store.remove(&key);
-            } else {
-                match &mut stored_value.data {
-                    StoredData::List(list) => {
-                        list.extend(values);
-                        let len = list.len();
-                        drop(store);
-                        self.notify_waiters(&key);
-                        return Ok(len);
-                    }
-                    _ => {
-                        return Err(
-                            "WRONGTYPE Operation against a key holding the wrong kind of value"
-                                .to_string(),
-                        )
+    pub fn get(&self, key: &str) -> Option<Vec<u8>> {
+        let mut store = self.inner.lock().unwrap();
+        self.live_value(&mut store, key)
+            .and_then(|stored_value| stored_value.as_string().cloned())
+    }
+
+    /// Substring of the string at `key`, with Redis' inclusive and negative
+    /// index rules. A missing key reads as the empty string.
+    pub fn getrange(&self, key: &str, start: i64, end: i64) -> Result<Vec<u8>, String> {
+        let mut store = self.inner.lock().unwrap();
+        let bytes = match self.live_value(&mut store, key) {
+            None => return Ok(vec![]),
+            Some(stored_value) => match stored_value.as_string() {
+                Some(bytes) => bytes,
+                None => return Err(WRONGTYPE.to_string()),
+            },
+        };
+
+        let len = bytes.len() as i64;
+        if len == 0 || (start < 0 && end < 0 && start > end) {
+            return Ok(vec![]);
+        }
+
+        let start = if start < 0 {
+            (len + start).max(0)
+        } else {
+            start
+        };
+        let end = if end < 0 {
+            (len + end).max(0)
+        } else {
+            end.min(len - 1)
+        };
+        if start > end {
+            return Ok(vec![]);
+        }
+
+        Ok(bytes[start as usize..=end as usize].to_vec())
+    }
+
+    /// Overwrites the string at `key` from `offset` on, padding with zero bytes
+    /// as needed, and returns the new length. The caller is responsible for
+    /// keeping `offset + value.len()` within proto-max-bulk-len.
+    pub fn setrange(&self, key: String, offset: usize, value: &[u8]) -> Result<usize, String> {
+        self.typed_mut(&key, DataType::String, |store| match store.get_mut(&key) {
+            Some(stored_value) => {
+                if !value.is_empty() {
+                    let bytes = stored_value.string_mut();
+                    let end = offset + value.len();
+                    if bytes.len() < end {
+                        bytes.resize(end, 0);
                     }
+                    bytes[offset..end].copy_from_slice(value);
+                    stored_value.version = self.bump_version();
+                    stored_value.raw = true;
+                }
+                Ok(stored_value.string_mut().len())
+            }
+            None if value.is_empty() => Ok(0),
+            None => {
+                let mut bytes = vec![0; offset];
+                bytes.extend_from_slice(value);
+                let len = bytes.len();
+                // Redis builds the new string in place, so it is raw even
+                // when it happens to look like an integer.
+                let mut stored_value = StoredValue::new(StoredData::String(bytes));
+                stored_value.raw = true;
+                self.insert(store, key.clone(), stored_value);
+                Ok(len)
+            }
+        })
+    }
+
+    /// Sets or clears bit `offset` of the string at `key`, padding with zero
+    /// bytes as needed, and returns the bit's previous value. Bit 0 is the
+    /// most significant bit of the first byte.
+    pub fn setbit(&self, key: String, offset: usize, on: bool) -> Result<bool, String> {
+        self.typed_mut(&key, DataType::String, |store| {
+            let stored_value = store.get_or_insert_with(key.clone(), || {
+                StoredValue::new(StoredData::String(Vec::new()))
+            });
+            let bytes = stored_value.string_mut();
+            let index = offset / 8;
+            if bytes.len() <= index {
+                bytes.resize(index + 1, 0);
+            }
+            let mask = 0x80 >> (offset % 8);
+            let previous = bytes[index] & mask != 0;
+            if on {
+                bytes[index] |= mask;
+            } else {
+                bytes[index] &= !mask;
+            }
+            stored_value.version = self.bump_version();
+            stored_value.raw = true;
+            Ok(previous)
+        })
+    }
+
+    /// Appends `value` to the string at `key`, creating it if needed, and
+    /// returns the new length. Refuses to grow the string past `max_len`,
+    /// checked under the same lock as the write.
+    pub fn append(&self, key: String, value: &[u8], max_len: usize) -> Result<usize, String> {
+        self.typed_mut(&key, DataType::String, |store| {
+            let current = store.get_mut(&key).map_or(0, |v| v.string_mut().len());
+            if current.saturating_add(value.len()) > max_len {
+                return Err(
+                    "ERR string exceeds maximum allowed size (proto-max-bulk-len)".to_string(),
+                );
+            }
+            match store.get_mut(&key) {
+                Some(stored_value) => {
+                    stored_value.string_mut().extend_from_slice(value);
+                    stored_value.version = self.bump_version();
+                    stored_value.raw = true;
+                    Ok(stored_value.string_mut().len())
+                }
+                None => {
+                    let stored_value = StoredValue::new(StoredData::String(value.to_vec()));
+                    self.insert(store, key.clone(), stored_value);
+                    Ok(value.len())
                 }
             }
+        })
+    }
+
+    /// Length of the string at `key`, 0 if it is missing.
+    pub fn strlen(&self, key: &str) -> Result<usize, String> {
+        let mut store = self.inner.lock().unwrap();
+        match self.live_value(&mut store, key) {
+            None => Ok(0),
+            Some(stored_value) => match &stored_value.data {
+                StoredData::String(bytes) => Ok(bytes.len()),
+                _ => Err(WRONGTYPE.to_string()),
+            },
         }
+    }
 
-        let len = values.len();
-        store.insert(key.clone(), StoredValue::new(StoredData::List(values)));
-        drop(store);
+    /// Adds `delta` to the integer stored at `key`, treating a missing key as
+    /// 0. The TTL is kept.
+    pub fn incr_by(&self, key: String, delta: i64) -> Result<i64, String> {
+        self.typed_mut(&key, DataType::String, |store| match store.get_mut(&key) {
+            Some(stored_value) => {
+                let bytes = stored_value.string_mut();
+                let current = parse_strict_i64(bytes).ok_or(NOT_AN_INTEGER)?;
+                let value = current
+                    .checked_add(delta)
+                    .ok_or("ERR increment or decrement would overflow")?;
+                *bytes = value.to_string().into_bytes();
+                stored_value.version = self.bump_version();
+                stored_value.raw = false;
+                Ok(value)
+            }
+            None => {
+                let bytes = delta.to_string().into_bytes();
+                self.insert(
+                    store,
+                    key.clone(),
+                    StoredValue::new(StoredData::String(bytes)),
+                );
+                Ok(delta)
+            }
+        })
+    }
+
+    /// Appends `values` to the list at `key`, creating it if needed.
+    ///
+    /// The push is atomic: every value is appended under one lock, so other
+    /// clients see all of them or none. On WRONGTYPE nothing is written.
+    pub fn rpush(&self, key: String, values: Vec<Vec<u8>>) -> Result<usize, String> {
+        let len = self.typed_mut(&key, DataType::List, |store| {
+            Ok(match store.get_mut(&key) {
+                Some(stored_value) => {
+                    stored_value.list_mut().extend(values);
+                    stored_value.version = self.bump_version();
+                    stored_value.list_mut().len()
+                }
+                None => {
+                    let len = values.len();
+                    self.insert(
+                        store,
+                        key.clone(),
+                        StoredValue::new(StoredData::List(values)),
+                    );
+                    len
+                }
+            })
+        })?;
         self.notify_waiters(&key);
         Ok(len)
     }
 
+    /// Prepends `values` to the list at `key`, with the same atomicity as
+    /// [`Storage::rpush`].
     pub fn lpush(&self, key: String, values: Vec<Vec<u8>>) -> Result<usize, String> {
-        let mut store = self.inner.lock().unwrap();
-
         let mut result = values.into_iter().rev().collect::<Vec<Vec<u8>>>();
-
-        if let Some(stored_value) = store.get_mut(&key) {
-            if stored_value.is_expired() {
-                store.remove(&key);
-            } else {
-                match &mut stored_value.data {
-                    StoredData::List(list) => {
-                        result.append(list);
-                        *list = result;
-                        let len = list.len();
-                        drop(store);
-                        self.notify_waiters(&key);
-                        return Ok(len);
-                    }
-                    _ => {
-                        return Err(
-                            "WRONGTYPE Operation against a key holding the wrong kind of value"
-                                .to_string(),
-                        )
-                    }
+        let len = self.typed_mut(&key, DataType::List, |store| {
+            Ok(match store.get_mut(&key) {
+                Some(stored_value) => {
+                    let list = stored_value.list_mut();
+                    result.append(list);
+                    *list = result;
+                    stored_value.version = self.bump_version();
+                    stored_value.list_mut().len()
                 }
-            }
-        }
-
-        let len = result.len();
-        store.insert(key.clone(), StoredValue::new(StoredData::List(result)));
-        drop(store);
+                None => {
+                    let len = result.len();
+                    self.insert(
+                        store,
+                        key.clone(),
+                        StoredValue::new(StoredData::List(result)),
+                    );
+                    len
+                }
+            })
+        })?;
         self.notify_waiters(&key);
         Ok(len)
     }
 
     pub fn lrange(&self, key: &str, start: isize, end: isize) -> Result<Vec<Vec<u8>>, String> {
         let mut store = self.inner.lock().unwrap();
-        match store.get(key) {
-            None => return Ok(vec![]),
-            Some(stored_value) => {
-                if stored_value.is_expired() {
-                    store.remove(key);
-                    return Ok(vec![]);
-                }
+        match self.live_value(&mut store, key) {
+            None => Ok(vec![]),
+            Some(stored_value) => match &stored_value.data {
+                StoredData::List(list) => {
+                    let len = list.len() as isize;
+
+                    let start_idx = if start < 0 {
+                        (len + start).max(0) as usize
+                    } else {
+                        start as usize
+                    };
 
-                match &stored_value.data {
-                    StoredData::List(list) => {
-                        let len = list.len() as isize;
-
-                        let start_idx = if start < 0 {
-                            (len + start).max(0) as usize
-                        } else {
-                            start as usize
-                        };
-
-                        let end_idx = if end < 0 {
-                            (len + end).max(0) as usize
-                        } else if end >= len {
-                            list.len() - 1
-                        } else {
-                            end as usize
-                        };
-
-                        if start_idx > end_idx || start_idx >= list.len() {
-                            return Ok(vec![]);
-                        }
+                    let end_idx = if end < 0 {
+                        (len + end).max(0) as usize
+                    } else if end >= len {
+                        list.len() - 1
+                    } else {
+                        end as usize
+                    };
 
-                        Ok(list[start_idx..=end_idx].to_vec())
-                    }
-                    _ => {
-                        return Err(
-                            "WRONGTYPE Operation against a key holding the wrong kind of value"
-                                .to_string(),
-                        )
+                    if start_idx > end_idx || start_idx >= list.len() {
+                        return Ok(vec![]);
                     }
+
+                    Ok(list[start_idx..=end_idx].to_vec())
                 }
-            }
+                _ => Err(WRONGTYPE.to_string()),
+            },
         }
     }
 
     pub fn llen(&self, key: &str) -> Result<usize, String> {
         let mut store = self.inner.lock().unwrap();
 
-        match store.get(key) {
+        match self.live_value(&mut store, key) {
             None => Ok(0),
-            Some(stored_value) => {
-                if stored_value.is_expired() {
-                    store.remove(key);
-                    return Ok(0);
-                }
-
-                match &stored_value.data {
-                    StoredData::List(list) => return Ok(list.len()),
-                    _ => {
-                        return Err(
-                            "WRONGTYPE Operation against a key holding the wrong kind of value"
-                                .to_string(),
-                        )
-                    }
-                }
-            }
+            Some(stored_value) => match &stored_value.data {
+                StoredData::List(list) => Ok(list.len()),
+                _ => Err(WRONGTYPE.to_string()),
+            },
         }
     }
 
     pub fn lpop(&self, key: &str) -> Result<Option<Vec<u8>>, String> {
-        let mut store = self.inner.lock().unwrap();
-        match store.get_mut(key) {
-            None => Ok(None),
-            Some(stored_value) => {
-                if stored_value.is_expired() {
-                    store.remove(key);
-                    return Ok(None);
-                }
+        let popped = self.lpop_multiple(key, 1)?;
+        Ok(popped.map(|mut elements| elements.remove(0)))
+    }
 
-                match &mut stored_value.data {
-                    StoredData::List(list) => {
-                        if list.is_empty() {
-                            store.remove(key);
-                            return Ok(None);
-                        }
-                        let element = list.remove(0);
-                        if list.is_empty() {
-                            store.remove(key);
-                        }
-                        return Ok(Some(element));
-                    }
-                    _ => {
-                        return Err(
-                            "WRONGTYPE Operation against a key holding the wrong kind of value"
-                                .to_string(),
-                        )
-                    }
-                }
+    pub fn lpop_multiple(&self, key: &str, count: usize) -> Result<Option<Vec<Vec<u8>>>, String> {
+        self.typed_mut(key, DataType::List, |store| {
+            let Some(stored_value) = store.get_mut(key) else {
+                return Ok(None);
+            };
+            let list = stored_value.list_mut();
+            if list.is_empty() {
+                store.remove(key);
+                return Ok(None);
             }
-        }
+            let count = count.min(list.len());
+            let elements = list.drain(0..count).collect();
+            let emptied = list.is_empty();
+            stored_value.version = self.bump_version();
+            if emptied {
+                store.remove(key);
+            }
+            Ok(Some(elements))
+        })
     }
 
-    pub fn lpop_multiple(
+    /// Pops up to `count` elements from the first non-empty list among
+    /// `keys`, from the head or the tail, checking every key under one lock.
+    /// A key holding another type fails the whole call when it is reached.
+    pub fn lmpop(
         &self,
-        key: &str,
-        mut count: usize,
-    ) -> Result<Option<Vec<Vec<u8>>>, String> {
+        keys: &[String],
+        from_left: bool,
+        count: usize,
+    ) -> Result<Option<ListPop>, String> {
         let mut store = self.inner.lock().unwrap();
-        match store.get_mut(key) {
-            None => Ok(None),
-            Some(stored_value) => {
-                if stored_value.is_expired() {
-                    store.remove(key);
-                    return Ok(None);
-                }
-
-                match &mut stored_value.data {
-                    StoredData::List(list) => {
-                        if list.is_empty() {
-                            store.remove(key);
-                            return Ok(None);
-                        }
-                        if count > list.len() {
-                            count = list.len();
-                        }
-                        let elements = list.drain(0..count).collect();
-                        if list.is_empty() {
-                            store.remove(key);
-                        }
-                        return Ok(Some(elements));
-                    }
-                    _ => {
-                        return Err(
-                            "WRONGTYPE Operation against a key holding the wrong kind of value"
-                                .to_string(),
-                        )
-                    }
-                }
+        for key in keys {
+            let stored_value = match self.checked_value(&mut store, key, DataType::List)? {
+                Some(stored_value) => stored_value,
+                None => continue,
+            };
+            let list = stored_value.list_mut();
+            if list.is_empty() {
+                continue;
             }
+            let count = count.min(list.len());
+            let elements = if from_left {
+                list.drain(..count).collect()
+            } else {
+                list.drain(list.len() - count..).rev().collect()
+            };
+            let emptied = list.is_empty();
+            stored_value.version = self.bump_version();
+            if emptied {
+                store.remove(key);
+            }
+            return Ok(Some((key.clone(), elements)));
         }
+        Ok(None)
     }
 
     pub fn blpop(
         &self,
         keys: Vec<String>,
-        timeout_secs: f64,
+        timeout: Duration,
     ) -> Result<Option<(String, Vec<u8>)>, String> {
-        for key in &keys {
-            let store = self.inner.lock().unwrap();
-
-            if let Some(stored_value) = store.get(key) {
-                if !matches!(stored_value.data, StoredData::List(_)) {
-                    return Err(
-                        "WRONGTYPE Operation against a key holding the wrong kind of value"
-                            .to_string(),
-                    );
-                }
-            }
-
-            drop(store);
+        let popped = self.blmpop(keys, true, 1, timeout)?;
+        Ok(popped.map(|(key, mut elements)| (key, elements.remove(0))))
+    }
 
-            if let Some(value) = self.lpop(key)? {
-                return Ok(Some((key.clone(), value)));
-            }
+    /// LMPOP that waits up to `timeout` (zero means forever) for one of
+    /// `keys` to receive elements when they are all empty.
+    pub fn blmpop(
+        &self,
+        keys: Vec<String>,
+        from_left: bool,
+        count: usize,
+        timeout: Duration,
+    ) -> Result<Option<ListPop>, String> {
+        // Checking and queueing under the waiters lock means a push can't
+        // slip in between and find nobody to hand its elements to.
+        let mut waiters = self.waiters.lock().unwrap();
+        if let Some(popped) = self.lmpop(&keys, from_left, count)? {
+            return Ok(Some(popped));
         }
 
         let (tx, rx) = mpsc::channel();
-
-        let waiter = Waiter {
-            keys: keys.clone(),
+        let id = self.next_waiter_id.fetch_add(1, Ordering::Relaxed);
+        waiters.push_back(Waiter {
+            id,
+            keys,
+            from_left,
+            count,
             sender: tx,
-        };
-
-        self.waiters.lock().unwrap().push_back(waiter);
+        });
+        drop(waiters);
 
-        let result = if timeout_secs == 0.0 {
+        let result = if timeout.is_zero() {
             match rx.recv() {
-                Ok((key, value)) => Some((key, value)),
+                Ok(popped) => Some(popped),
                 Err(_) => unreachable!("Channel shouldn't be closed"),
             }
         } else {
-            let timeout = Duration::from_secs_f64(timeout_secs);
             match rx.recv_timeout(timeout) {
-                Ok((key, value)) => Some((key, value)),
+                Ok(popped) => Some(popped),
                 Err(_) => {
-                    self.waiters.lock().unwrap().retain(|w| w.keys != keys);
-                    None
+                    let mut waiters = self.waiters.lock().unwrap();
+                    waiters.retain(|w| w.id != id);
+                    // Served between the timeout and taking the lock.
+                    rx.try_recv().ok()
                 }
             }
         };
@@ -436,110 +928,463 @@ impl Storage {
     }
 
     pub fn exists(&self, key: &str) -> bool {
-        let store = self.inner.lock().unwrap();
-        store.contains_key(key)
+        let mut store = self.inner.lock().unwrap();
+        self.live_value(&mut store, key).is_some()
     }
+
     pub fn delete(&self, key: &str) -> bool {
         let mut store = self.inner.lock().unwrap();
-        store.remove(key).is_some()
+        match store.remove(key) {
+            Some(stored_value) if stored_value.is_expired(self.now()) => {
+                self.expired_keys.fetch_add(1, Ordering::Relaxed);
+                false
+            }
+            Some(_) => true,
+            None => false,
+        }
     }
 
-    fn notify_waiters(&self, key: &str) {
-        let waiter: Option<Waiter> = {
-            let mut waiters = self.waiters.lock().unwrap();
-            waiters
-                .iter()
-                .position(|w| w.keys.contains(&key.to_string()))
-                .and_then(|pos| waiters.remove(pos))
+    /// Removes every key. With `lazy` the old map is dropped on a background
+    /// thread, so freeing a large database does not hold up the caller.
+    pub fn flush(&self, lazy: bool) {
+        let mut store = self.inner.lock().unwrap();
+        let removals = store.removals + 1;
+        let old = std::mem::replace(
+            &mut *store,
+            Keyspace {
+                removals,
+                ..Keyspace::default()
+            },
+        );
+        drop(store);
+        if lazy {
+            std::thread::spawn(move || drop(old));
+        }
+    }
+
+    /// Copies `source` into `destination` in `dest`, which may be this same
+    /// storage. The copy keeps the source's TTL. Returns false if the source
+    /// is missing or the destination exists and `replace` is not set.
+    pub fn copy_to(&self, source: &str, dest: &Storage, destination: &str, replace: bool) -> bool {
+        let value = {
+            let mut store = self.inner.lock().unwrap();
+            match self.live_value(&mut store, source) {
+                Some(stored_value) => stored_value.clone(),
+                None => return false,
+            }
         };
 
-        if let Some(waiter) = waiter {
-            if let Ok(Some(value)) = self.lpop(key) {
-                let _ = waiter.sender.send((key.to_string(), value));
+        {
+            let mut store = dest.inner.lock().unwrap();
+            if !replace && dest.live_value(&mut store, destination).is_some() {
+                return false;
             }
+            dest.insert(&mut store, destination.to_string(), value);
+        }
+        dest.notify_waiters(destination);
+        true
+    }
+
+    /// Moves `key` into `dest`, a different storage, keeping its TTL. Returns
+    /// false if the key is missing here or already exists in `dest`. Both
+    /// maps are locked in a fixed order for the whole move, so concurrent
+    /// moves in opposite directions can't deadlock and nobody sees the key
+    /// in both places or in neither.
+    pub fn move_to(&self, key: &str, dest: &Storage) -> bool {
+        if Arc::ptr_eq(&self.inner, &dest.inner) {
+            return false;
         }
+        let self_first = Arc::as_ptr(&self.inner) < Arc::as_ptr(&dest.inner);
+        let (mut store, mut dest_store) = if self_first {
+            let store = self.inner.lock().unwrap();
+            (store, dest.inner.lock().unwrap())
+        } else {
+            let dest_store = dest.inner.lock().unwrap();
+            (self.inner.lock().unwrap(), dest_store)
+        };
+
+        if self.live_value(&mut store, key).is_none()
+            || dest.live_value(&mut dest_store, key).is_some()
+        {
+            return false;
+        }
+
+        let value = store.remove(key).unwrap();
+        dest.insert(&mut dest_store, key.to_string(), value);
+        drop(store);
+        drop(dest_store);
+        dest.notify_waiters(key);
+        true
     }
 
-    pub fn get_type(&self, key: &str) -> String {
+    /// Live keys and how many of them have a TTL, for DBSIZE and INFO keyspace.
+    pub fn key_counts(&self) -> (usize, usize) {
         let store = self.inner.lock().unwrap();
+        let now = self.now();
+        let live = store.values.values().filter(|value| !value.is_expired(now));
+        live.fold((0, 0), |(keys, expires), value| {
+            (keys + 1, expires + usize::from(value.expired_at.is_some()))
+        })
+    }
+
+    /// Number of keys removed because they had expired. A key is counted at
+    /// the moment it is removed, so it can never be counted twice.
+    pub fn expired_keys(&self) -> u64 {
+        self.expired_keys.load(Ordering::Relaxed)
+    }
+
+    /// One SCAN step: returns the next cursor (0 once the scan is complete) and
+    /// the keys visited from `cursor` on, roughly `count` at a time.
+    ///
+    /// Each key lives in the bucket given by its 64-bit hash, and buckets are
+    /// visited in reverse-binary order, so the cursor is the next bucket to
+    /// visit. Every call partitions the bucket space the same way no matter
+    /// what else is in the map, so a key present for the whole scan is
+    /// returned exactly once. Keys added or removed while a scan is in
+    /// progress may or may not be returned.
+    ///
+    /// `count` bounds the work done, not the reply: about `count` keys are
+    /// visited per call, and MATCH or `type_name` filtering afterwards may
+    /// leave few or none of them. Expired keys are dropped as they are visited.
+    pub fn scan(&self, cursor: u64, count: usize, type_name: Option<&str>) -> (u64, Vec<String>) {
+        let mut store = self.inner.lock().unwrap();
 
-        match store.get(key) {
-            None => "none".to_string(),
-            Some(stored_value) => {
-                if stored_value.is_expired() {
-                    "none".to_string()
-                } else {
-                    match stored_value.data {
-                        StoredData::List(_) => "list".to_string(),
-                        StoredData::String(_) => "string".to_string(),
-                        StoredData::Stream(_) => "stream".to_string(),
-                    }
+        let mut visited: Vec<(u64, Arc<str>)> = Vec::new();
+        let mut next = 0;
+        for (position, key) in store
+            .positions
+            .range((cursor.reverse_bits(), Arc::from(""))..)
+        {
+            // Never split a bucket, otherwise keys sharing a hash could be lost.
+            if visited.len() >= count.max(1) && visited[visited.len() - 1].0 != *position {
+                next = position.reverse_bits();
+                break;
+            }
+            visited.push((*position, key.clone()));
+        }
+
+        let mut keys = Vec::new();
+        for (_, key) in visited {
+            if let Some(value) = self.live_value(&mut store, &key) {
+                if type_name.is_none_or(|name| name == value.data.type_name()) {
+                    keys.push(key.to_string());
                 }
             }
         }
+        (next, keys)
     }
 
-    pub fn xadd(
-        &self,
-        key: String,
-        id: &str,
-        values: HashMap<String, Vec<u8>>,
-    ) -> Result<String, String> {
+    /// One step of active expiry: examines up to `sample_size` keys that have
+    /// a TTL, removes the expired ones and returns how many it removed. Each
+    /// call resumes where the last one stopped, so repeated calls cycle
+    /// through the whole keyspace.
+    pub(crate) fn clear_expired_sample(&self, sample_size: usize) -> usize {
         let mut store = self.inner.lock().unwrap();
+        let now = self.now();
+        let start = (store.expire_cursor, Arc::from(""));
+        let max_visits = sample_size.saturating_mul(EXPIRE_SAMPLE_VISIT_FACTOR);
+
+        let mut examined = 0;
+        let mut expired = Vec::new();
+        let mut next = store.expire_cursor;
+        let wrapped = store
+            .positions
+            .range(start.clone()..)
+            .chain(store.positions.range(..start));
+        for (visits, (position, key)) in wrapped.enumerate() {
+            if examined == sample_size || visits == max_visits {
+                next = *position;
+                break;
+            }
+            let value = &store.values[key];
+            if value.expired_at.is_some() {
+                examined += 1;
+                if value.is_expired(now) {
+                    expired.push(key.clone());
+                }
+            }
+        }
 
-        let stored_value = store
-            .entry(key.clone())
-            .or_insert_with(|| StoredValue::new(StoredData::Stream(Vec::new())));
+        store.expire_cursor = next;
+        for key in &expired {
+            store.remove(key);
+        }
+        self.expired_keys
+            .fetch_add(expired.len() as u64, Ordering::Relaxed);
+        expired.len()
+    }
 
-        if stored_value.is_expired() {
-            *stored_value = StoredValue::new(StoredData::Stream(Vec::new()));
+    /// Live keys matching the glob `pattern`. With a non-zero `limit`, gives
+    /// up as soon as more than `limit` keys match instead of building the
+    /// whole list under the lock.
+    pub fn keys(&self, pattern: &[u8], limit: usize) -> Result<Vec<String>, String> {
+        let store = self.inner.lock().unwrap();
+        let now = self.now();
+        let mut keys = Vec::new();
+        for (key, value) in &store.values {
+            if value.is_expired(now) || !glob_match(pattern, key.as_bytes()) {
+                continue;
+            }
+            if limit > 0 && keys.len() == limit {
+                return Err("ERR KEYS result too large, use SCAN".to_string());
+            }
+            keys.push(key.to_string());
+        }
+        Ok(keys)
+    }
+
+    /// Modification version of `key`, or `None` if it does not exist. Every
+    /// write gives the key a version never used before in this storage, so a
+    /// key that is deleted and recreated never gets its old version back.
+    pub fn version(&self, key: &str) -> Option<u64> {
+        let mut store = self.inner.lock().unwrap();
+        self.live_value(&mut store, key)
+            .map(|stored_value| stored_value.version)
+    }
+
+    /// What WATCH records for `key` and EXEC compares against.
+    pub fn key_version(&self, key: &str) -> KeyVersion {
+        let mut store = self.inner.lock().unwrap();
+        match self.live_value(&mut store, key) {
+            Some(stored_value) => KeyVersion::Exists(stored_value.version),
+            None => KeyVersion::Missing {
+                removals: store.removals,
+            },
         }
+    }
+
+    fn bump_version(&self) -> u64 {
+        self.next_version.fetch_add(1, Ordering::Relaxed)
+    }
 
-        match &mut stored_value.data {
-            StoredData::Stream(list) => {
-                let id_spec = parse_id_spec(id)?;
-                let entry_id = resolve_id(id_spec, list.last().map(|e| &e.id))?;
-                validate_monotonic(&entry_id, list.last().map(|e| &e.id))?;
-                Ok(push_entry(list, entry_id, values))
+    /// Absolute expiry `milliseconds` from now. Like Redis, the result has to
+    /// fit in a signed millisecond unix timestamp.
+    fn expires_in(&self, milliseconds: i64) -> Result<SystemTime, String> {
+        let now = self
+            .now()
+            .duration_since(UNIX_EPOCH)
+            .map_err(|_| INVALID_EXPIRE)?
+            .as_millis() as i64;
+        let at = now.checked_add(milliseconds).ok_or(INVALID_EXPIRE)?;
+        Ok(UNIX_EPOCH + Duration::from_millis(at as u64))
+    }
+
+    fn insert(&self, store: &mut Keyspace, key: String, mut value: StoredValue) {
+        value.version = self.bump_version();
+        store.insert(key, value);
+    }
+
+    fn purge_expired(&self, store: &mut Keyspace, key: &str) {
+        if store
+            .get(key)
+            .is_some_and(|value| value.is_expired(self.now()))
+        {
+            store.remove(key);
+            self.expired_keys.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// The live value at `key`, or WRONGTYPE if it holds something other than
+    /// `expect`.
+    fn checked_value<'a>(
+        &self,
+        store: &'a mut Keyspace,
+        key: &str,
+        expect: DataType,
+    ) -> Result<Option<&'a mut StoredValue>, String> {
+        match self.live_value(store, key) {
+            Some(stored_value) if stored_value.data.data_type() != expect => {
+                Err(WRONGTYPE.to_string())
             }
-            _ => {
-                Err("WRONGTYPE Operation against a key holding the wrong kind of value".to_string())
+            stored_value => Ok(stored_value),
+        }
+    }
+
+    /// Runs the write `f` against `key` under the keyspace lock. Every write
+    /// command goes through here, so they all purge an expired key first and
+    /// fail with WRONGTYPE before `f` can change anything when the key holds
+    /// something other than `expect`. `f` gets the keyspace with the key
+    /// still in it, missing or of the expected type.
+    fn typed_mut<T>(
+        &self,
+        key: &str,
+        expect: DataType,
+        f: impl FnOnce(&mut Keyspace) -> Result<T, String>,
+    ) -> Result<T, String> {
+        let mut store = self.inner.lock().unwrap();
+        self.checked_value(&mut store, key, expect)?;
+        f(&mut store)
+    }
+
+    /// Looks `key` up, dropping it first if it has expired, so callers only ever
+    /// see live values.
+    fn live_value<'a>(&self, store: &'a mut Keyspace, key: &str) -> Option<&'a mut StoredValue> {
+        self.purge_expired(store, key);
+        store.get_mut(key)
+    }
+
+    /// Hands elements of `key` to its blocked clients, longest waiting
+    /// first, until the list or the queue runs out. Each waiter is dequeued
+    /// in the same step it is served, so it can't be served twice.
+    fn notify_waiters(&self, key: &str) {
+        let mut waiters = self.waiters.lock().unwrap();
+        let keys = [key.to_string()];
+        while let Some(pos) = waiters.iter().position(|w| w.keys.contains(&keys[0])) {
+            let popped = match self.lmpop(&keys, waiters[pos].from_left, waiters[pos].count) {
+                Ok(Some(popped)) => popped,
+                _ => return,
+            };
+            let waiter = waiters.remove(pos).unwrap();
+            let _ = waiter.sender.send(popped);
+        }
+    }
+
+    pub fn get_type(&self, key: &str) -> String {
+        let mut store = self.inner.lock().unwrap();
+
+        match self.live_value(&mut store, key) {
+            None => "none".to_string(),
+            Some(stored_value) => stored_value.data.type_name().to_string(),
+        }
+    }
+
+    pub fn object_encoding(&self, key: &str) -> Option<&'static str> {
+        let mut store = self.inner.lock().unwrap();
+        self.live_value(&mut store, key)
+            .map(|stored_value| stored_value.encoding(self.packed_threshold()))
+    }
+
+    /// OBJECT REFCOUNT: shared integers report Redis' immortal refcount.
+    pub fn object_refcount(&self, key: &str) -> Option<i64> {
+        let mut store = self.inner.lock().unwrap();
+        self.live_value(&mut store, key)
+            .map(|stored_value| stored_value.refcount())
+    }
+
+    /// MEMORY USAGE: modelled bytes for the key and its value.
+    pub fn memory_usage(&self, key: &str) -> Option<usize> {
+        let mut store = self.inner.lock().unwrap();
+        self.live_value(&mut store, key).map(|stored_value| {
+            DICT_ENTRY_BYTES + key.len() + SDS_OVERHEAD_BYTES + stored_value.memory_usage()
+        })
+    }
+
+    fn packed_threshold(&self) -> usize {
+        self.packed_threshold.load(Ordering::Relaxed)
+    }
+
+    /// Sets the size above which a list element is stored in a plain node, as
+    /// DEBUG QUICKLIST-PACKED-THRESHOLD does.
+    pub fn set_packed_threshold(&self, bytes: usize) {
+        self.packed_threshold.store(bytes, Ordering::Relaxed);
+    }
+
+    /// Entries of a listpack-encoded value, for DEBUG LISTPACK. `Ok(None)`
+    /// means the key doesn't exist.
+    pub fn listpack_entries(&self, key: &str) -> Result<Option<Vec<Vec<u8>>>, String> {
+        let mut store = self.inner.lock().unwrap();
+        let packed_threshold = self.packed_threshold();
+        let stored_value = match self.live_value(&mut store, key) {
+            Some(stored_value) => stored_value,
+            None => return Ok(None),
+        };
+        match &stored_value.data {
+            StoredData::List(list) if stored_value.encoding(packed_threshold) == "listpack" => {
+                Ok(Some(list.to_vec()))
             }
+            _ => Err(
+                "ERR The value stored at the specified key is not represented using an listpack"
+                    .to_string(),
+            ),
         }
     }
 
+    pub fn debug_object(&self, key: &str) -> Option<String> {
+        let mut store = self.inner.lock().unwrap();
+        self.live_value(&mut store, key).map(|stored_value| {
+            let serialized_length = match &stored_value.data {
+                StoredData::String(bytes) => bytes.len(),
+                StoredData::List(list) => list.iter().map(Vec::len).sum(),
+                StoredData::Stream(entries) => entries.len(),
+            };
+            let mut out = format!(
+                "Value at:0x0 refcount:{} encoding:{} serializedlength:{} lru:0 lru_seconds_idle:0",
+                stored_value.refcount(),
+                stored_value.encoding(self.packed_threshold()),
+                serialized_length
+            );
+
+            if let StoredData::List(list) = &stored_value.data {
+                let nodes = list.len().div_ceil(LIST_MAX_LISTPACK_SIZE).max(1);
+                out.push_str(&format!(
+                    " ql_nodes:{} ql_avg_node:{:.2} ql_listpack_max:{} ql_compressed:0 ql_uncompressed_size:{}",
+                    nodes,
+                    list.len() as f64 / nodes as f64,
+                    LIST_MAX_LISTPACK_SIZE,
+                    serialized_length
+                ));
+            }
+            if let StoredData::Stream(entries) = &stored_value.data {
+                let last_id = entries.last().map(|entry| entry.id.clone()).unwrap_or_default();
+                out.push_str(&format!(
+                    " entries:{} last-id:{}-{} radix-tree-keys:{}",
+                    entries.len(),
+                    last_id.ms,
+                    last_id.seq,
+                    entries.len().div_ceil(STREAM_NODE_MAX_ENTRIES)
+                ));
+            }
+            out
+        })
+    }
+
+    pub fn xadd(
+        &self,
+        key: String,
+        id: &str,
+        values: HashMap<String, Vec<u8>>,
+    ) -> Result<String, String> {
+        self.typed_mut(&key, DataType::Stream, |store| {
+            let last_id = store
+                .get_mut(&key)
+                .and_then(|stored_value| stored_value.stream_mut().last())
+                .map(|entry| entry.id.clone());
+            let id_spec = parse_id_spec(id)?;
+            let entry_id = resolve_id(id_spec, last_id.as_ref())?;
+            validate_monotonic(&entry_id, last_id.as_ref())?;
+
+            // Only created once the id is known to be valid, so a rejected
+            // XADD leaves no empty stream behind.
+            let stored_value = store.get_or_insert_with(key.clone(), || {
+                StoredValue::new(StoredData::Stream(Vec::new()))
+            });
+            stored_value.version = self.bump_version();
+            let id = push_entry(stored_value.stream_mut(), entry_id, values);
+            self.stream_added.notify_all();
+            Ok(id)
+        })
+    }
+
     pub fn xrange(&self, key: &str, start: &str, end: &str) -> Result<Vec<Vec<Vec<u8>>>, String> {
         let mut store = self.inner.lock().unwrap();
 
         let start = parse_range_id(start, true)?;
         let end = parse_range_id(end, false)?;
 
-        let stored = store.get(key);
-
-        let data = match stored {
+        let data = match self.live_value(&mut store, key) {
             Some(data) => data,
             None => return Ok(vec![]),
         };
 
-        if data.is_expired() {
-            store.remove(key);
-            return Ok(vec![]);
-        }
-
         let out = match &data.data {
             StoredData::Stream(s) => {
-                if let Some((lower, upper)) = xrange_range_indices(&s, &start, &end) {
-                    let out = entries_to_vec(&s[lower..=upper]);
-                    out
+                if let Some((lower, upper)) = xrange_range_indices(s, &start, &end) {
+                    entries_to_vec(&s[lower..=upper])
                 } else {
                     return Ok(vec![]);
                 }
             }
-            _ => {
-                return Err(
-                    "WRONGTYPE Operation against a key holding the wrong kind of value".to_string(),
-                )
-            }
+            _ => return Err(WRONGTYPE.to_string()),
         };
 
         Ok(out)
@@ -550,39 +1395,114 @@ impl Storage {
 
         let start = parse_range_id(id, true)?;
 
-        let stored = store.get(key);
-
-        let data = match stored {
+        let data = match self.live_value(&mut store, key) {
             Some(data) => data,
             None => return Ok(vec![]),
         };
 
-        if data.is_expired() {
-            store.remove(key);
-            return Ok(vec![]);
-        }
-
         let out = match &data.data {
             StoredData::Stream(s) => {
-                if let Some((lower, upper)) = xread_range_indices(&s, &start) {
-                    let out = entries_to_vec(&s[lower..=upper]);
-                    out
+                if let Some((lower, upper)) = xread_range_indices(s, &start) {
+                    entries_to_vec(&s[lower..=upper])
                 } else {
                     return Ok(vec![]);
                 }
             }
-            _ => {
-                return {
-                    Err(
-                        "WRONGTYPE Operation against a key holding the wrong kind of value"
-                            .to_string(),
-                    )
-                }
-            }
+            _ => return Err(WRONGTYPE.to_string()),
         };
 
         Ok(out)
     }
+
+    /// Entries after the given id for each `(key, id)` stream, skipping streams
+    /// with nothing new. `$` stands for the stream's last id at call time.
+    ///
+    /// With `block`, waits until at least one stream has new entries or the
+    /// timeout passes, returning an empty result on timeout. A zero timeout
+    /// waits forever.
+    pub fn xread_streams(
+        &self,
+        streams: &[(String, String)],
+        count: Option<usize>,
+        block: Option<Duration>,
+    ) -> Result<Vec<StreamRead>, String> {
+        let mut store = self.inner.lock().unwrap();
+
+        let mut starts = Vec::with_capacity(streams.len());
+        for (key, id) in streams {
+            let start = if id == "$" {
+                let entries = stream_entries(self.live_value(&mut store, key).map(|v| &*v))?;
+                entries.last().map(|e| e.id.clone()).unwrap_or_default()
+            } else {
+                parse_range_id(id, true)?
+            };
+            starts.push((key, start));
+        }
+
+        let deadline = block
+            .filter(|timeout| !timeout.is_zero())
+            .map(|timeout| Instant::now() + timeout);
+
+        loop {
+            let mut out = Vec::new();
+            for (key, start) in &starts {
+                let entries = stream_entries(self.live_value(&mut store, key).map(|v| &*v))?;
+                if let Some((lower, upper)) = xread_range_indices(entries, start) {
+                    let upper = match count {
+                        Some(count) => upper.min(lower + count - 1),
+                        None => upper,
+                    };
+                    out.push((key.to_string(), entries_to_vec(&entries[lower..=upper])));
+                }
+            }
+
+            if !out.is_empty() || block.is_none() {
+                return Ok(out);
+            }
+
+            store = match deadline {
+                None => self.stream_added.wait(store).unwrap(),
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return Ok(out);
+                    }
+                    self.stream_added
+                        .wait_timeout(store, deadline - now)
+                        .unwrap()
+                        .0
+                }
+            };
+        }
+    }
+}
+
+/// `milliseconds` shifted by `random`, mapped uniformly onto
+/// +/- `jitter_pct` percent of it. Never drops below 1ms.
+pub fn jittered_millis(milliseconds: u64, jitter_pct: u64, random: u64) -> u64 {
+    let window = milliseconds.saturating_mul(jitter_pct.min(100)) / 100;
+    if window == 0 {
+        return milliseconds;
+    }
+    let offset = random % (2 * window + 1);
+    (milliseconds - window).saturating_add(offset).max(1)
+}
+
+/// Parses an integer as strictly as Redis' `string2ll`: an optional `-`, no
+/// `+`, no whitespace and no leading zeros, so only the canonical form of an
+/// `i64` is accepted.
+pub(crate) fn parse_strict_i64(bytes: &[u8]) -> Option<i64> {
+    let s = std::str::from_utf8(bytes).ok()?;
+    let n = s.parse::<i64>().ok()?;
+    (n.to_string() == s).then_some(n)
+}
+
+/// Where SCAN visits `key`: its hash with the bits reversed, so the cursor
+/// order is stable however many keys are in the map.
+fn scan_position(key: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    hasher.finish().reverse_bits()
 }
 
 fn entries_to_vec(entries: &[Entry]) -> Vec<Vec<Vec<u8>>> {
@@ -605,14 +1525,8 @@ fn xrange_range_indices(
     start: &EntryId,
     end: &EntryId,
 ) -> Option<(usize, usize)> {
-    let lower = match entries.iter().position(|first| first.id >= *start) {
-        Some(lower) => lower,
-        None => return None,
-    };
-    let upper = match entries.iter().rposition(|last| last.id <= *end) {
-        Some(upper) => upper,
-        None => return None,
-    };
+    let lower = entries.iter().position(|first| first.id >= *start)?;
+    let upper = entries.iter().rposition(|last| last.id <= *end)?;
 
     if lower > upper {
         return None;
@@ -621,11 +1535,16 @@ fn xrange_range_indices(
     Some((lower, upper))
 }
 
+fn stream_entries(stored_value: Option<&StoredValue>) -> Result<&[Entry], String> {
+    match stored_value.map(|stored_value| &stored_value.data) {
+        Some(StoredData::Stream(entries)) => Ok(entries),
+        Some(_) => Err(WRONGTYPE.to_string()),
+        None => Ok(&[]),
+    }
+}
+
 fn xread_range_indices(entries: &[Entry], start: &EntryId) -> Option<(usize, usize)> {
-    let lower = match entries.iter().position(|first| first.id > *start) {
-        Some(lower) => lower,
-        None => return None,
-    };
+    let lower = entries.iter().position(|first| first.id > *start)?;
 
     let upper = entries.len().saturating_sub(1);
 
@@ -671,20 +1590,16 @@ fn parse_range_id(id: &str, is_start: bool) -> Result<EntryId, String> {
 
 fn parse_id_spec(id: &str) -> Result<IdSpec, String> {
     if id == "*" {
-        return Ok(IdSpec::FullWildcard);
+        Ok(IdSpec::FullWildcard)
     } else {
         match id.split_once("-") {
             Some((first, second)) => {
                 if second == "*" {
                     match first.parse::<u128>() {
-                        Ok(ms) => return Ok(IdSpec::PartialWildcardMs(ms)),
-                        Err(_) => {
-                            return Err(
-                                "ERR Invalid stream ID specified as stream command argument"
-                                    .to_string(),
-                            )
-                        }
-                    };
+                        Ok(ms) => Ok(IdSpec::PartialWildcardMs(ms)),
+                        Err(_) => Err("ERR Invalid stream ID specified as stream command argument"
+                            .to_string()),
+                    }
                 } else {
                     match EntryId::from_str(id) {
                         Ok(id) => {
@@ -696,21 +1611,13 @@ fn parse_id_spec(id: &str) -> Result<IdSpec, String> {
                             }
                             Ok(IdSpec::Explicit(id))
                         }
-                        Err(_) => {
-                            return Err(
-                                "ERR Invalid stream ID specified as stream command argument"
-                                    .to_string(),
-                            )
-                        }
+                        Err(_) => Err("ERR Invalid stream ID specified as stream command argument"
+                            .to_string()),
                     }
                 }
             }
 
-            None => {
-                return Err(
-                    "ERR Invalid stream ID specified as stream command argument".to_string()
-                );
-            }
+            None => Err("ERR Invalid stream ID specified as stream command argument".to_string()),
         }
     }
 }
@@ -777,9 +1684,10 @@ fn push_entry(list: &mut Vec<Entry>, id: EntryId, values: HashMap<String, Vec<u8
 
 #[cfg(test)]
 mod tests {
-    use std::{thread::sleep, u64};
+    use std::thread::sleep;
 
     use super::*;
+    use crate::clock::TestClock;
 
     #[test]
     fn set_and_get_success() {
@@ -804,16 +1712,373 @@ mod tests {
 
     #[test]
     fn test_get_expired_returns_none() {
+        let clock = TestClock::new();
+        let storage = Storage::new_with_clock(Arc::new(clock.clone()));
+        storage
+            .set_ex("key".to_string(), b"value".to_vec(), 1)
+            .unwrap();
+        clock.advance(Duration::from_millis(1100));
+        assert_eq!(storage.get("key"), None);
+    }
+
+    #[test]
+    fn test_exists_purges_expired_key() {
+        let clock = TestClock::new();
+        let storage = Storage::new_with_clock(Arc::new(clock.clone()));
+        storage
+            .set_ex("key".to_string(), b"value".to_vec(), 1)
+            .unwrap();
+        assert!(storage.exists("key"));
+        clock.advance(Duration::from_millis(1100));
+        assert!(!storage.exists("key"));
+        assert_eq!(storage.get_type("key"), "none");
+        assert!(!storage.delete("key"));
+    }
+
+    #[test]
+    fn test_scan_returns_persistent_keys_despite_concurrent_writes() {
+        let storage = Storage::new();
+        for i in 0..200 {
+            storage.set(format!("stable:{}", i), b"v".to_vec());
+            storage.set(format!("doomed:{}", i), b"v".to_vec());
+        }
+
+        let mut seen = std::collections::HashSet::new();
+        let mut cursor = 0;
+        let mut step = 0;
+        loop {
+            let (next, keys) = storage.scan(cursor, 10, None);
+            seen.extend(keys);
+
+            storage.delete(&format!("doomed:{}", step));
+            for i in 0..5 {
+                storage.set(format!("added:{}:{}", step, i), b"v".to_vec());
+            }
+            step += 1;
+
+            cursor = next;
+            if cursor == 0 {
+                break;
+            }
+        }
+
+        for i in 0..200 {
+            assert!(
+                seen.contains(&format!("stable:{}", i)),
+                "missed stable:{}",
+                i
+            );
+        }
+    }
+
+    #[test]
+    fn test_scan_survives_the_keyspace_growing_tenfold() {
+        let storage = Storage::new();
+        for i in 0..1000 {
+            storage.set(format!("original:{}", i), b"v".to_vec());
+        }
+
+        // The map rehashes several times while the scan is under way, which
+        // must not move the originals behind the cursor.
+        let mut seen = std::collections::HashSet::new();
+        let mut cursor = 0;
+        let mut added = 0;
+        loop {
+            let (next, keys) = storage.scan(cursor, 10, None);
+            seen.extend(keys);
+
+            for _ in 0..500.min(9000 - added) {
+                storage.set(format!("added:{}", added), b"v".to_vec());
+                added += 1;
+            }
+
+            cursor = next;
+            if cursor == 0 {
+                break;
+            }
+        }
+
+        assert_eq!(added, 9000);
+        for i in 0..1000 {
+            assert!(
+                seen.contains(&format!("original:{}", i)),
+                "missed original:{}",
+                i
+            );
+        }
+    }
+
+    #[test]
+    fn test_scan_type_filter_keeps_count_bound() {
         let storage = Storage::new();
-        storage.set_ex("key".to_string(), b"value".to_vec(), 1);
-        sleep(Duration::from_millis(1100));
+        for i in 0..200 {
+            match i % 4 {
+                0 => storage.set(format!("string:{}", i), b"v".to_vec()),
+                1 => {
+                    storage
+                        .rpush(format!("list:{}", i), vec![b"v".to_vec()])
+                        .unwrap();
+                }
+                _ => {
+                    storage
+                        .xadd(format!("stream:{}", i), "*", HashMap::new())
+                        .unwrap();
+                }
+            }
+        }
+
+        let mut streams = std::collections::HashSet::new();
+        let mut cursor = 0;
+        let mut calls = 0;
+        loop {
+            let (next, keys) = storage.scan(cursor, 50, Some("stream"));
+            calls += 1;
+            // Keys sharing a position are never split, so allow a little slack.
+            assert!(keys.len() <= 55, "one call returned {} keys", keys.len());
+            for key in keys {
+                assert!(key.starts_with("stream:"), "{} is not a stream", key);
+                streams.insert(key);
+            }
+            cursor = next;
+            if cursor == 0 {
+                break;
+            }
+        }
+
+        assert!(calls >= 4, "200 keys at COUNT 50 took {} calls", calls);
+        assert_eq!(streams.len(), 100);
+    }
+
+    #[test]
+    fn test_clear_expired_sample_removes_only_expired_keys() {
+        let clock = TestClock::new();
+        let storage = Storage::new_with_clock(Arc::new(clock.clone()));
+        for i in 0..5 {
+            storage.set(format!("persistent:{}", i), b"v".to_vec());
+            storage
+                .set_px(format!("short:{}", i), b"v".to_vec(), 10)
+                .unwrap();
+            storage
+                .set_ex(format!("long:{}", i), b"v".to_vec(), 100)
+                .unwrap();
+        }
+        clock.advance(Duration::from_millis(20));
+
+        assert_eq!(storage.clear_expired_sample(100), 5);
+        assert_eq!(storage.expired_keys(), 5);
+        assert_eq!(storage.key_counts(), (10, 5));
+        assert_eq!(storage.clear_expired_sample(100), 0);
+    }
+
+    #[test]
+    fn test_clear_expired_sample_resumes_where_it_stopped() {
+        let clock = TestClock::new();
+        let storage = Storage::new_with_clock(Arc::new(clock.clone()));
+        for i in 0..10 {
+            storage
+                .set_px(format!("key:{}", i), b"v".to_vec(), 10)
+                .unwrap();
+        }
+        clock.advance(Duration::from_millis(20));
+
+        let removed: Vec<usize> = (0..4).map(|_| storage.clear_expired_sample(3)).collect();
+        assert_eq!(removed, vec![3, 3, 3, 1]);
+        assert_eq!(storage.key_counts(), (0, 0));
+    }
+
+    #[test]
+    fn test_scan_skips_expired_keys() {
+        let clock = TestClock::new();
+        let storage = Storage::new_with_clock(Arc::new(clock.clone()));
+        storage.set("live".to_string(), b"v".to_vec());
+        storage
+            .set_px("gone".to_string(), b"v".to_vec(), 1)
+            .unwrap();
+        clock.advance(Duration::from_millis(10));
+
+        assert_eq!(storage.scan(0, 10, None), (0, vec!["live".to_string()]));
+    }
+
+    #[test]
+    fn test_string_encoding() {
+        let encoding =
+            |bytes: &[u8]| StoredValue::new(StoredData::String(bytes.to_vec())).string_encoding();
+        assert_eq!(encoding(b"123"), "int");
+        assert_eq!(encoding(b"-42"), "int");
+        assert_eq!(encoding(b"0123"), "embstr");
+        assert_eq!(encoding(b"hello"), "embstr");
+        assert_eq!(encoding(&[b'a'; 44]), "embstr");
+        assert_eq!(encoding(&[b'a'; 60]), "raw");
+    }
+
+    #[test]
+    fn test_lazy_expiry_counts_each_key_once() {
+        let clock = TestClock::new();
+        let storage = Storage::new_with_clock(Arc::new(clock.clone()));
+        storage
+            .set_px("key".to_string(), b"value".to_vec(), 1)
+            .unwrap();
+        clock.advance(Duration::from_millis(10));
+
+        assert_eq!(storage.expired_keys(), 0);
         assert_eq!(storage.get("key"), None);
+        assert_eq!(storage.expired_keys(), 1);
+        assert_eq!(storage.get("key"), None);
+        assert!(!storage.delete("key"));
+        assert_eq!(storage.expired_keys(), 1);
+    }
+
+    #[test]
+    fn test_copy_to_other_storage_respects_replace() {
+        let source = Storage::new();
+        let dest = Storage::new();
+        source.set("key".to_string(), b"new".to_vec());
+        dest.set("key".to_string(), b"old".to_vec());
+
+        assert!(!source.copy_to("key", &dest, "key", false));
+        assert_eq!(dest.get("key"), Some(b"old".to_vec()));
+        assert!(source.copy_to("key", &dest, "key", true));
+        assert_eq!(dest.get("key"), Some(b"new".to_vec()));
+        assert!(!source.copy_to("missing", &dest, "other", true));
+    }
+
+    #[test]
+    fn test_debug_object_reports_quicklist_nodes_for_lists() {
+        let storage = Storage::new();
+        let values = (0..300).map(|i| i.to_string().into_bytes()).collect();
+        storage.rpush("list".to_string(), values).unwrap();
+
+        let description = storage.debug_object("list").unwrap();
+        assert!(
+            description.contains(" encoding:quicklist "),
+            "{}",
+            description
+        );
+        assert!(description.contains(" ql_nodes:3 "), "{}", description);
+        assert!(
+            description.contains(" ql_avg_node:100.00 "),
+            "{}",
+            description
+        );
+        assert!(
+            description.contains(" ql_listpack_max:128 "),
+            "{}",
+            description
+        );
+    }
+
+    #[test]
+    fn test_getrange_handles_negative_and_out_of_range_indexes() {
+        let storage = Storage::new();
+        storage.set("key".to_string(), b"Hello World".to_vec());
+
+        assert_eq!(storage.getrange("key", 0, 4), Ok(b"Hello".to_vec()));
+        assert_eq!(storage.getrange("key", -5, -1), Ok(b"World".to_vec()));
+        assert_eq!(storage.getrange("key", 6, 100), Ok(b"World".to_vec()));
+        assert_eq!(storage.getrange("key", -1, -5), Ok(vec![]));
+        assert_eq!(storage.getrange("missing", 0, -1), Ok(vec![]));
+    }
+
+    #[test]
+    fn test_setrange_pads_and_keeps_empty_writes_noop() {
+        let storage = Storage::new();
+        assert_eq!(storage.setrange("key".to_string(), 2, b"hi"), Ok(4));
+        assert_eq!(storage.get("key"), Some(b"\0\0hi".to_vec()));
+        assert_eq!(storage.setrange("key".to_string(), 1, b""), Ok(4));
+        assert_eq!(storage.setrange("missing".to_string(), 5, b""), Ok(0));
+        assert!(!storage.exists("missing"));
+    }
+
+    #[test]
+    fn test_version_changes_on_write_and_never_repeats() {
+        let storage = Storage::new();
+        assert_eq!(storage.version("key"), None);
+
+        storage.set("key".to_string(), b"value".to_vec());
+        let first = storage.version("key").unwrap();
+        assert_eq!(storage.version("key"), Some(first));
+
+        storage.delete("key");
+        assert_eq!(storage.version("key"), None);
+        storage.set("key".to_string(), b"value".to_vec());
+        assert_ne!(storage.version("key"), Some(first));
+    }
+
+    #[test]
+    fn test_scan_index_shares_key_names_with_the_map() {
+        let storage = Storage::new();
+        storage.set("key".to_string(), b"1".to_vec());
+        storage.set("key".to_string(), b"2".to_vec());
+
+        let store = storage.inner.lock().unwrap();
+        let (key, _) = store.values.get_key_value("key").unwrap();
+        let (_, indexed) = store.positions.first().unwrap();
+        assert!(Arc::ptr_eq(key, indexed));
+        assert_eq!(Arc::strong_count(key), 2);
+        drop(store);
+
+        storage.delete("key");
+        assert!(storage.inner.lock().unwrap().positions.is_empty());
+    }
+
+    #[test]
+    fn test_concurrent_appends_never_pass_the_size_limit() {
+        let storage = Storage::new();
+        let writers: Vec<_> = (0..8)
+            .map(|_| {
+                let storage = storage.clone();
+                std::thread::spawn(move || {
+                    (0..100)
+                        .filter(|_| storage.append("key".to_string(), b"xy", 100).is_ok())
+                        .count()
+                })
+            })
+            .collect();
+
+        let appended: usize = writers.into_iter().map(|w| w.join().unwrap()).sum();
+        assert_eq!(appended, 50);
+        assert_eq!(storage.strlen("key"), Ok(100));
+        assert_eq!(
+            storage.append("key".to_string(), b"z", 100),
+            Err("ERR string exceeds maximum allowed size (proto-max-bulk-len)".to_string())
+        );
+    }
+
+    #[test]
+    fn test_xread_streams_blocks_until_xadd() {
+        let storage = Storage::new();
+        let reader = {
+            let storage = storage.clone();
+            std::thread::spawn(move || {
+                let streams = vec![("stream".to_string(), "$".to_string())];
+                storage.xread_streams(&streams, None, Some(Duration::from_secs(5)))
+            })
+        };
+
+        sleep(Duration::from_millis(100));
+        let values = HashMap::from([("field".to_string(), b"value".to_vec())]);
+        storage.xadd("stream".to_string(), "1-1", values).unwrap();
+
+        let result = reader.join().unwrap().unwrap();
+        assert_eq!(result.len(), 1);
+        assert_eq!(result[0].0, "stream");
+        assert_eq!(result[0].1[0][0], b"1-1".to_vec());
+    }
+
+    #[test]
+    fn test_xread_streams_times_out_empty() {
+        let storage = Storage::new();
+        let streams = vec![("stream".to_string(), "$".to_string())];
+        let result = storage.xread_streams(&streams, None, Some(Duration::from_millis(50)));
+        assert_eq!(result, Ok(vec![]));
     }
 
     #[test]
     fn test_get_non_expired() {
         let storage = Storage::new();
-        storage.set_ex("key".to_string(), b"value".to_vec(), 100);
+        storage
+            .set_ex("key".to_string(), b"value".to_vec(), 100)
+            .unwrap();
         assert_eq!(storage.get("key"), Some(b"value".to_vec()));
     }
 
@@ -853,7 +2118,34 @@ mod tests {
         assert_eq!(
             err,
             Err("WRONGTYPE Operation against a key holding the wrong kind of value".to_string())
-        )
+        );
+        assert_eq!(storage.get("key"), Some(b"value".to_vec()));
+    }
+
+    #[test]
+    fn test_rpush_multiple_values_is_all_or_nothing() {
+        let storage = Storage::new();
+        let reader = {
+            let storage = storage.clone();
+            std::thread::spawn(move || {
+                for _ in 0..1000 {
+                    let len = storage.llen("list").unwrap();
+                    assert_eq!(len % 3, 0, "saw a partial push: {}", len);
+                }
+            })
+        };
+
+        for _ in 0..100 {
+            let values = vec![b"a".to_vec(), b"b".to_vec(), b"c".to_vec()];
+            storage.rpush("list".to_string(), values).unwrap();
+        }
+        reader.join().unwrap();
+
+        assert_eq!(storage.llen("list"), Ok(300));
+        assert_eq!(
+            storage.lrange("list", 0, 2),
+            Ok(vec![b"a".to_vec(), b"b".to_vec(), b"c".to_vec()])
+        );
     }
 
     #[test]
@@ -1240,7 +2532,7 @@ mod tests {
 
         let popped = storage.blpop(
             vec!["list".to_string(), "list2".to_string(), "list3".to_string()],
-            10.0,
+            Duration::from_secs(10),
         );
         assert_eq!(Ok(Some(("list".to_string(), b"a".to_vec()))), popped);
     }
@@ -1252,8 +2544,7 @@ mod tests {
         let storage_clone = storage.clone();
 
         let handle = std::thread::spawn(move || {
-            let result = storage_clone.blpop(vec!["list".to_string()], 0.1);
-            result
+            storage_clone.blpop(vec!["list".to_string()], Duration::from_millis(100))
         });
 
         std::thread::sleep(Duration::from_millis(50));
@@ -1272,14 +2563,16 @@ mod tests {
 
         let storage_first = storage.clone();
 
-        let client_one =
-            std::thread::spawn(move || storage_first.blpop(vec!["list".to_string()], 10.0));
+        let client_one = std::thread::spawn(move || {
+            storage_first.blpop(vec!["list".to_string()], Duration::from_secs(10))
+        });
 
         std::thread::sleep(Duration::from_millis(100));
 
         let storage_two = storage.clone();
-        let _client_two =
-            std::thread::spawn(move || storage_two.blpop(vec!["list".to_string()], 10.0));
+        let _client_two = std::thread::spawn(move || {
+            storage_two.blpop(vec!["list".to_string()], Duration::from_secs(10))
+        });
 
         std::thread::sleep(Duration::from_millis(100));
 
@@ -1300,8 +2593,7 @@ mod tests {
         let storage_clone = storage.clone();
 
         let handle = std::thread::spawn(move || {
-            let result = storage_clone.blpop(vec!["infinite".to_string()], 0.0);
-            result
+            storage_clone.blpop(vec!["infinite".to_string()], Duration::ZERO)
         });
 
         std::thread::sleep(Duration::from_millis(100));
@@ -1318,7 +2610,7 @@ mod tests {
     fn test_blpop_command_doesnt_work_on_maps() {
         let storage = Storage::new();
         storage.set("key".to_string(), b"value".to_vec());
-        let err = storage.blpop(vec![String::from("key")], 10.0);
+        let err = storage.blpop(vec![String::from("key")], Duration::from_secs(10));
 
         assert_eq!(
             err,
@@ -1813,4 +3105,209 @@ mod tests {
         let range = storage.xread("mystream", "abc");
         assert_eq!(range, Err("Invalid id".to_string()));
     }
+
+    #[test]
+    fn test_parse_strict_i64_rejects_non_canonical_forms() {
+        assert_eq!(parse_strict_i64(b"5"), Some(5));
+        assert_eq!(parse_strict_i64(b"-5"), Some(-5));
+        assert_eq!(parse_strict_i64(b"0"), Some(0));
+        assert_eq!(parse_strict_i64(b"-9223372036854775808"), Some(i64::MIN));
+        for input in [
+            &b" 5"[..],
+            b"5 ",
+            b"+5",
+            b"5\n",
+            b"05",
+            b"-0",
+            b"",
+            b"-",
+            b"9223372036854775808",
+        ] {
+            assert_eq!(parse_strict_i64(input), None, "{:?}", input);
+        }
+    }
+
+    #[test]
+    fn test_incr_by_keeps_ttl_and_checks_overflow() {
+        let storage = Storage::new();
+        assert_eq!(storage.incr_by("n".to_string(), 5), Ok(5));
+        storage.expire("n", 10_000).unwrap();
+        assert_eq!(storage.incr_by("n".to_string(), -7), Ok(-2));
+        assert!(storage
+            .inner
+            .lock()
+            .unwrap()
+            .get("n")
+            .unwrap()
+            .expired_at
+            .is_some());
+
+        storage.set("max".to_string(), i64::MAX.to_string().into_bytes());
+        assert!(storage.incr_by("max".to_string(), 1).is_err());
+        assert_eq!(storage.get("max"), Some(i64::MAX.to_string().into_bytes()));
+    }
+
+    #[test]
+    fn test_move_to_keeps_ttl() {
+        let source = Storage::new();
+        let dest = Storage::new();
+        source
+            .set_ex("key".to_string(), b"v".to_vec(), 100)
+            .unwrap();
+
+        assert!(source.move_to("key", &dest));
+        assert!(!source.exists("key"));
+        assert!(dest
+            .inner
+            .lock()
+            .unwrap()
+            .get("key")
+            .unwrap()
+            .expired_at
+            .is_some());
+        assert!(!dest.move_to("key", &dest.clone()));
+    }
+
+    fn remaining_ms(storage: &Storage, key: &str) -> u128 {
+        let expires_at = storage.inner.lock().unwrap().get(key).unwrap().expired_at;
+        expires_at
+            .unwrap()
+            .duration_since(SystemTime::now())
+            .unwrap()
+            .as_millis()
+    }
+
+    #[test]
+    fn test_set_ex_jittered_stays_within_window() {
+        assert_eq!(jittered_millis(100_000, 0, 12345), 100_000);
+        assert_eq!(jittered_millis(100_000, 10, 0), 90_000);
+        assert_eq!(jittered_millis(100_000, 10, 20_000), 110_000);
+
+        let storage = Storage::new();
+        storage
+            .set_ex_jittered("exact".to_string(), b"v".to_vec(), 100, 0, || u64::MAX)
+            .unwrap();
+        assert!((99_000..=100_000).contains(&remaining_ms(&storage, "exact")));
+
+        for random in [0, 7, 10_000, 19_999, u64::MAX] {
+            storage
+                .set_ex_jittered("k".to_string(), b"v".to_vec(), 100, 10, || random)
+                .unwrap();
+            let remaining = remaining_ms(&storage, "k");
+            assert!((89_000..=110_000).contains(&remaining), "{}", remaining);
+        }
+    }
+
+    #[test]
+    fn test_lmpop_pops_from_first_non_empty_list() {
+        let storage = Storage::new();
+        storage
+            .rpush(
+                "b".to_string(),
+                vec![b"1".to_vec(), b"2".to_vec(), b"3".to_vec()],
+            )
+            .unwrap();
+        let keys = vec!["a".to_string(), "b".to_string()];
+
+        assert_eq!(
+            storage.lmpop(&keys, false, 2),
+            Ok(Some(("b".to_string(), vec![b"3".to_vec(), b"2".to_vec()])))
+        );
+        assert_eq!(
+            storage.lmpop(&keys, true, 10),
+            Ok(Some(("b".to_string(), vec![b"1".to_vec()])))
+        );
+        assert!(!storage.exists("b"));
+        assert_eq!(storage.lmpop(&keys, true, 1), Ok(None));
+
+        storage.set("a".to_string(), b"x".to_vec());
+        assert_eq!(storage.lmpop(&keys, true, 1), Err(WRONGTYPE.to_string()));
+    }
+
+    #[test]
+    fn test_debug_object_reports_stream_internals() {
+        let storage = Storage::new();
+        let fields = || HashMap::from([("f".to_string(), b"v".to_vec())]);
+        storage.xadd("s".to_string(), "1-1", fields()).unwrap();
+        storage.xadd("s".to_string(), "5-3", fields()).unwrap();
+
+        let description = storage.debug_object("s").unwrap();
+        assert!(description.contains(" encoding:stream "), "{}", description);
+        assert!(
+            description.ends_with(" entries:2 last-id:5-3 radix-tree-keys:1"),
+            "{}",
+            description
+        );
+        assert_eq!(storage.get_type("s"), "stream");
+        assert_eq!(storage.object_encoding("s"), Some("stream"));
+    }
+
+    #[test]
+    fn test_blocked_pops_are_served_in_arrival_order() {
+        let storage = Storage::new();
+        let blocked: Vec<_> = (0..3)
+            .map(|i| {
+                let storage = storage.clone();
+                let handle = std::thread::spawn(move || {
+                    storage
+                        .blpop(vec!["list".to_string()], Duration::from_secs(5))
+                        .unwrap()
+                });
+                // Let each client block before the next one arrives.
+                std::thread::sleep(Duration::from_millis(50));
+                (i, handle)
+            })
+            .collect();
+
+        storage
+            .rpush("list".to_string(), vec![b"first".to_vec()])
+            .unwrap();
+        std::thread::sleep(Duration::from_millis(50));
+        assert_eq!(storage.waiters.lock().unwrap().len(), 2);
+
+        storage
+            .rpush(
+                "list".to_string(),
+                vec![b"second".to_vec(), b"third".to_vec()],
+            )
+            .unwrap();
+        let served: Vec<_> = blocked
+            .into_iter()
+            .map(|(i, handle)| (i, handle.join().unwrap().unwrap().1))
+            .collect();
+        assert_eq!(
+            served,
+            vec![
+                (0, b"first".to_vec()),
+                (1, b"second".to_vec()),
+                (2, b"third".to_vec())
+            ]
+        );
+    }
+
+    #[test]
+    fn test_timed_out_waiter_leaves_others_queued() {
+        let storage = Storage::new();
+        let patient = {
+            let storage = storage.clone();
+            std::thread::spawn(move || {
+                storage
+                    .blpop(vec!["list".to_string()], Duration::ZERO)
+                    .unwrap()
+            })
+        };
+        std::thread::sleep(Duration::from_millis(50));
+        assert_eq!(
+            storage.blpop(vec!["list".to_string()], Duration::from_millis(50)),
+            Ok(None)
+        );
+
+        storage
+            .rpush("list".to_string(), vec![b"v".to_vec()])
+            .unwrap();
+        assert_eq!(
+            patient.join().unwrap(),
+            Some(("list".to_string(), b"v".to_vec()))
+        );
+    }
 }